@@ -0,0 +1,220 @@
+use crate::util::{hash_seed, Camera, Point3, Scene};
+use std::fs;
+use std::path::Path;
+
+// Keyframes for one camera parameter, `N` numbers each, kept in time order. In between the
+// keys the values follow a Catmull-Rom spline, so a camera path through several points turns
+// smoothly instead of at corners; before the first key and after the last they hold still.
+#[derive(Clone)]
+pub struct Track<const N: usize> {
+    keys: Vec<(f64, [f64; N])>,
+}
+
+impl<const N: usize> Track<N> {
+    pub fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    // A key at a time that already has one replaces it.
+    pub fn add(&mut self, time: f64, value: [f64; N]) {
+        let at = self.keys.partition_point(|(t, _)| *t < time);
+        if at < self.keys.len() && self.keys[at].0 == time {
+            self.keys[at].1 = value;
+        } else {
+            self.keys.insert(at, (time, value));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn at(&self, time: f64) -> Option<[f64; N]> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        if time <= first.0 {
+            return Some(first.1);
+        }
+        if time >= last.0 {
+            return Some(last.1);
+        }
+        let i = self.keys.partition_point(|(t, _)| *t <= time) - 1;
+        let (t0, p0) = self.keys[i];
+        let (t1, p1) = self.keys[i + 1];
+        let span = t1 - t0;
+        // the slope at a key is that of the line through its neighbours, in units of this span
+        let slope = |k: usize| {
+            let (a, b) = (k.saturating_sub(1), (k + 1).min(self.keys.len() - 1));
+            let (ta, pa) = self.keys[a];
+            let (tb, pb) = self.keys[b];
+            let mut m = [0.0; N];
+            for c in 0..N {
+                m[c] = (pb[c] - pa[c]) / (tb - ta) * span;
+            }
+            m
+        };
+        let (m0, m1) = (slope(i), slope(i + 1));
+        let s = (time - t0) / span;
+        let (s2, s3) = (s * s, s * s * s);
+        let h00 = 2.0 * s3 - 3.0 * s2 + 1.0;
+        let h10 = s3 - 2.0 * s2 + s;
+        let h01 = -2.0 * s3 + 3.0 * s2;
+        let h11 = s3 - s2;
+        let mut value = [0.0; N];
+        for c in 0..N {
+            value[c] = h00 * p0[c] + h10 * m0[c] + h01 * p1[c] + h11 * m1[c];
+        }
+        Some(value)
+    }
+
+    fn point_at(&self, time: f64) -> Option<Point3> {
+        self.at(time).map(|p| Point3::new(p[0], p[1], p[2]))
+    }
+}
+
+// A sequence of frames over the same scene. Frame `k` is shown at time
+// `start + (end - start) * k / frames`, so `end` itself is where frame `frames` would be and a
+// turn from `start` to `end` loops. Objects move by their own keyframes (see `Motion`), the
+// camera by the tracks here; a camera parameter without keys stays as the scene set it.
+#[derive(Clone)]
+pub struct Animation {
+    // 0 if the scene is not animated
+    pub frames: u32,
+    pub start: f64,
+    pub end: f64,
+    // how much of a frame the shutter stays open, 0 for no motion blur
+    pub shutter: f64,
+    pub lookfrom: Track<3>,
+    pub lookat: Track<3>,
+    pub vfov: Track<1>,
+    pub focus_dist: Track<1>,
+}
+
+impl Animation {
+    pub fn default() -> Self {
+        Self {
+            frames: 0,
+            start: 0.0,
+            end: 1.0,
+            shutter: 0.5,
+            lookfrom: Track::new(),
+            lookat: Track::new(),
+            vfov: Track::new(),
+            focus_dist: Track::new(),
+        }
+    }
+
+    pub fn frame_time(&self, frame: u32) -> f64 {
+        self.start + (self.end - self.start) * frame as f64 / self.frames.max(1) as f64
+    }
+
+    // The camera for one frame: the keyed parameters at the frame's time, and the shutter open
+    // from there for `shutter` of a frame, with the camera following its keys meanwhile.
+    pub fn camera(&self, camera: &Camera, frame: u32) -> Camera {
+        let mut camera = *camera;
+        let open = self.frame_time(frame);
+        let close = open + self.shutter * (self.frame_time(frame + 1) - open);
+        if let Some(p) = self.lookfrom.point_at(open) {
+            camera.lookfrom = p;
+        }
+        if let Some(p) = self.lookat.point_at(open) {
+            camera.lookat = p;
+        }
+        if let Some([vfov]) = self.vfov.at(open) {
+            camera.vfov = vfov;
+        }
+        if let Some([focus_dist]) = self.focus_dist.at(open) {
+            camera.focus_dist = focus_dist;
+        }
+        if !self.lookfrom.is_empty() {
+            camera.lookfrom_end = self.lookfrom.point_at(close);
+        }
+        if !self.lookat.is_empty() {
+            camera.lookat_end = self.lookat.point_at(close);
+        }
+        camera.shutter_open = open;
+        camera.shutter_close = close;
+        // a new noise pattern every frame rather than one that stays on the screen
+        camera.seed = hash_seed(&[camera.seed, frame as u64]);
+        camera
+    }
+
+    // Renders every frame into `dir` as frame_0000.<ext>, frame_0001.<ext>, ..., taking the
+    // extension from `scene.path`. The scene is built once and shared by all the frames. With
+    // `skip_existing`, frames already on disk are left alone, so a stopped run can go on.
+    pub fn render(&self, scene: &Scene, dir: &str, skip_existing: bool) -> Result<(), String> {
+        if self.frames == 0 {
+            return Err(String::from("the scene has no frames to render"));
+        }
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+        let extension = Path::new(&scene.path)
+            .extension()
+            .map_or(String::from("png"), |e| e.to_string_lossy().into_owned());
+        let digits = (self.frames - 1).to_string().len().max(4);
+        for frame in 0..self.frames {
+            let path = Path::new(dir).join(format!(
+                "frame_{:0width$}.{}",
+                frame,
+                extension,
+                width = digits
+            ));
+            let path = path.to_string_lossy();
+            if skip_existing && Path::new(path.as_ref()).exists() {
+                println!("Skipping frame {} ({} exists)", frame, path);
+                continue;
+            }
+            println!(
+                "Frame {}/{} at time {}",
+                frame + 1,
+                self.frames,
+                self.frame_time(frame)
+            );
+            let mut camera = self.camera(&scene.camera, frame);
+            camera.render(&scene.world, &scene.lights, &path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_passes_through_keys() {
+        let mut track = Track::<1>::new();
+        assert_eq!(track.at(0.5), None);
+        track.add(1.0, [10.0]);
+        track.add(0.0, [0.0]);
+        assert_eq!(track.at(-1.0), Some([0.0]));
+        assert_eq!(track.at(2.0), Some([10.0]));
+        // with two keys the spline is the straight line between them
+        assert!((track.at(0.25).unwrap()[0] - 2.5).abs() < 1e-12);
+
+        track.add(2.0, [0.0]);
+        assert_eq!(track.at(1.0), Some([10.0]));
+        // level at the top, so it rises past the straight line on the way there
+        assert!(track.at(0.75).unwrap()[0] > 7.5);
+        track.add(2.0, [20.0]);
+        assert_eq!(track.at(3.0), Some([20.0]));
+    }
+
+    #[test]
+    fn test_frame_cameras() {
+        let mut animation = Animation::default();
+        animation.frames = 4;
+        animation.lookfrom.add(0.0, [0.0, 0.0, 0.0]);
+        animation.lookfrom.add(1.0, [8.0, 0.0, 0.0]);
+        animation.vfov.add(0.0, [40.0]);
+        let camera = Camera::default();
+
+        let first = animation.camera(&camera, 0);
+        let third = animation.camera(&camera, 2);
+        assert_eq!((third.shutter_open, third.shutter_close), (0.5, 0.625));
+        assert!((third.lookfrom.x - 4.0).abs() < 1e-12);
+        assert!((third.lookfrom_end.unwrap().x - 5.0).abs() < 1e-12);
+        assert_eq!(third.lookat, camera.lookat);
+        assert_eq!(third.lookat_end, None);
+        assert_eq!(third.vfov, 40.0);
+        assert_ne!(first.seed, third.seed);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::util::{tagged_path, Color, FrameBuffer, ToneMap, Vec3};
+use std::collections::HashMap;
+
+// Arbitrary output variables: what the camera sees first through each pixel, written next to
+// the beauty image as `<path>.<name>.<ext>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    Depth,
+    Normal,
+    Albedo,
+    Uv,
+    Object,
+    Material,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Uv,
+        Aov::Object,
+        Aov::Material,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Uv => "uv",
+            Aov::Object => "object",
+            Aov::Material => "material",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .iter()
+            .find(|aov| aov.name() == name)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown AOV `{}` (depth, normal, albedo, uv, object, material, all)",
+                    name
+                )
+            })
+    }
+}
+
+// The AOVs to write. A bit set so that `Camera` stays `Copy`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AovSet {
+    bits: u8,
+}
+
+impl AovSet {
+    pub fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    // Names as on the command line; `all` turns on every AOV.
+    pub fn parse(names: &[&str]) -> Result<Self, String> {
+        let mut set = Self::empty();
+        for name in names {
+            if *name == "all" {
+                Aov::ALL.iter().for_each(|aov| set.insert(*aov));
+            } else {
+                set.insert(Aov::from_name(name)?);
+            }
+        }
+        Ok(set)
+    }
+
+    pub fn insert(&mut self, aov: Aov) {
+        self.bits |= 1 << aov as u8;
+    }
+
+    pub fn contains(&self, aov: Aov) -> bool {
+        self.bits & (1 << aov as u8) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+}
+
+// One first hit. `object` and `material` are only keys that tell objects apart within a render;
+// `FeatureBuffers::number_ids` turns them into small numbers.
+#[derive(Clone, Copy)]
+pub struct FirstHit {
+    pub albedo: Color,
+    pub normal: Vec3,
+    pub depth: f64,
+    pub u: f64,
+    pub v: f64,
+    pub object: usize,
+    pub material: usize,
+}
+
+impl FirstHit {
+    // Rays that escape have a zero normal, depth and ID, and the background as albedo.
+    pub fn miss(background: Color) -> Self {
+        Self {
+            albedo: background,
+            normal: Vec3::zero(),
+            depth: 0.0,
+            u: 0.0,
+            v: 0.0,
+            object: 0,
+            material: 0,
+        }
+    }
+}
+
+// The first hits of every pixel: albedo, normal, depth and UV averaged over a few rays, the IDs
+// taken from one of them. They guide the denoiser and are the AOV images.
+pub struct FeatureBuffers {
+    pub albedo: FrameBuffer,
+    normal: Vec<Vec3>,
+    depth: Vec<f64>,
+    uv: Vec<(f64, f64)>,
+    object: Vec<usize>,
+    material: Vec<usize>,
+}
+
+impl FeatureBuffers {
+    pub fn new(width: u32, height: u32) -> Self {
+        let pixels = (width * height) as usize;
+        Self {
+            albedo: FrameBuffer::new(width, height),
+            normal: vec![Vec3::zero(); pixels],
+            depth: vec![0.0; pixels],
+            uv: vec![(0.0, 0.0); pixels],
+            object: vec![0; pixels],
+            material: vec![0; pixels],
+        }
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, hit: &FirstHit) {
+        let index = j * self.albedo.width() as usize + i;
+        self.albedo.set(i, j, hit.albedo);
+        self.normal[index] = hit.normal;
+        self.depth[index] = hit.depth;
+        self.uv[index] = (hit.u, hit.v);
+        self.object[index] = hit.object;
+        self.material[index] = hit.material;
+    }
+
+    pub fn normal(&self, i: usize, j: usize) -> Vec3 {
+        self.normal[j * self.albedo.width() as usize + i]
+    }
+
+    pub fn depth(&self, i: usize, j: usize) -> f64 {
+        self.depth[j * self.albedo.width() as usize + i]
+    }
+
+    // Replaces the ID keys by 1, 2, 3, ... in the order they first appear from the top left, so
+    // the same scene gets the same IDs every time. 0 stays the background.
+    pub fn number_ids(&mut self) {
+        for ids in [&mut self.object, &mut self.material] {
+            let mut numbers = HashMap::new();
+            numbers.insert(0, 0);
+            for id in ids.iter_mut() {
+                let next = numbers.len();
+                *id = *numbers.entry(*id).or_insert(next);
+            }
+        }
+    }
+
+    // With `hdr` the values are written as they are (IDs as numbers); otherwise they are
+    // scaled into [0, 1] for viewing: depth from white (near) to black, normals and UVs as
+    // `0.5 * n + 0.5` and `(u, v, 0)`, and every ID in its own color.
+    pub fn image(&self, aov: Aov, hdr: bool) -> FrameBuffer {
+        let (width, height) = (self.albedo.width(), self.albedo.height());
+        let max_depth = self.depth.iter().cloned().fold(0.0, f64::max);
+        let mut image = FrameBuffer::new(width, height);
+        for j in 0..height as usize {
+            for i in 0..width as usize {
+                let index = j * width as usize + i;
+                let color = match aov {
+                    Aov::Albedo => self.albedo.get(i, j),
+                    Aov::Depth => {
+                        let d = self.depth[index];
+                        if hdr {
+                            Color::new(d, d, d)
+                        } else if d > 0.0 {
+                            let v = 1.0 - d / max_depth;
+                            Color::new(v, v, v)
+                        } else {
+                            Color::new(0.0, 0.0, 0.0)
+                        }
+                    }
+                    Aov::Normal => {
+                        let n = self.normal[index];
+                        if hdr {
+                            Color::new(n.x, n.y, n.z)
+                        } else {
+                            Color::new(0.5 * n.x + 0.5, 0.5 * n.y + 0.5, 0.5 * n.z + 0.5)
+                        }
+                    }
+                    Aov::Uv => Color::new(self.uv[index].0, self.uv[index].1, 0.0),
+                    Aov::Object => Self::id_color(self.object[index], hdr),
+                    Aov::Material => Self::id_color(self.material[index], hdr),
+                };
+                image.set(i, j, color);
+            }
+        }
+        image
+    }
+
+    fn id_color(id: usize, hdr: bool) -> Color {
+        if hdr {
+            let id = id as f64;
+            return Color::new(id, id, id);
+        }
+        if id == 0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        // golden-ratio steps around the hue circle keep neighbouring IDs apart
+        let hue = (id as f64 * 0.618033988749895).fract() * 6.0;
+        let x = 1.0 - (hue % 2.0 - 1.0).abs();
+        let (r, g, b) = match hue as u32 {
+            0 => (1.0, x, 0.0),
+            1 => (x, 1.0, 0.0),
+            2 => (0.0, 1.0, x),
+            3 => (0.0, x, 1.0),
+            4 => (x, 0.0, 1.0),
+            _ => (1.0, 0.0, x),
+        };
+        Color::new(0.2 + 0.8 * r, 0.2 + 0.8 * g, 0.2 + 0.8 * b)
+    }
+
+    // Writes every AOV in `aovs` in the format of `path`. Albedo is a color and is tone mapped
+    // like the beauty image; the other passes are data and skip gamma in 8-bit files.
+    pub fn save(&self, path: &str, aovs: AovSet, tone_map: &ToneMap) -> Result<(), String> {
+        let hdr = FrameBuffer::is_hdr_path(path);
+        for aov in Aov::ALL.iter().filter(|aov| aovs.contains(**aov)) {
+            let aov_path = tagged_path(path, aov.name());
+            let image = self.image(*aov, hdr);
+            if *aov == Aov::Albedo {
+                image.save(&aov_path, tone_map)?;
+            } else {
+                image.save_data(&aov_path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aov_set() {
+        let set = AovSet::parse(&["depth", "uv"]).unwrap();
+        assert!(set.contains(Aov::Depth) && set.contains(Aov::Uv));
+        assert!(!set.contains(Aov::Normal));
+        assert!(Aov::ALL
+            .iter()
+            .all(|aov| AovSet::parse(&["all"]).unwrap().contains(*aov)));
+        assert!(AovSet::parse(&["beauty"]).is_err());
+    }
+
+    #[test]
+    fn test_ids_numbered_in_raster_order() {
+        let mut features = FeatureBuffers::new(3, 1);
+        let black = Color::new(0.0, 0.0, 0.0);
+        for (i, object) in [0xbeef, 0, 0x1234].iter().enumerate() {
+            let hit = FirstHit {
+                object: *object,
+                material: 0x1000,
+                ..FirstHit::miss(black)
+            };
+            features.set(i, 0, &hit);
+        }
+        features.number_ids();
+        assert_eq!(features.object, vec![1, 0, 2]);
+        assert_eq!(features.material, vec![1, 1, 1]);
+        assert_eq!(features.image(Aov::Object, true).get(2, 0).r, 2.0);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::util::{Arc, Color, HitRecord, Material, Point3, Ray, ScatterRecord, Texture, Vec3};
+
+// Detail that bends the shading normal without changing the geometry.
+pub enum BumpMap {
+    // A tangent-space normal map: red along +u, green along +v and blue out of the surface,
+    // each mapped from [0, 1] to [-1, 1]. `strength` scales the tilt, 1 as the map was made.
+    Normal {
+        texture: Arc<dyn Texture>,
+        strength: f64,
+    },
+    // A height field: the luminance of the texture times `scale`, in scene units, raised along
+    // the normal.
+    Height {
+        texture: Arc<dyn Texture>,
+        scale: f64,
+    },
+}
+
+// Any material with a bump or normal map on top. The normal is bent once per hit, before
+// anything is shaded, so lights and bounces both see the same bent normal; the surface needs
+// UV derivatives (`HitRecord::dpdu`), which spheres, quads, disks, triangles and meshes give.
+pub struct Bumped {
+    material: Arc<dyn Material>,
+    map: BumpMap,
+}
+
+impl Bumped {
+    pub fn new(material: Arc<dyn Material>, map: BumpMap) -> Self {
+        Self { material, map }
+    }
+
+    fn normal_map(texture: &Arc<dyn Texture>, strength: f64, rec: &HitRecord) -> Option<Vec3> {
+        let (tangent, bitangent) = tangent_frame(rec)?;
+        let c = texture.filtered_value(rec.u, rec.v, &rec.p, rec.footprint);
+        let x = (2.0 * c.r - 1.0) * strength;
+        let y = (2.0 * c.g - 1.0) * strength;
+        let z = 2.0 * c.b - 1.0;
+        let n = tangent * x + bitangent * y + rec.normal * z;
+        if n.near_zero() {
+            None
+        } else {
+            Some(n.normalize())
+        }
+    }
+
+    // The normal of the displaced surface p + h n, from the slope of h along u and v; the
+    // change of n itself is left out.
+    fn height_map(texture: &Arc<dyn Texture>, scale: f64, rec: &HitRecord) -> Option<Vec3> {
+        if rec.dpdu.near_zero() || rec.dpdv.near_zero() {
+            return None;
+        }
+        // about half the footprint, so the slope is measured at the scale the pixel sees
+        let delta = if rec.footprint > 0.0 {
+            0.5 * rec.footprint
+        } else {
+            5e-4
+        };
+        let height = |u: f64, v: f64, p: Point3| {
+            texture.filtered_value(u, v, &p, rec.footprint).luminance() * scale
+        };
+        let h = height(rec.u, rec.v, rec.p);
+        let dhdu = (height(rec.u + delta, rec.v, rec.p + rec.dpdu * delta) - h) / delta;
+        let dhdv = (height(rec.u, rec.v + delta, rec.p + rec.dpdv * delta) - h) / delta;
+        let dpdu = rec.dpdu + rec.normal * dhdu;
+        let dpdv = rec.dpdv + rec.normal * dhdv;
+        let n = dpdu.cross(&dpdv);
+        if n.near_zero() {
+            return None;
+        }
+        // the UVs may run either way around the normal
+        let n = n.normalize();
+        Some(if n.dot(&rec.normal) < 0.0 {
+            n * (-1.0)
+        } else {
+            n
+        })
+    }
+}
+
+// The shading normal's frame: the tangent along +u and the bitangent toward +v.
+fn tangent_frame(rec: &HitRecord) -> Option<(Vec3, Vec3)> {
+    let n = rec.normal;
+    let tangent = rec.dpdu - n * n.dot(&rec.dpdu);
+    if tangent.near_zero() {
+        return None;
+    }
+    let tangent = tangent.normalize();
+    let bitangent = n.cross(&tangent);
+    if bitangent.dot(&rec.dpdv) < 0.0 {
+        Some((tangent, bitangent * (-1.0)))
+    } else {
+        Some((tangent, bitangent))
+    }
+}
+
+impl Material for Bumped {
+    fn scatter(&self, r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        self.material.scatter(r_in, hit_record, srec)
+    }
+    fn emitted(&self, u: f64, v: f64, p: &Point3) -> Color {
+        self.material.emitted(u, v, p)
+    }
+    fn scattering_pdf(&self, r_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> f64 {
+        self.material.scattering_pdf(r_in, hit_record, scattered)
+    }
+    fn scattering_value(
+        &self,
+        r_in: &Ray,
+        hit_record: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Color {
+        self.material
+            .scattering_value(r_in, hit_record, srec, scattered)
+    }
+    fn perturb_normal(&self, hit_record: &mut HitRecord) {
+        self.material.perturb_normal(hit_record);
+        let normal = match &self.map {
+            BumpMap::Normal { texture, strength } => {
+                Self::normal_map(texture, *strength, hit_record)
+            }
+            BumpMap::Height { texture, scale } => Self::height_map(texture, *scale, hit_record),
+        };
+        if let Some(normal) = normal {
+            hit_record.normal = normal;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{Hittable, Interval, Lambertian, Quad, Solid_Color, Sphere};
+
+    // A hit on the top of the unit square at z = 0, u along x and v along y.
+    fn square_hit(point: Point3) -> HitRecord {
+        let quad = Quad::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            None,
+        );
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(
+            point + Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            0.0,
+        );
+        assert!(quad.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        rec
+    }
+
+    fn white() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new_by_color(Color::new(1.0, 1.0, 1.0)))
+    }
+
+    // A solid texture whose luminance rises by `slope` per unit of x.
+    struct Ramp {
+        slope: f64,
+    }
+
+    impl Texture for Ramp {
+        fn value(&self, _u: f64, _v: f64, p: &Point3) -> Color {
+            let h = self.slope * p.x;
+            Color::new(h, h, h)
+        }
+    }
+
+    #[test]
+    fn test_normal_map_tilts_along_the_tangent() {
+        let mut rec = square_hit(Point3::new(0.5, 0.5, 0.0));
+        // (1, 0.5, 1) in [-1, 1] is tilted halfway toward +u
+        let texture = Arc::new(Solid_Color::new_by_f64(1.0, 0.5, 1.0));
+        let bumped = Bumped::new(
+            white(),
+            BumpMap::Normal {
+                texture,
+                strength: 1.0,
+            },
+        );
+        bumped.perturb_normal(&mut rec);
+        let expected = Vec3::new(1.0, 0.0, 1.0).normalize();
+        assert!((rec.normal - expected).length() < 1e-9);
+        // the flat normal (0.5, 0.5, 1) leaves the surface alone
+        let mut rec = square_hit(Point3::new(0.5, 0.5, 0.0));
+        let texture = Arc::new(Solid_Color::new_by_f64(0.5, 0.5, 1.0));
+        let flat = Bumped::new(
+            white(),
+            BumpMap::Normal {
+                texture,
+                strength: 2.0,
+            },
+        );
+        flat.perturb_normal(&mut rec);
+        assert!((rec.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-9);
+    }
+
+    #[test]
+    fn test_height_map_follows_the_slope() {
+        let mut rec = square_hit(Point3::new(0.5, 0.5, 0.0));
+        // height 0.5 x, so the surface rises toward +x and faces back toward -x
+        let bumped = Bumped::new(
+            white(),
+            BumpMap::Height {
+                texture: Arc::new(Ramp { slope: 1.0 }),
+                scale: 0.5,
+            },
+        );
+        bumped.perturb_normal(&mut rec);
+        let expected = Vec3::new(-0.5, 0.0, 1.0).normalize();
+        assert!((rec.normal - expected).length() < 1e-6);
+    }
+
+    #[test]
+    fn test_sphere_tangent_frame() {
+        // the frame comes from the sphere's parametrization; a flat map changes nothing
+        let sphere = Sphere::new_static(Point3::new(0.0, 0.0, 0.0), 2.0, None);
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(Point3::new(0.3, 0.4, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(sphere.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        let normal = rec.normal;
+        // stepping along dp/du and dp/dv moves the UVs by the step
+        let step = 1e-6;
+        for (direction, du, dv) in [(rec.dpdu, step, 0.0), (rec.dpdv, 0.0, step)] {
+            let target = rec.p + direction * step;
+            let mut moved = HitRecord::default();
+            let ray = Ray::new(Point3::zero(), target.to_vec3(), 0.0);
+            assert!(sphere.hit(ray, Interval::new(0.001, f64::INFINITY), &mut moved));
+            assert!((moved.u - rec.u - du).abs() < 1e-9 && (moved.v - rec.v - dv).abs() < 1e-9);
+        }
+        let (tangent, bitangent) = tangent_frame(&rec).unwrap();
+        assert!(tangent.dot(&normal).abs() < 1e-9 && bitangent.dot(&normal).abs() < 1e-9);
+        let texture = Arc::new(Solid_Color::new_by_f64(0.5, 0.5, 1.0));
+        let flat = Bumped::new(
+            white(),
+            BumpMap::Normal {
+                texture,
+                strength: 1.0,
+            },
+        );
+        flat.perturb_normal(&mut rec);
+        assert!((rec.normal - normal).length() < 1e-9);
+    }
+}
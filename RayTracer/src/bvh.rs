@@ -0,0 +1,485 @@
+use crate::util::{Arc, HitRecord, Hittable, HittableList, Interval, Material, Point3, Ray, AABB};
+
+// A node of a `Bvh`. The nodes are stored depth first, so the first child of an inner node is
+// the node right after it; `offset` is where its second child is. A leaf (`count > 0`) holds
+// `count` primitives from `offset`.
+#[derive(Clone, Copy)]
+struct LinearNode {
+    bbox: AABB,
+    offset: u32,
+    count: u16,
+    // the axis an inner node was split along, to visit the nearer child first
+    axis: u8,
+}
+
+// Number of buckets the centroids are sorted into when looking for the best split.
+const BINS: usize = 12;
+// Cost of visiting a node, relative to testing one primitive.
+const TRAVERSAL_COST: f64 = 0.5;
+// Past this depth nodes are split at the median, so the tree can't grow arbitrarily deep.
+const MAX_SAH_DEPTH: usize = 48;
+// The traversal stack holds one node per level. Below `MAX_SAH_DEPTH` every split at least
+// halves the node, and there are fewer than 2^32 primitives.
+const STACK_SIZE: usize = MAX_SAH_DEPTH + u32::BITS as usize;
+
+// A bounding volume hierarchy over anything with a box, as one flat array of nodes. Building
+// it gives the order the primitives have to be kept in, so that every leaf is one run of them.
+pub struct Bvh {
+    nodes: Vec<LinearNode>,
+}
+
+impl Bvh {
+    // With `sah`, each node is split where the surface area heuristic expects the cheapest
+    // traversal, or not at all if testing its primitives directly is cheaper (up to
+    // `max_leaf_size` of them). Otherwise nodes are split in half along the longest axis.
+    pub fn build(boxes: &[AABB], max_leaf_size: usize, sah: bool) -> (Self, Vec<usize>) {
+        let centroids: Vec<Point3> = boxes
+            .iter()
+            .map(|b| Point3::new(b.x.min + b.x.max, b.y.min + b.y.max, b.z.min + b.z.max) * 0.5)
+            .collect();
+        let mut order: Vec<usize> = (0..boxes.len()).collect();
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(2 * boxes.len()),
+        };
+        if !boxes.is_empty() {
+            let builder = Builder {
+                boxes,
+                centroids: &centroids,
+                max_leaf_size: max_leaf_size.clamp(1, u16::MAX as usize),
+                sah,
+            };
+            builder.build_node(&mut bvh.nodes, &mut order, 0, 0);
+        }
+        (bvh, order)
+    }
+
+    pub fn bounding_box(&self) -> AABB {
+        self.nodes.first().map_or(AABB::default(), |node| node.bbox)
+    }
+
+    // Walks the tree front to back with an explicit stack. `hit_primitive` tests primitive
+    // `k` (in build order) against the interval left and returns the distance of a hit.
+    pub fn hit<F>(&self, ray: Ray, ray_t: Interval, mut hit_primitive: F) -> bool
+    where
+        F: FnMut(usize, Interval) -> Option<f64>,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let direction = ray.direction();
+        let negative = [direction.x < 0.0, direction.y < 0.0, direction.z < 0.0];
+        let mut stack = [0u32; STACK_SIZE];
+        let mut top = 0;
+        let mut current = 0;
+        let mut closest_so_far = ray_t.max;
+        let mut hit_anything = false;
+
+        loop {
+            let node = &self.nodes[current];
+            if node.bbox.hit(ray, Interval::new(ray_t.min, closest_so_far)) {
+                if node.count == 0 {
+                    // go on with the child on the near side of the split, the other one later
+                    let (near, far) = if negative[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    stack[top] = far as u32;
+                    top += 1;
+                    current = near;
+                    continue;
+                }
+                let first = node.offset as usize;
+                for k in first..first + node.count as usize {
+                    if let Some(t) = hit_primitive(k, Interval::new(ray_t.min, closest_so_far)) {
+                        hit_anything = true;
+                        closest_so_far = t;
+                    }
+                }
+            }
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+            current = stack[top] as usize;
+        }
+        hit_anything
+    }
+}
+
+struct Builder<'a> {
+    boxes: &'a [AABB],
+    centroids: &'a [Point3],
+    max_leaf_size: usize,
+    sah: bool,
+}
+
+impl Builder<'_> {
+    // Adds the subtree over `order` (the primitives from `first` on in the final order).
+    fn build_node(
+        &self,
+        nodes: &mut Vec<LinearNode>,
+        order: &mut [usize],
+        first: usize,
+        depth: usize,
+    ) {
+        let count = order.len();
+        let bbox = order
+            .iter()
+            .fold(AABB::default(), |b, &k| AABB::new_by_aabb(b, self.boxes[k]));
+        let index = nodes.len();
+        nodes.push(LinearNode {
+            bbox,
+            offset: first as u32,
+            count: count as u16,
+            axis: 0,
+        });
+        if count == 1 {
+            return;
+        }
+
+        // the bounds of the centroids, unpadded so a flat set of them is seen as such
+        let mut bounds = [Interval::empty(); 3];
+        for &k in order.iter() {
+            for (axis, interval) in bounds.iter_mut().enumerate() {
+                let c = self.centroids[k].iloc(axis);
+                *interval = Interval::new(interval.min.min(c), interval.max.max(c));
+            }
+        }
+        let axis = (0..3)
+            .max_by(|&a, &b| bounds[a].size().total_cmp(&bounds[b].size()))
+            .unwrap();
+        let extent = bounds[axis];
+        let split = if extent.size() <= 0.0 {
+            // every centroid is the same point; only split if the leaf would be too big
+            (count > self.max_leaf_size).then_some(count / 2)
+        } else if self.sah && depth < MAX_SAH_DEPTH {
+            self.sah_split(order, bbox, axis, extent)
+        } else {
+            (count > self.max_leaf_size).then(|| {
+                let mid = count / 2;
+                order.select_nth_unstable_by(mid, |&a, &b| {
+                    let (ca, cb) = (self.centroids[a].iloc(axis), self.centroids[b].iloc(axis));
+                    ca.total_cmp(&cb)
+                });
+                mid
+            })
+        };
+        let Some(mid) = split else {
+            return;
+        };
+
+        let (left, right) = order.split_at_mut(mid);
+        self.build_node(nodes, left, first, depth + 1);
+        let second = nodes.len();
+        self.build_node(nodes, right, first + mid, depth + 1);
+        nodes[index].offset = second as u32;
+        nodes[index].count = 0;
+        nodes[index].axis = axis as u8;
+    }
+
+    // Sorts the centroids into `BINS` buckets along `axis` and tries a split between every
+    // two of them. Returns how many primitives go left, after moving them to the front, or
+    // `None` if a leaf is cheaper.
+    fn sah_split(
+        &self,
+        order: &mut [usize],
+        bbox: AABB,
+        axis: usize,
+        extent: Interval,
+    ) -> Option<usize> {
+        let count = order.len();
+        let bin = |k: usize| {
+            let t = (self.centroids[k].iloc(axis) - extent.min) / extent.size();
+            ((t * BINS as f64) as usize).min(BINS - 1)
+        };
+        let mut bin_counts = [0usize; BINS];
+        let mut bin_boxes = [AABB::default(); BINS];
+        for &k in order.iter() {
+            let b = bin(k);
+            bin_counts[b] += 1;
+            bin_boxes[b] = AABB::new_by_aabb(bin_boxes[b], self.boxes[k]);
+        }
+
+        // the sums of count * area on either side of each split
+        let mut below = [0.0; BINS - 1];
+        let (mut n, mut b) = (0, AABB::default());
+        for i in 0..BINS - 1 {
+            n += bin_counts[i];
+            b = AABB::new_by_aabb(b, bin_boxes[i]);
+            below[i] = if n > 0 {
+                n as f64 * b.surface_area()
+            } else {
+                0.0
+            };
+        }
+        let mut above = [0.0; BINS - 1];
+        let (mut n, mut b) = (0, AABB::default());
+        for i in (1..BINS).rev() {
+            n += bin_counts[i];
+            b = AABB::new_by_aabb(b, bin_boxes[i]);
+            above[i - 1] = if n > 0 {
+                n as f64 * b.surface_area()
+            } else {
+                0.0
+            };
+        }
+
+        let area = bbox.surface_area().max(f64::MIN_POSITIVE);
+        let (best, cost) = (0..BINS - 1)
+            .map(|i| (i, TRAVERSAL_COST + (below[i] + above[i]) / area))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        if count <= self.max_leaf_size && cost >= count as f64 {
+            return None;
+        }
+
+        let mut mid = 0;
+        for i in 0..count {
+            if bin(order[i]) <= best {
+                order.swap(i, mid);
+                mid += 1;
+            }
+        }
+        if mid == 0 || mid == count {
+            // all in one bucket range; fall back to halving
+            mid = count / 2;
+        }
+        Some(mid)
+    }
+}
+
+// A BVH over a list of objects. Each leaf holds a few of them, and the tree decides for
+// itself how deep to go.
+pub struct BvhNode {
+    objects: Vec<Arc<dyn Hittable>>,
+    bvh: Bvh,
+}
+
+const OBJECTS_PER_LEAF: usize = 4;
+
+impl BvhNode {
+    pub fn new_by_object_list(list: &HittableList) -> Self {
+        Self::new(&list.objects, true)
+    }
+
+    pub fn new(objects: &[Arc<dyn Hittable>], sah: bool) -> Self {
+        let boxes: Vec<AABB> = objects.iter().map(|object| object.bounding_box()).collect();
+        let (bvh, order) = Bvh::build(&boxes, OBJECTS_PER_LEAF, sah);
+        Self {
+            objects: order.iter().map(|&k| objects[k].clone()).collect(),
+            bvh,
+        }
+    }
+}
+
+impl Hittable for BvhNode {
+    fn hit(&self, r: Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        self.bvh.hit(r, ray_t, |k, interval| {
+            if self.objects[k].hit(r, interval, rec) {
+                Some(rec.t)
+            } else {
+                None
+            }
+        })
+    }
+    fn bounding_box(&self) -> AABB {
+        self.bvh.bounding_box()
+    }
+    fn display(&self) {
+        println!("BvhNode");
+    }
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{MeshVertex, Sphere, Triangle, TriangleMesh, Vec3};
+    use std::fs;
+    use std::time::Instant;
+
+    #[test]
+    fn test_bvh_finds_the_nearest_hit() {
+        // a 10 x 10 x 10 grid of small spheres
+        let mut list = HittableList::new();
+        for x in 0..10 {
+            for y in 0..10 {
+                for z in 0..10 {
+                    let center = Point3::new(x as f64, y as f64, z as f64);
+                    list.add(Arc::new(Sphere::new_static(center, 0.25, None)));
+                }
+            }
+        }
+        for sah in [true, false] {
+            let bvh = BvhNode::new(&list.objects, sah);
+            let bbox = bvh.bounding_box();
+            assert_eq!((bbox.x.min, bbox.x.max), (-0.25, 9.25));
+            for (origin, direction, t) in [
+                ((3.0, 4.0, -5.0), (0.0, 0.0, 1.0), 4.75),
+                ((3.0, 4.0, 15.0), (0.0, 0.0, -1.0), 5.75),
+                ((-2.0, 7.0, 2.0), (1.0, 0.0, 0.0), 1.75),
+                (
+                    (12.0, 12.0, 12.0),
+                    (-1.0, -1.0, -1.0),
+                    3.0 - 0.25 / 3f64.sqrt(),
+                ),
+            ] {
+                let ray = Ray::new(
+                    Point3::new(origin.0, origin.1, origin.2),
+                    Vec3::new(direction.0, direction.1, direction.2),
+                    0.0,
+                );
+                let mut rec = HitRecord::default();
+                assert!(bvh.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+                assert!((rec.t - t).abs() < 1e-9, "{} != {}", rec.t, t);
+            }
+            let mut rec = HitRecord::default();
+            let ray = Ray::new(Point3::new(0.5, 0.5, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+            assert!(!bvh.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        }
+        let empty = BvhNode::new(&[], true);
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(Point3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(!empty.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+    }
+
+    #[test]
+    fn test_bvh_survives_a_degenerate_split() {
+        // boxes at x = 16^k, each of which the SAH splits off on its own, in front of a tight
+        // cluster that is split at the median once the SAH stops
+        let cube = |x: f64| {
+            AABB::new_by_point(
+                Point3::new(x - 0.25, -0.25, -0.25),
+                Point3::new(x + 0.25, 0.25, 0.25),
+            )
+        };
+        let mut boxes: Vec<AABB> = (1..=60).map(|k| cube(16f64.powi(k))).collect();
+        boxes.extend((0..1 << 17).map(|i| cube(i as f64 * 1e-9)));
+        let (bvh, order) = Bvh::build(&boxes, 1, true);
+
+        // every inner node on the way down leaves its other child on the stack
+        let mut depth = vec![0usize; bvh.nodes.len()];
+        for (index, node) in bvh.nodes.iter().enumerate() {
+            if node.count == 0 {
+                depth[index + 1] = depth[index] + 1;
+                depth[node.offset as usize] = depth[index] + 1;
+            }
+        }
+        assert!(*depth.iter().max().unwrap() > 64);
+
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let mut closest = f64::INFINITY;
+        let hit = bvh.hit(ray, Interval::new(0.001, f64::INFINITY), |k, ray_t| {
+            let t = boxes[order[k]].x.min + 5.0;
+            let found = ray_t.surround(t).then_some(t);
+            closest = found.unwrap_or(closest);
+            found
+        });
+        assert!(hit);
+        assert_eq!(closest, 4.75);
+    }
+
+    // Build and trace times on the spot cow (three of them, as in the cow scenes), for the
+    // median split and the SAH, over standalone triangles and over a mesh. Run with
+    // `cargo test --release bench_cow_bvh -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_cow_bvh() {
+        let source = fs::read_to_string("support/spotCow/spot_triangulated.obj").unwrap();
+        let mut positions = Vec::new();
+        let mut faces = Vec::new();
+        for line in source.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.first() {
+                Some(&"v") => {
+                    let p: Vec<f64> = tokens[1..4].iter().map(|t| t.parse().unwrap()).collect();
+                    positions.push(Point3::new(p[0], p[1], p[2]));
+                }
+                Some(&"f") => {
+                    let corner = |token: &str| MeshVertex {
+                        position: token.split('/').next().unwrap().parse::<u32>().unwrap() - 1,
+                        normal: None,
+                        uv: None,
+                    };
+                    faces.push([corner(tokens[1]), corner(tokens[2]), corner(tokens[3])]);
+                }
+                _ => {}
+            }
+        }
+        let offsets = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(-1.0, 0.0, 1.0),
+        ];
+
+        // primary rays of the snowy_cows camera, 640 x 360
+        let (width, height) = (640, 360);
+        let lookfrom = Point3::new(3.0, 2.0, -2.0);
+        let w = (lookfrom - Point3::zero()).normalize();
+        let u = Vec3::new(0.0, 1.0, 0.0).cross(&w).normalize();
+        let v = w.cross(&u);
+        let half_height = (80f64.to_radians() / 2.0).tan();
+        let half_width = half_height * width as f64 / height as f64;
+        let rays: Vec<Ray> = (0..height)
+            .flat_map(|j| (0..width).map(move |i| (i, j)))
+            .map(|(i, j)| {
+                let x = (2.0 * (i as f64 + 0.5) / width as f64 - 1.0) * half_width;
+                let y = (1.0 - 2.0 * (j as f64 + 0.5) / height as f64) * half_height;
+                Ray::new(lookfrom, u * x + v * y - w, 0.0)
+            })
+            .collect();
+
+        let trace = |name: &str, build: &dyn Fn() -> Arc<dyn Hittable>| {
+            let start = Instant::now();
+            let world = build();
+            let build_time = start.elapsed().as_secs_f64();
+            let start = Instant::now();
+            let mut hits = 0;
+            for ray in &rays {
+                let mut rec = HitRecord::default();
+                if world.hit(*ray, Interval::new(0.001, f64::INFINITY), &mut rec) {
+                    hits += 1;
+                }
+            }
+            let trace_time = start.elapsed().as_secs_f64();
+            println!(
+                "{:<24} build {:>7.2} ms  trace {:>7.2} ms  {:>6.2} Mrays/s  ({} hits)",
+                name,
+                build_time * 1e3,
+                trace_time * 1e3,
+                rays.len() as f64 / trace_time / 1e6,
+                hits
+            );
+        };
+        for sah in [false, true] {
+            let name = if sah {
+                "triangles, SAH"
+            } else {
+                "triangles, median"
+            };
+            trace(name, &|| {
+                let mut cows = HittableList::new();
+                for offset in offsets {
+                    for face in &faces {
+                        let [p0, p1, p2] = face.map(|c| positions[c.position as usize] + offset);
+                        let tex = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+                        cows.add(Arc::new(Triangle::new(p0, p1 - p0, p2 - p0, tex, None)));
+                    }
+                }
+                Arc::new(BvhNode::new(&cows.objects, sah))
+            });
+        }
+        trace("mesh, SAH", &|| {
+            let mut cows = HittableList::new();
+            for offset in offsets {
+                let moved = positions.iter().map(|p| *p + offset).collect();
+                let mesh = TriangleMesh::new(moved, Vec::new(), Vec::new(), faces.clone(), None);
+                cows.add(Arc::new(mesh.unwrap()));
+            }
+            Arc::new(BvhNode::new_by_object_list(&cows))
+        });
+    }
+}
@@ -0,0 +1,296 @@
+use crate::util::{Color, FrameBuffer};
+use std::fs;
+use std::io::{BufWriter, Write};
+
+const MAGIC: &[u8; 8] = b"RTCKPT04";
+// width, height, samples per pixel and passes, then the render's fingerprint
+const HEADER_SIZE: usize = 16 + 8;
+
+// Running per-pixel sums of a progressive render. Every pass adds a few samples to every pixel
+// (or, with adaptive sampling, to the pixels that are still noisy), so a render that is stopped
+// after a pass can carry on from here later.
+pub struct Accumulator {
+    sum: FrameBuffer,
+    // sum of the squared luminance of every sample, for the variance
+    sum_sq: Vec<f64>,
+    samples: Vec<u32>,
+    // passes finished so far
+    pub passes: u32,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            sum: FrameBuffer::new(width, height),
+            sum_sq: vec![0.0; (width * height) as usize],
+            samples: vec![0; (width * height) as usize],
+            passes: 0,
+        }
+    }
+
+    pub fn add(&mut self, i: usize, j: usize, color: Color, luminance_sq: f64, samples: u32) {
+        let index = j * self.sum.width() as usize + i;
+        self.sum.set(i, j, self.sum.get(i, j) + color);
+        self.sum_sq[index] += luminance_sq;
+        self.samples[index] += samples;
+    }
+
+    pub fn samples(&self, i: usize, j: usize) -> u32 {
+        self.samples[j * self.sum.width() as usize + i]
+    }
+
+    // Standard error of the pixel's mean luminance relative to the mean. The mean is floored at
+    // 0.01 so near-black pixels don't ask for samples over noise nobody can see.
+    pub fn relative_error(&self, i: usize, j: usize) -> f64 {
+        let n = self.samples(i, j) as f64;
+        if n < 2.0 {
+            return f64::INFINITY;
+        }
+        let mean = self.sum.get(i, j).luminance() / n;
+        let variance = (self.sum_sq[j * self.sum.width() as usize + i] / n - mean * mean).max(0.0)
+            * n
+            / (n - 1.0);
+        (variance / n).sqrt() / mean.max(0.01)
+    }
+
+    // The pixels that are above `threshold` and below `max_samples`, row by row.
+    pub fn noisy_pixels(&self, threshold: f64, max_samples: u32) -> Vec<bool> {
+        let mut noisy = Vec::with_capacity(self.samples.len());
+        for j in 0..self.sum.height() as usize {
+            for i in 0..self.sum.width() as usize {
+                noisy.push(
+                    self.samples(i, j) < max_samples && self.relative_error(i, j) > threshold,
+                );
+            }
+        }
+        noisy
+    }
+
+    // The mean radiance of every pixel.
+    pub fn resolve(&self) -> FrameBuffer {
+        let mut image = FrameBuffer::new(self.sum.width(), self.sum.height());
+        for j in 0..self.sum.height() as usize {
+            for i in 0..self.sum.width() as usize {
+                let n = self.samples[j * self.sum.width() as usize + i];
+                if n > 0 {
+                    image.set(i, j, self.sum.get(i, j) / n as f64);
+                }
+            }
+        }
+        image
+    }
+
+    // Samples taken per pixel, from black at `min_samples` through red and yellow to white at
+    // `max_samples`.
+    pub fn sample_heatmap(&self, min_samples: u32, max_samples: u32) -> FrameBuffer {
+        let mut heatmap = FrameBuffer::new(self.sum.width(), self.sum.height());
+        let range = (max_samples.saturating_sub(min_samples)).max(1) as f64;
+        for j in 0..self.sum.height() as usize {
+            for i in 0..self.sum.width() as usize {
+                let t = (self.samples(i, j).saturating_sub(min_samples) as f64 / range).min(1.0);
+                heatmap.set(
+                    i,
+                    j,
+                    Color::new(
+                        (3.0 * t).min(1.0),
+                        (3.0 * t - 1.0).clamp(0.0, 1.0),
+                        (3.0 * t - 2.0).clamp(0.0, 1.0),
+                    ),
+                );
+            }
+        }
+        heatmap
+    }
+
+    // Written to a temporary file first so a crash while saving keeps the previous checkpoint.
+    // `fingerprint` stands for whatever else has to match to resume: the scene and the camera's
+    // render settings.
+    pub fn save(&self, path: &str, samples_per_pixel: u32, fingerprint: u64) -> Result<(), String> {
+        let temp_path = format!("{}.tmp", path);
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(fs::File::create(&temp_path)?);
+            writer.write_all(MAGIC)?;
+            for value in [
+                self.sum.width(),
+                self.sum.height(),
+                samples_per_pixel,
+                self.passes,
+            ] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&fingerprint.to_le_bytes())?;
+            for j in 0..self.sum.height() as usize {
+                for i in 0..self.sum.width() as usize {
+                    let c = self.sum.get(i, j);
+                    for value in [c.r, c.g, c.b] {
+                        writer.write_all(&value.to_le_bytes())?;
+                    }
+                }
+            }
+            for value in self.sum_sq.iter() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            for n in self.samples.iter() {
+                writer.write_all(&n.to_le_bytes())?;
+            }
+            writer.flush()
+        };
+        write()
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| format!("Failed to write checkpoint {}: {}", path, e))
+    }
+
+    // Ok(None) when there is no checkpoint; an error when there is one for a different render.
+    pub fn load(
+        path: &str,
+        width: u32,
+        height: u32,
+        samples_per_pixel: u32,
+        fingerprint: u64,
+    ) -> Result<Option<Self>, String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Ok(None),
+        };
+        let error = |msg: &str| format!("Checkpoint {}: {}", path, msg);
+        let pixels = (width * height) as usize;
+        if bytes.len() != MAGIC.len() + HEADER_SIZE + pixels * 36 || &bytes[..MAGIC.len()] != MAGIC
+        {
+            return Err(error("not a checkpoint of this image size"));
+        }
+
+        let mut reader = &bytes[MAGIC.len()..];
+        let header = [
+            read_u32(&mut reader),
+            read_u32(&mut reader),
+            read_u32(&mut reader),
+            read_u32(&mut reader),
+        ];
+        if header[..3] != [width, height, samples_per_pixel] {
+            return Err(error(&format!(
+                "made for a {}x{} render with {} spp, delete it to start over",
+                header[0], header[1], header[2]
+            )));
+        }
+        if read_u64(&mut reader) != fingerprint {
+            return Err(error(
+                "made for another scene or other render settings, delete it to start over",
+            ));
+        }
+
+        let mut accumulator = Self::new(width, height);
+        accumulator.passes = header[3];
+        for j in 0..height as usize {
+            for i in 0..width as usize {
+                let r = read_f64(&mut reader);
+                let g = read_f64(&mut reader);
+                let b = read_f64(&mut reader);
+                accumulator.sum.set(i, j, Color::new(r, g, b));
+            }
+        }
+        for value in accumulator.sum_sq.iter_mut() {
+            *value = read_f64(&mut reader);
+        }
+        for n in accumulator.samples.iter_mut() {
+            *n = read_u32(&mut reader);
+        }
+        Ok(Some(accumulator))
+    }
+}
+
+// The length of the file is checked up front, so these never run past the end.
+fn read_u32(reader: &mut &[u8]) -> u32 {
+    let (value, rest) = reader.split_at(4);
+    *reader = rest;
+    u32::from_le_bytes(value.try_into().unwrap())
+}
+
+fn read_u64(reader: &mut &[u8]) -> u64 {
+    let (value, rest) = reader.split_at(8);
+    *reader = rest;
+    u64::from_le_bytes(value.try_into().unwrap())
+}
+
+fn read_f64(reader: &mut &[u8]) -> f64 {
+    let (value, rest) = reader.split_at(8);
+    *reader = rest;
+    f64::from_le_bytes(value.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{Camera, MisHeuristic, Point3, SamplerType};
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let mut accumulator = Accumulator::new(3, 2);
+        accumulator.add(2, 1, Color::new(1.5, 3.0, 0.5), 1.0, 2);
+        accumulator.add(2, 1, Color::new(0.5, 1.0, 0.5), 2.0, 2);
+        accumulator.passes = 2;
+        let path = std::env::temp_dir().join("raytracer_test_checkpoint.ckpt");
+        let path = path.to_str().unwrap();
+        accumulator.save(path, 4, 77).unwrap();
+
+        let loaded = Accumulator::load(path, 3, 2, 4, 77).unwrap().unwrap();
+        assert_eq!(loaded.passes, 2);
+        assert_eq!(loaded.resolve().get(2, 1), Color::new(0.5, 1.0, 0.25));
+        assert_eq!(loaded.resolve().get(0, 0), Color::new(0.0, 0.0, 0.0));
+        assert_eq!(loaded.sum_sq[5], 3.0);
+        assert!(Accumulator::load(path, 3, 2, 5, 77).is_err());
+        // same size, but another scene or other render settings
+        let err = Accumulator::load(path, 3, 2, 4, 78).err().unwrap();
+        assert!(err
+            .ends_with("made for another scene or other render settings, delete it to start over"));
+        fs::remove_file(path).unwrap();
+        assert!(Accumulator::load(path, 3, 2, 4, 77).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_checkpoint_of_other_render_settings_is_rejected() {
+        let camera = Camera::default();
+        let accumulator = Accumulator::new(2, 2);
+        let path = std::env::temp_dir().join("raytracer_test_checkpoint_settings.ckpt");
+        let path = path.to_str().unwrap();
+        accumulator.save(path, 4, camera.fingerprint()).unwrap();
+        assert!(Accumulator::load(path, 2, 2, 4, camera.fingerprint()).is_ok());
+
+        let changes: [fn(&mut Camera); 12] = [
+            |c| c.scene_id = 1,
+            |c| c.seed = 1,
+            |c| c.sampler = SamplerType::Sobol,
+            |c| c.max_depth = 10,
+            |c| c.roulette_depth = 2,
+            |c| c.mis_heuristic = MisHeuristic::Off,
+            |c| c.adaptive_threshold = 0.05,
+            |c| c.max_samples_per_pixel = 64,
+            |c| c.background = Color::new(0.0, 0.0, 0.0),
+            |c| c.shutter_close = 0.5,
+            |c| c.lookfrom = Point3::new(0.0, 1.0, 0.0),
+            |c| c.lookat_end = Some(Point3::new(1.0, 0.0, -1.0)),
+        ];
+        for change in changes {
+            let mut other = camera;
+            change(&mut other);
+            assert!(Accumulator::load(path, 2, 2, 4, other.fingerprint()).is_err());
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_relative_error() {
+        let mut accumulator = Accumulator::new(2, 1);
+        // a flat pixel and one whose samples are 0 and 2
+        for _ in 0..8 {
+            accumulator.add(0, 0, Color::new(1.0, 1.0, 1.0), 1.0, 1);
+            accumulator.add(1, 0, Color::new(0.0, 0.0, 0.0), 0.0, 1);
+            accumulator.add(1, 0, Color::new(2.0, 2.0, 2.0), 4.0, 1);
+        }
+        assert!(accumulator.relative_error(0, 0) < 1e-6);
+        // sample variance 16/15, 16 samples, mean 1
+        let expected = (16.0 / 15.0 / 16.0f64).sqrt();
+        assert!((accumulator.relative_error(1, 0) - expected).abs() < 1e-6);
+        assert_eq!(accumulator.noisy_pixels(0.1, 32), vec![false, true]);
+        assert_eq!(accumulator.noisy_pixels(0.1, 16), vec![false, false]);
+    }
+}
@@ -1,21 +1,12 @@
-use std::ops::Mul;
-mod sup;
 use crate::File;
 use crate::Vec3;
 use std::io::Write;
-use std::sync::{Arc, Mutex};
-use sup::Interval;
+use std::ops::Mul;
 extern crate image;
 use image::{DynamicImage, GenericImageView};
 use std::path::Path;
 
-use image::RgbImage;
-/// the multi-sample write_color() function
-pub fn write_color(pixel_color: [u8; 3], img: &Arc<Mutex<RgbImage>>, i: usize, j: usize) {
-    let mut img_lock = img.lock().unwrap(); // Lock the mutex to access the image buffer.
-    let pixel = img_lock.get_pixel_mut(i.try_into().unwrap(), j.try_into().unwrap());
-    *pixel = image::Rgb(pixel_color);
-}
+use crate::util::random_double;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Color {
@@ -47,18 +38,24 @@ impl Color {
             b: self.b * other.b,
         }
     }
+    pub fn max_component(&self) -> f64 {
+        self.r.max(self.g).max(self.b)
+    }
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
     pub fn random() -> Self {
         Self {
-            r: rand::random::<f64>(),
-            g: rand::random::<f64>(),
-            b: rand::random::<f64>(),
+            r: random_double(),
+            g: random_double(),
+            b: random_double(),
         }
     }
     pub fn random_between(min: f64, max: f64) -> Self {
         Self {
-            r: rand::random::<f64>() * (max - min) + min,
-            g: rand::random::<f64>() * (max - min) + min,
-            b: rand::random::<f64>() * (max - min) + min,
+            r: random_double() * (max - min) + min,
+            g: random_double() * (max - min) + min,
+            b: random_double() * (max - min) + min,
         }
     }
 }
@@ -87,6 +84,18 @@ impl std::ops::Add for Color {
     }
 }
 
+impl std::ops::Sub for Color {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            r: self.r - other.r,
+            g: self.g - other.g,
+            b: self.b - other.b,
+        }
+    }
+}
+
 impl std::ops::Add<Vec3> for Color {
     type Output = Self;
 
@@ -138,12 +147,6 @@ impl Image {
             let pixel = self.img.get_pixel(i, j).0; // 获取像素值
             Some((pixel[0], pixel[1], pixel[2])) // 返回RGB值
         } else {
-            println!(
-                "width: {:?}, height: {:?}",
-                self.img.width(),
-                self.img.height()
-            );
-            std::process::exit(0);
             None // 超出图片范围，返回None
         }
     }
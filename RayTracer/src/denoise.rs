@@ -0,0 +1,182 @@
+use crate::util::{Color, FeatureBuffers, FrameBuffer};
+
+// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010). Five passes of a 5x5 B3-spline
+// kernel whose taps spread 1, 2, 4, 8 and 16 pixels apart; each tap is weighted down by how much
+// its color, normal, albedo and depth differ from the center pixel. The color is divided by the
+// albedo first so textures are not blurred, and multiplied back at the end.
+pub struct Denoiser {
+    pub iterations: u32,
+    pub sigma_color: f64,
+    pub sigma_normal: f64,
+    pub sigma_albedo: f64,
+    // relative to the depth of the center pixel
+    pub sigma_depth: f64,
+}
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+impl Denoiser {
+    pub fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_color: 0.6,
+            sigma_normal: 0.3,
+            sigma_albedo: 0.1,
+            sigma_depth: 0.1,
+        }
+    }
+
+    pub fn denoise(&self, image: &FrameBuffer, features: &FeatureBuffers) -> FrameBuffer {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut irradiance = FrameBuffer::new(image.width(), image.height());
+        for j in 0..height {
+            for i in 0..width {
+                let albedo = Self::demodulation(features.albedo.get(i, j));
+                let c = image.get(i, j);
+                irradiance.set(
+                    i,
+                    j,
+                    Color::new(c.r / albedo.r, c.g / albedo.g, c.b / albedo.b),
+                );
+            }
+        }
+
+        let mut sigma_color = self.sigma_color;
+        for iteration in 0..self.iterations {
+            irradiance = self.filter_pass(&irradiance, features, 1 << iteration, sigma_color);
+            // later passes reach further, so they must be stricter about color
+            sigma_color /= 2.0;
+        }
+
+        for j in 0..height {
+            for i in 0..width {
+                let albedo = Self::demodulation(features.albedo.get(i, j));
+                irradiance.set(i, j, irradiance.get(i, j).element_mul(albedo));
+            }
+        }
+        irradiance
+    }
+
+    // Dark albedo channels are clamped so the division doesn't blow the noise up.
+    fn demodulation(albedo: Color) -> Color {
+        Color::new(albedo.r.max(0.01), albedo.g.max(0.01), albedo.b.max(0.01))
+    }
+
+    // Bright pixels are compared after Reinhard compression, so fireflies don't stop the filter
+    // from running over them.
+    fn compress(c: Color) -> Color {
+        c / (1.0 + c.luminance().max(0.0))
+    }
+
+    fn filter_pass(
+        &self,
+        input: &FrameBuffer,
+        features: &FeatureBuffers,
+        step: i64,
+        sigma_color: f64,
+    ) -> FrameBuffer {
+        let (width, height) = (input.width() as i64, input.height() as i64);
+        let mut output = FrameBuffer::new(input.width(), input.height());
+        for j in 0..height {
+            for i in 0..width {
+                let (pi, pj) = (i as usize, j as usize);
+                let color_p = Self::compress(input.get(pi, pj));
+                let normal_p = features.normal(pi, pj);
+                let albedo_p = features.albedo.get(pi, pj);
+                let depth_p = features.depth(pi, pj);
+
+                let mut sum = Color::new(0.0, 0.0, 0.0);
+                let mut total_weight = 0.0;
+                for (dy, ky) in KERNEL.iter().enumerate() {
+                    let qj = j + (dy as i64 - 2) * step;
+                    if qj < 0 || qj >= height {
+                        continue;
+                    }
+                    for (dx, kx) in KERNEL.iter().enumerate() {
+                        let qi = i + (dx as i64 - 2) * step;
+                        if qi < 0 || qi >= width {
+                            continue;
+                        }
+                        let (qi, qj) = (qi as usize, qj as usize);
+                        let c = input.get(qi, qj);
+
+                        let dc = Self::compress(c) - color_p;
+                        let dn = features.normal(qi, qj) - normal_p;
+                        let da = features.albedo.get(qi, qj) - albedo_p;
+                        let depth_q = features.depth(qi, qj);
+                        let depth_scale = depth_p.max(depth_q).max(1e-6) * self.sigma_depth;
+                        let exponent = Self::squared(dc) / (sigma_color * sigma_color)
+                            + dn.squared_length() / (self.sigma_normal * self.sigma_normal)
+                            + Self::squared(da) / (self.sigma_albedo * self.sigma_albedo)
+                            + (depth_p - depth_q).abs() / depth_scale;
+
+                        let weight = kx * ky * (-exponent).exp();
+                        sum = sum + c * weight;
+                        total_weight += weight;
+                    }
+                }
+                // the center tap always has weight, so this never divides by zero
+                output.set(pi, pj, sum / total_weight);
+            }
+        }
+        output
+    }
+
+    fn squared(c: Color) -> f64 {
+        c.r * c.r + c.g * c.g + c.b * c.b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{FirstHit, Vec3};
+
+    #[test]
+    fn test_denoise_smooths_noise_but_keeps_edges() {
+        // left half faces +z, right half faces +x; both are gray with noise on top
+        let (width, height) = (32, 16);
+        let mut image = FrameBuffer::new(width, height);
+        let mut features = FeatureBuffers::new(width, height);
+        for j in 0..height as usize {
+            for i in 0..width as usize {
+                let left = i < width as usize / 2;
+                let base = if left { 0.2 } else { 0.8 };
+                let noise = if (i * 7 + j * 13) % 5 < 2 {
+                    0.1
+                } else {
+                    -0.066
+                };
+                let v = base + noise;
+                image.set(i, j, Color::new(v, v, v));
+                let normal = if left {
+                    Vec3::new(0.0, 0.0, 1.0)
+                } else {
+                    Vec3::new(1.0, 0.0, 0.0)
+                };
+                let hit = FirstHit {
+                    albedo: Color::new(0.5, 0.5, 0.5),
+                    normal,
+                    depth: 3.0,
+                    ..FirstHit::miss(Color::new(0.0, 0.0, 0.0))
+                };
+                features.set(i, j, &hit);
+            }
+        }
+
+        let denoised = Denoiser::default().denoise(&image, &features);
+        let spread = |fb: &FrameBuffer, range: std::ops::Range<usize>| {
+            let values: Vec<f64> = range
+                .flat_map(|i| (0..height as usize).map(move |j| (i, j)))
+                .map(|(i, j)| fb.get(i, j).g)
+                .collect();
+            let max = values.iter().cloned().fold(f64::MIN, f64::max);
+            let min = values.iter().cloned().fold(f64::MAX, f64::min);
+            max - min
+        };
+        assert!(spread(&denoised, 0..16) < 0.5 * spread(&image, 0..16));
+        // the normals keep the two halves apart
+        assert!((denoised.get(15, 8).g - 0.2).abs() < 0.05);
+        assert!((denoised.get(16, 8).g - 0.8).abs() < 0.05);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::util::{Color, ToneMap};
+use image::codecs::hdr::{HdrDecoder, HdrEncoder};
+use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Linear radiance of every pixel, row by row from the top. Nothing is clamped or gamma corrected
+// until the image is written to an 8-bit format.
+#[derive(Clone)]
+pub struct FrameBuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Color::new(0.0, 0.0, 0.0); (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> Color {
+        self.pixels[j * self.width as usize + i]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, color: Color) {
+        self.pixels[j * self.width as usize + i] = color;
+    }
+
+    // The format comes from the extension: .hdr (Radiance RGBE), .pfm and .exr keep the linear
+    // values, anything else is tone mapped and goes through `Color::to_rgb` (PNG if the
+    // extension is unknown).
+    pub fn save(&self, path: &str, tone_map: &ToneMap) -> Result<(), String> {
+        let result = match Self::extension(path).as_str() {
+            "hdr" => self.write_hdr(path),
+            "pfm" => self.write_pfm(path),
+            "exr" => self.write_exr(path),
+            _ => self.write_ldr(path, |c| tone_map.apply(c).to_rgb()),
+        };
+        result.map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+
+    // For data rather than colors (depth, normals, ...): like `save`, but 8-bit files get the
+    // values clamped to [0, 1] without tone mapping or gamma.
+    pub fn save_data(&self, path: &str) -> Result<(), String> {
+        if Self::is_hdr_path(path) {
+            return self.save(path, &ToneMap::default());
+        }
+        self.write_ldr(path, |c| {
+            [c.r, c.g, c.b].map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+        })
+        .map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+
+    // Whether `save` keeps the linear values for this path.
+    pub fn is_hdr_path(path: &str) -> bool {
+        matches!(Self::extension(path).as_str(), "hdr" | "pfm" | "exr")
+    }
+
+    // Reads back an .hdr, .pfm or .exr file written by `save`, e.g. to tone map it again.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let result = match Self::extension(path).as_str() {
+            "pfm" => Self::read_pfm(path),
+            "hdr" => Self::read_hdr(path),
+            "exr" => Self::read_image(path),
+            _ => Err(String::from("not an .hdr, .pfm or .exr file")),
+        };
+        result.map_err(|e| format!("Failed to read {}: {}", path, e))
+    }
+
+    fn extension(path: &str) -> String {
+        Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    }
+
+    fn rgb_f32(&self) -> Vec<Rgb<f32>> {
+        self.pixels
+            .iter()
+            .map(|c| Rgb([c.r as f32, c.g as f32, c.b as f32]))
+            .collect()
+    }
+
+    fn write_hdr(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        HdrEncoder::new(BufWriter::new(file))
+            .encode(&self.rgb_f32(), self.width as usize, self.height as usize)
+            .map_err(|e| e.to_string())
+    }
+
+    // Portable float map: a short text header, then little-endian floats with the bottom row first.
+    fn write_pfm(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+        let mut data = Vec::with_capacity(self.pixels.len() * 12);
+        for j in (0..self.height as usize).rev() {
+            for i in 0..self.width as usize {
+                let c = self.get(i, j);
+                for value in [c.r, c.g, c.b] {
+                    data.extend_from_slice(&(value as f32).to_le_bytes());
+                }
+            }
+        }
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)
+            .and_then(|_| writer.write_all(&data))
+            .and_then(|_| writer.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    fn write_exr(&self, path: &str) -> Result<(), String> {
+        let mut buffer: ImageBuffer<Rgb<f32>, Vec<f32>> = ImageBuffer::new(self.width, self.height);
+        for (pixel, value) in buffer.pixels_mut().zip(self.rgb_f32()) {
+            *pixel = value;
+        }
+        DynamicImage::ImageRgb32F(buffer)
+            .save_with_format(path, ImageFormat::OpenExr)
+            .map_err(|e| e.to_string())
+    }
+
+    fn write_ldr<F: Fn(Color) -> [u8; 3]>(&self, path: &str, to_rgb: F) -> Result<(), String> {
+        let mut buffer = image::RgbImage::new(self.width, self.height);
+        for (pixel, value) in buffer.pixels_mut().zip(self.pixels.iter()) {
+            *pixel = Rgb(to_rgb(*value));
+        }
+        let format = ImageFormat::from_path(path).unwrap_or(ImageFormat::Png);
+        DynamicImage::ImageRgb8(buffer)
+            .save_with_format(path, format)
+            .map_err(|e| e.to_string())
+    }
+
+    // Not through `image::open`, which turns Radiance files into gamma-corrected 8-bit pixels.
+    fn read_hdr(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let decoder = HdrDecoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+        let metadata = decoder.metadata();
+        let pixels = decoder.read_image_hdr().map_err(|e| e.to_string())?;
+        let mut framebuffer = Self::new(metadata.width, metadata.height);
+        for (k, pixel) in pixels.iter().enumerate() {
+            let color = Color::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64);
+            framebuffer.pixels[k] = color;
+        }
+        Ok(framebuffer)
+    }
+
+    fn read_image(path: &str) -> Result<Self, String> {
+        let image = image::open(path).map_err(|e| e.to_string())?.to_rgb32f();
+        let mut framebuffer = Self::new(image.width(), image.height());
+        for (i, j, pixel) in image.enumerate_pixels() {
+            let color = Color::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64);
+            framebuffer.set(i as usize, j as usize, color);
+        }
+        Ok(framebuffer)
+    }
+
+    fn read_pfm(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        // three header lines: "PF", "<width> <height>", and the scale whose sign is the byte order
+        let mut lines = bytes.splitn(4, |b| *b == b'\n');
+        let mut header = || {
+            lines
+                .next()
+                .map(|line| String::from_utf8_lossy(line).trim().to_string())
+                .ok_or_else(|| String::from("truncated header"))
+        };
+        if header()? != "PF" {
+            return Err(String::from("only color PFM files are supported"));
+        }
+        let size = header()?;
+        let size: Vec<u32> = size
+            .split_whitespace()
+            .map(|v| v.parse::<u32>().map_err(|_| format!("bad size `{}`", size)))
+            .collect::<Result<_, _>>()?;
+        if size.len() != 2 {
+            return Err(String::from("bad size line"));
+        }
+        let scale = header()?;
+        let little_endian = scale
+            .parse::<f64>()
+            .map_err(|_| format!("bad scale `{}`", scale))?
+            < 0.0;
+        let data = lines.next().unwrap_or(&[]);
+
+        let (width, height) = (size[0], size[1]);
+        if data.len() < (width * height * 12) as usize {
+            return Err(String::from("not enough pixel data"));
+        }
+        let value = |k: usize| {
+            let b = [
+                data[4 * k],
+                data[4 * k + 1],
+                data[4 * k + 2],
+                data[4 * k + 3],
+            ];
+            if little_endian {
+                f32::from_le_bytes(b) as f64
+            } else {
+                f32::from_be_bytes(b) as f64
+            }
+        };
+        let mut framebuffer = Self::new(width, height);
+        for j in 0..height as usize {
+            for i in 0..width as usize {
+                let k = 3 * ((height as usize - 1 - j) * width as usize + i);
+                framebuffer.set(i, j, Color::new(value(k), value(k + 1), value(k + 2)));
+            }
+        }
+        Ok(framebuffer)
+    }
+}
+
+// `<dir>/<stem>.<tag>.<ext>` for an image that goes next to `path`, e.g. `out.depth.png`.
+pub fn tagged_path(path: &str, tag: &str) -> String {
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| String::from("png"));
+    Path::new(path)
+        .with_extension(format!("{}.{}", tag, extension))
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pfm_layout() {
+        let mut fb = FrameBuffer::new(2, 2);
+        fb.set(0, 0, Color::new(1.0, 2.0, 3.0));
+        fb.set(1, 1, Color::new(40.0, 0.5, 0.25));
+        let path = std::env::temp_dir().join("raytracer_test_pfm_layout.pfm");
+        fb.save(path.to_str().unwrap(), &ToneMap::default())
+            .unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let floats: Vec<f32> = bytes[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(floats.len(), 12);
+        // bottom row first, so pixel (1, 1) comes right after (0, 1)
+        assert_eq!(&floats[3..6], &[40.0, 0.5, 0.25]);
+        assert_eq!(&floats[6..9], &[1.0, 2.0, 3.0]);
+
+        let loaded = FrameBuffer::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.get(0, 0), Color::new(1.0, 2.0, 3.0));
+        assert_eq!(loaded.get(1, 1), Color::new(40.0, 0.5, 0.25));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hdr_and_exr_roundtrip() {
+        let mut fb = FrameBuffer::new(3, 2);
+        fb.set(0, 0, Color::new(0.0175, 0.5, 1.0));
+        fb.set(2, 0, Color::new(40.0, 0.003, 2.5));
+        fb.set(1, 1, Color::new(1000.0, 12.0, 0.25));
+        for extension in ["hdr", "exr"] {
+            let path = std::env::temp_dir().join(format!("raytracer_test_roundtrip.{}", extension));
+            let path = path.to_str().unwrap();
+            fb.save(path, &ToneMap::default()).unwrap();
+            let loaded = FrameBuffer::load(path).unwrap();
+            assert_eq!((loaded.width(), loaded.height()), (3, 2));
+            for j in 0..2 {
+                for i in 0..3 {
+                    let (a, b) = (fb.get(i, j), loaded.get(i, j));
+                    // RGBE keeps 8 bits of mantissa under an exponent shared by the pixel
+                    let tolerance = match extension {
+                        "hdr" => a.max_component() / 128.0,
+                        _ => 1e-6 * a.max_component(),
+                    };
+                    for (x, y) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+                        assert!((x - y).abs() <= tolerance, "{}: {:?} {:?}", extension, a, b);
+                    }
+                }
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
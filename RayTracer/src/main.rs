@@ -1,15 +1,17 @@
 use std::{f64::consts::PI, f64::INFINITY, fs::File};
 mod util;
+use clap::builder::PossibleValuesParser;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use rayon::iter::Positions;
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 use util::*;
 extern crate lazy_static;
-extern crate obj;
-use obj::Obj;
 
 const AUTHOR: &str = "CHENG";
 
-fn bouncing_spheres() {
+fn bouncing_spheres() -> Scene {
     let path = "output/book2/bouncing_sphere_with_background_color.png";
     let R = (PI / 4.0).cos();
     let mut world = HittableList::new();
@@ -101,7 +103,8 @@ fn bouncing_spheres() {
     let defocus_angle = 0.6;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -118,13 +121,16 @@ fn bouncing_spheres() {
     bvh_world.add(Arc::new(BvhNode::new_by_object_list(&world)));
     let boxed_world = Arc::new(bvh_world) as Arc<dyn Hittable>;
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Ouput image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn checkered_spheres() {
+fn checkered_spheres() -> Scene {
     let path = "output/book2/checkered_spheres.png";
     let R = (PI / 4.0).cos();
     let mut world = HittableList::new();
@@ -162,7 +168,8 @@ fn checkered_spheres() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -179,13 +186,16 @@ fn checkered_spheres() {
     bvh_world.add(Arc::new(BvhNode::new_by_object_list(&world)));
     let boxed_world = Arc::new(bvh_world) as Arc<dyn Hittable>;
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn earth() {
+fn earth() -> Scene {
     let path = "output/book2/earth.png";
     let earth_texture = Arc::new(Image_Texture::new("support/earthmap.jpg"));
     let earth_surface = Arc::new(Lambertian::new(earth_texture));
@@ -213,7 +223,8 @@ fn earth() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -226,13 +237,16 @@ fn earth() {
         focus_dist,
         background,
     );
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn perlin_spheres() {
+fn perlin_spheres() -> Scene {
     let path = "output/book2/perlin_noise_marbled_texture.png";
     let perlin_texture = Arc::new(Noise_Texture::new(4.0));
     let earth_surface = Arc::new(Lambertian::new(perlin_texture));
@@ -266,7 +280,8 @@ fn perlin_spheres() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -280,13 +295,16 @@ fn perlin_spheres() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn quads() {
+fn quads() -> Scene {
     let path = "output/book2/quads.png";
 
     let LEFT_RED = Arc::new(Lambertian::new_by_color(Color::new(1.0, 0.2, 0.2)));
@@ -348,7 +366,8 @@ fn quads() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -362,10 +381,13 @@ fn quads() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
 // fn triangles() {
@@ -430,7 +452,7 @@ fn quads() {
 //     let defocus_angle = 0.0;
 //     let focus_dist = 10.0;
 //     let background = Color::new(0.7, 0.8, 1.0);
-//     let mut cam = Camera::new(
+//     let cam = Camera::new(
 //         aspect_ratio,
 //         image_width,
 //         samples_per_pixel,
@@ -450,7 +472,7 @@ fn quads() {
 //     println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
 // }
 
-fn disk() {
+fn disk() -> Scene {
     let path = "output/book2/disk.png";
 
     let LEFT_RED = Arc::new(Lambertian::new_by_color(Color::new(1.0, 0.2, 0.2)));
@@ -512,7 +534,8 @@ fn disk() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -526,10 +549,13 @@ fn disk() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
 // fn dick() {
@@ -594,7 +620,7 @@ fn disk() {
 //     let defocus_angle = 0.0;
 //     let focus_dist = 10.0;
 //     let background = Color::new(0.7, 0.8, 1.0);
-//     let mut cam = Camera::new(
+//     let cam = Camera::new(
 //         aspect_ratio,
 //         image_width,
 //         samples_per_pixel,
@@ -614,7 +640,7 @@ fn disk() {
 //     println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
 // }
 
-fn simple_light() {
+fn simple_light() -> Scene {
     let path = "output/book2/lights_with_sphere.png";
 
     let DIFF_LIGHT = Arc::new(DiffuseLight::new_by_color(Color::new(4.0, 4.0, 4.0)));
@@ -647,8 +673,8 @@ fn simple_light() {
     let mut world = HittableList::new();
     world.add(globe1);
     world.add(globe2);
-    world.add(globe3);
-    world.add(quad1);
+    world.add(globe3.clone());
+    world.add(quad1.clone());
 
     let mut bvh_world: HittableList = HittableList::new();
     bvh_world.add(Arc::new(BvhNode::new_by_object_list(&world)));
@@ -665,7 +691,11 @@ fn simple_light() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.0, 0.0, 0.0);
-    let mut cam = Camera::new(
+    let mut lights = HittableList::new();
+    lights.add(globe3);
+    lights.add(quad1);
+
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -679,13 +709,16 @@ fn simple_light() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn cornell_box() {
+fn cornell_box() -> Scene {
     let path = "output/book2/cornell_box_stratified.png";
 
     let LIGHT = Arc::new(DiffuseLight::new_by_color(Color::new(15.0, 15.0, 15.0)));
@@ -747,7 +780,7 @@ fn cornell_box() {
     let mut world = HittableList::new();
     world.add(quad1);
     world.add(quad2);
-    world.add(quad3);
+    world.add(quad3.clone());
     world.add(quad4);
     world.add(quad5);
     world.add(quad6);
@@ -769,7 +802,11 @@ fn cornell_box() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.0, 0.0, 0.0);
-    let mut cam = Camera::new(
+    // The ceiling light is sampled directly.
+    let mut lights = HittableList::new();
+    lights.add(quad3);
+
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -783,13 +820,16 @@ fn cornell_box() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn cornell_smoke() {
+fn cornell_smoke() -> Scene {
     let path = "output/book2/cornell_smoke.png";
 
     let LIGHT = Arc::new(DiffuseLight::new_by_color(Color::new(7.0, 7.0, 7.0)));
@@ -861,7 +901,7 @@ fn cornell_smoke() {
     let mut world = HittableList::new();
     world.add(quad1);
     world.add(quad2);
-    world.add(quad3);
+    world.add(quad3.clone());
     world.add(quad4);
     world.add(quad5);
     world.add(quad6);
@@ -883,7 +923,11 @@ fn cornell_smoke() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.0, 0.0, 0.0);
-    let mut cam = Camera::new(
+    // The ceiling light is sampled directly.
+    let mut lights = HittableList::new();
+    lights.add(quad3);
+
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -897,13 +941,16 @@ fn cornell_smoke() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn final_scene(image_width: u32, samples_per_pixel: u32, max_depth: u32) {
+fn final_scene() -> Scene {
     let path = "output/book2/final_scene.png";
     let mut boxes1 = HittableList::new();
 
@@ -933,12 +980,15 @@ fn final_scene(image_width: u32, samples_per_pixel: u32, max_depth: u32) {
     world.add(Arc::new(BvhNode::new_by_object_list(&boxes1)));
 
     let light = Arc::new(DiffuseLight::new_by_color(Color::new(7.0, 7.0, 7.0)));
-    world.add(Arc::new(Quad::new(
+    let light_quad = Arc::new(Quad::new(
         Point3::new(123.0, 554.0, 147.0),
         Vec3::new(300.0, 0.0, 0.0),
         Vec3::new(0.0, 0.0, 265.0),
         Some(light.clone() as Arc<dyn Material>),
-    )));
+    ));
+    world.add(light_quad.clone());
+    let mut lights = HittableList::new();
+    lights.add(light_quad);
 
     let center1 = Point3::new(400.0, 400.0, 400.0);
     let center2 = center1 + Vec3::new(30.0, 0.0, 0.0);
@@ -1016,9 +1066,9 @@ fn final_scene(image_width: u32, samples_per_pixel: u32, max_depth: u32) {
     world.add(boxes2);
 
     let aspect_ratio = 1.0;
-    let image_width = image_width;
-    let samples_per_pixel = samples_per_pixel;
-    let max_depth = max_depth;
+    let image_width = 400;
+    let samples_per_pixel = 250;
+    let max_depth = 4;
     let vfov = 40.0;
     let lookfrom = Point3::new(478.0, 278.0, -600.0);
     let lookat = Point3::new(278.0, 278.0, 0.0);
@@ -1026,7 +1076,7 @@ fn final_scene(image_width: u32, samples_per_pixel: u32, max_depth: u32) {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.0, 0.0, 0.0);
-    let mut cam = Camera::new(
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -1040,21 +1090,130 @@ fn final_scene(image_width: u32, samples_per_pixel: u32, max_depth: u32) {
         background,
     );
 
-    cam.render(&(Arc::new(world) as Arc<dyn Hittable>), path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: Arc::new(world) as Arc<dyn Hittable>,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn test() {
-    let ganyu = Obj::load("support/spotCow/spot_triangulated.obj");
-    let ganyu = ganyu.unwrap();
-    let Groups: &Vec<obj::Group> = &ganyu.data.objects[0].groups;
-    let Positions = &ganyu.data.position;
-    println!("{:?}", ganyu.data.objects[0].groups[0].material);
+// The multiple importance sampling test scene from Veach's thesis: four glossy plates of
+// decreasing smoothness reflect four spherical lights of increasing size but equal power.
+fn veach_mis() -> Scene {
+    let path = "output/book3/veach_mis.png";
+
+    let lookfrom = Point3::new(0.0, 2.0, 15.0);
+    let mut world = HittableList::new();
+    let mut lights = HittableList::new();
+
+    let light_radius = [0.03, 0.1, 0.3, 0.9];
+    let light_color = [
+        Color::new(0.8, 0.1, 0.1),
+        Color::new(0.9, 0.7, 0.1),
+        Color::new(0.2, 0.8, 0.2),
+        Color::new(0.2, 0.3, 0.9),
+    ];
+    for i in 0..4 {
+        let radius = light_radius[i];
+        // keep the emitted power equal: radiance falls with the surface area
+        let radiance = light_color[i] * (0.8 / (radius * radius));
+        let light = Arc::new(Sphere::new_static(
+            Point3::new(-3.75 + 2.5 * i as f64, 1.0, -1.0),
+            radius,
+            Some(Arc::new(DiffuseLight::new_by_color(radiance)) as Arc<dyn Material>),
+        ));
+        world.add(light.clone());
+        lights.add(light);
+    }
+    // a dim light off to the side so the rest of the scene is visible
+    let fill = Arc::new(Sphere::new_static(
+        Point3::new(10.0, 10.0, 4.0),
+        0.5,
+        Some(
+            Arc::new(DiffuseLight::new_by_color(Color::new(80.0, 80.0, 80.0))) as Arc<dyn Material>,
+        ),
+    ));
+    world.add(fill.clone());
+    lights.add(fill);
+
+    // Each plate is tilted so that it mirrors the row of lights towards the camera.
+    let plate_center = [
+        Point3::new(0.0, 0.0, 4.0),
+        Point3::new(0.0, -1.2, 3.0),
+        Point3::new(0.0, -2.7, 2.0),
+        Point3::new(0.0, -4.5, 1.0),
+    ];
+    let plate_fuzz = [0.2, 0.08, 0.03, 0.01];
+    for i in 0..4 {
+        let center = plate_center[i];
+        let to_camera = (lookfrom - center).normalize();
+        let to_lights = (Point3::new(0.0, 1.0, -1.0) - center).normalize();
+        let normal = (to_camera + to_lights).normalize();
+        let across = Vec3::new(1.0, 0.0, 0.0);
+        let along = across.cross(&normal).normalize();
+        world.add(Arc::new(Quad::new(
+            center - across * 5.0 - along * 0.6,
+            across * 10.0,
+            along * 1.2,
+            Some(
+                Arc::new(Metal::new(Color::new(0.35, 0.35, 0.35), plate_fuzz[i]))
+                    as Arc<dyn Material>,
+            ),
+        )));
+    }
+
+    let grey = Arc::new(Lambertian::new_by_color(Color::new(0.4, 0.4, 0.4)));
+    world.add(Arc::new(Quad::new(
+        Point3::new(-10.0, -6.0, -4.0),
+        Vec3::new(20.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 20.0),
+        Some(grey.clone() as Arc<dyn Material>),
+    )));
+    world.add(Arc::new(Quad::new(
+        Point3::new(-10.0, -6.0, -4.0),
+        Vec3::new(20.0, 0.0, 0.0),
+        Vec3::new(0.0, 15.0, 0.0),
+        Some(grey.clone() as Arc<dyn Material>),
+    )));
+
+    let boxed_world = Arc::new(BvhNode::new_by_object_list(&world)) as Arc<dyn Hittable>;
+
+    let aspect_ratio = 3.0 / 2.0;
+    let image_width = 600;
+    let samples_per_pixel = 64;
+    let max_depth = 10;
+    let vfov = 28.0;
+    let lookat = Point3::new(0.0, -1.5, 2.5);
+    let vup = Vec3::new(0.0, 1.0, 0.0);
+    let defocus_angle = 0.0;
+    let focus_dist = 10.0;
+    let background = Color::new(0.0, 0.0, 0.0);
+    let cam = Camera::new(
+        aspect_ratio,
+        image_width,
+        samples_per_pixel,
+        max_depth,
+        vfov,
+        lookfrom,
+        lookat,
+        vup,
+        defocus_angle,
+        focus_dist,
+        background,
+    );
+
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn snowy_cows() {
+fn snowy_cows() -> Scene {
     let path = "output/cow/snowy_cows_high.png";
 
     let aspect_ratio = 16.0 / 9.0;
@@ -1077,87 +1236,25 @@ fn snowy_cows() {
     )))];
 
     let mut world = HittableList::new();
-    let mut cow1 = HittableList::new();
-    let mut cow2 = HittableList::new();
-    let mut cow3 = HittableList::new();
-
-    let ganyu = Obj::load("support/spotCow/spot_triangulated.obj");
-    let ganyu = ganyu.unwrap();
-    let groups: &Vec<obj::Group> = &ganyu.data.objects[0].groups;
-    let positions = &ganyu.data.position;
-    let tex_coords = &ganyu.data.texture;
-
-    for i in 0..groups.len() {
-        let group = &groups[i];
-        for poly in &group.polys {
-            let P = Point3::new(
-                positions[poly.0[0].0][0] as f64,
-                positions[poly.0[0].0][1] as f64,
-                positions[poly.0[0].0][2] as f64,
-            );
-            let P_tex = [
-                tex_coords[poly.0[0].1.unwrap()][0] as f64,
-                tex_coords[poly.0[0].1.unwrap()][1] as f64,
-            ];
-            let Q = Point3::new(
-                positions[poly.0[1].0][0] as f64,
-                positions[poly.0[1].0][1] as f64,
-                positions[poly.0[1].0][2] as f64,
-            );
-            let Q_tex = [
-                tex_coords[poly.0[1].1.unwrap()][0] as f64,
-                tex_coords[poly.0[1].1.unwrap()][1] as f64,
-            ];
-            let R = Point3::new(
-                positions[poly.0[2].0][0] as f64,
-                positions[poly.0[2].0][1] as f64,
-                positions[poly.0[2].0][2] as f64,
-            );
-            let R_tex = [
-                tex_coords[poly.0[2].1.unwrap()][0] as f64,
-                tex_coords[poly.0[2].1.unwrap()][1] as f64,
-            ];
-            cow1.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(materials[0].clone()),
-            )));
-            cow2.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(materials[0].clone()),
-            )));
-            cow3.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(materials[0].clone()),
-            )));
-        }
-    }
-    let cow2 = Arc::new(Translate::new(
-        Arc::new(BvhNode::new_by_object_list(&cow2)),
-        Vec3::new(1.0, 0.0, 1.0),
-    ));
-    let cow3 = Arc::new(Translate::new(
-        Arc::new(BvhNode::new_by_object_list(&cow3)),
-        Vec3::new(-1.0, 0.0, 1.0),
-    ));
-    let cow1 = Arc::new(BvhNode::new_by_object_list(&cow1));
+    // one mesh shared by the three cows
+    let cow = load_obj(
+        "support/spotCow/spot_triangulated.obj",
+        Some(materials[0].clone()),
+        true,
+    )
+    .unwrap();
+    let place = |offset: Vec3| -> Arc<dyn Hittable> {
+        Arc::new(Instance::new(cow.clone(), Mat4::translation(offset), None).unwrap())
+    };
 
     world.add(Arc::new(Sphere::new_static(
         Point3::new(0.0, -1000.0, 0.0),
         1000.0,
         Some(WHITE.clone()),
     )));
-    world.add(cow1);
-    world.add(cow2);
-    world.add(cow3);
+    world.add(place(Vec3::new(0.0, 0.0, 0.0)));
+    world.add(place(Vec3::new(1.0, 0.0, 1.0)));
+    world.add(place(Vec3::new(-1.0, 0.0, 1.0)));
 
     let mut snow = HittableList::new();
     for _ in 0..100000 {
@@ -1187,7 +1284,8 @@ fn snowy_cows() {
 
     let boxed_world = Arc::new(world) as Arc<dyn Hittable>;
 
-    let mut cam = Camera::new(
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -1201,13 +1299,16 @@ fn snowy_cows() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn marine_cows() {
+fn marine_cows() -> Scene {
     let path = "output/cow/marine_cows.png";
 
     let BLUE = Arc::new(Lambertian::new_by_color(Color::new(0.2, 0.2, 1.0)));
@@ -1218,82 +1319,19 @@ fn marine_cows() {
     )))];
 
     let mut world = HittableList::new();
-    let mut cow1 = HittableList::new();
-    let mut cow2 = HittableList::new();
-    let mut cow3 = HittableList::new();
-
-    let ganyu = Obj::load("support/spotCow/spot_triangulated.obj");
-    let ganyu = ganyu.unwrap();
-    let groups: &Vec<obj::Group> = &ganyu.data.objects[0].groups;
-    let positions = &ganyu.data.position;
-    let tex_coords = &ganyu.data.texture;
-
-    for i in 0..groups.len() {
-        let group = &groups[i];
-        for poly in &group.polys {
-            let P = Point3::new(
-                positions[poly.0[0].0][0] as f64,
-                positions[poly.0[0].0][1] as f64,
-                positions[poly.0[0].0][2] as f64,
-            );
-            let P_tex = [
-                tex_coords[poly.0[0].1.unwrap()][0] as f64,
-                tex_coords[poly.0[0].1.unwrap()][1] as f64,
-            ];
-            let Q = Point3::new(
-                positions[poly.0[1].0][0] as f64,
-                positions[poly.0[1].0][1] as f64,
-                positions[poly.0[1].0][2] as f64,
-            );
-            let Q_tex = [
-                tex_coords[poly.0[1].1.unwrap()][0] as f64,
-                tex_coords[poly.0[1].1.unwrap()][1] as f64,
-            ];
-            let R = Point3::new(
-                positions[poly.0[2].0][0] as f64,
-                positions[poly.0[2].0][1] as f64,
-                positions[poly.0[2].0][2] as f64,
-            );
-            let R_tex = [
-                tex_coords[poly.0[2].1.unwrap()][0] as f64,
-                tex_coords[poly.0[2].1.unwrap()][1] as f64,
-            ];
-            cow1.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(materials[0].clone()),
-            )));
-            cow2.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(materials[0].clone()),
-            )));
-            cow3.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(materials[0].clone()),
-            )));
-        }
-    }
-    let cow2 = Arc::new(Translate::new(
-        Arc::new(BvhNode::new_by_object_list(&cow2)),
-        Vec3::new(1.0, 0.0, 1.0),
-    ));
-    let cow3 = Arc::new(Translate::new(
-        Arc::new(BvhNode::new_by_object_list(&cow3)),
-        Vec3::new(-1.0, 0.0, 1.0),
-    ));
-    let cow1 = Arc::new(BvhNode::new_by_object_list(&cow1));
-
-    world.add(cow1);
-    world.add(cow2);
-    world.add(cow3);
+    // one mesh shared by the three cows
+    let cow = load_obj(
+        "support/spotCow/spot_triangulated.obj",
+        Some(materials[0].clone()),
+        true,
+    )
+    .unwrap();
+    let place = |offset: Vec3| -> Arc<dyn Hittable> {
+        Arc::new(Instance::new(cow.clone(), Mat4::translation(offset), None).unwrap())
+    };
+    world.add(place(Vec3::new(0.0, 0.0, 0.0)));
+    world.add(place(Vec3::new(1.0, 0.0, 1.0)));
+    world.add(place(Vec3::new(-1.0, 0.0, 1.0)));
 
     let sun = Arc::new(DiffuseLight::new_by_color(
         Color::new(0.9, 0.55, 0.8) * 10.0,
@@ -1337,7 +1375,9 @@ fn marine_cows() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    // The sun is above the water surface, so shadow rays could never reach it.
+    let lights = HittableList::new();
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -1351,13 +1391,16 @@ fn marine_cows() {
         background,
     );
 
-    cam.render(&boxed_world, path);
-
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
 }
 
-fn space_cows() {
+fn space_cows() -> Scene {
     let path = "output/cow/space_cows_metal.png";
 
     let BLUE = Arc::new(Lambertian::new_by_color(Color::new(0.2, 0.2, 1.0)));
@@ -1368,84 +1411,20 @@ fn space_cows() {
     )))];
 
     let mut world = HittableList::new();
-    let mut cow1 = HittableList::new();
-    let mut cow2 = HittableList::new();
-    let mut cow3 = HittableList::new();
-
-    let ganyu = Obj::load("support/spotCow/spot_triangulated.obj");
-    let ganyu = ganyu.unwrap();
-    let groups: &Vec<obj::Group> = &ganyu.data.objects[0].groups;
-    let positions = &ganyu.data.position;
-    let tex_coords = &ganyu.data.texture;
-
-    for i in 0..groups.len() {
-        let group = &groups[i];
-        for poly in &group.polys {
-            let P = Point3::new(
-                positions[poly.0[0].0][0] as f64,
-                positions[poly.0[0].0][1] as f64,
-                positions[poly.0[0].0][2] as f64,
-            );
-            let P_tex = [
-                tex_coords[poly.0[0].1.unwrap()][0] as f64,
-                tex_coords[poly.0[0].1.unwrap()][1] as f64,
-            ];
-            let Q = Point3::new(
-                positions[poly.0[1].0][0] as f64,
-                positions[poly.0[1].0][1] as f64,
-                positions[poly.0[1].0][2] as f64,
-            );
-            let Q_tex = [
-                tex_coords[poly.0[1].1.unwrap()][0] as f64,
-                tex_coords[poly.0[1].1.unwrap()][1] as f64,
-            ];
-            let R = Point3::new(
-                positions[poly.0[2].0][0] as f64,
-                positions[poly.0[2].0][1] as f64,
-                positions[poly.0[2].0][2] as f64,
-            );
-            let R_tex = [
-                tex_coords[poly.0[2].1.unwrap()][0] as f64,
-                tex_coords[poly.0[2].1.unwrap()][1] as f64,
-            ];
-            cow1.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 0.0))),
-            )));
-            cow2.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 0.0))),
-            )));
-            cow3.add(Arc::new(Triangle::new(
-                P,
-                Q - P,
-                R - P,
-                [P_tex, Q_tex, R_tex],
-                Some(Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 0.0))),
-            )));
-        }
-    }
-    let cow2 = Arc::new(Translate::new(
-        Arc::new(BvhNode::new_by_object_list(&cow2)),
-        Vec3::new(1.0, 0.0, 1.0),
-    ));
-    let cow2 = Arc::new(RotateY::new(cow2, 15.0));
-    let cow3 = Arc::new(Translate::new(
-        Arc::new(BvhNode::new_by_object_list(&cow3)),
-        Vec3::new(-1.0, 0.0, 1.0),
-    ));
-    let cow3 = Arc::new(RotateY::new(cow3, -18.0));
-    let cow1 = Arc::new(BvhNode::new_by_object_list(&cow1));
-
-    world.add(cow1);
-    world.add(cow2);
-    world.add(cow3);
+    // one mesh shared by the three cows
+    let cow = load_obj(
+        "support/spotCow/spot_triangulated.obj",
+        Some(Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 0.0))),
+        true,
+    )
+    .unwrap();
+    let place = |angle: f64, offset: Vec3| -> Arc<dyn Hittable> {
+        let matrix = Mat4::rotation_y(angle) * Mat4::translation(offset);
+        Arc::new(Instance::new(cow.clone(), matrix, None).unwrap())
+    };
+    world.add(place(0.0, Vec3::new(0.0, 0.0, 0.0)));
+    world.add(place(15.0, Vec3::new(1.0, 0.0, 1.0)));
+    world.add(place(-18.0, Vec3::new(-1.0, 0.0, 1.0)));
 
     let SUN = Arc::new(DiffuseLight::new_by_color(
         Color::new(0.9, 0.55, 0.8) * 10.0,
@@ -1477,8 +1456,8 @@ fn space_cows() {
     }
     let bubble = Arc::new(BvhNode::new_by_object_list(&bubble));
 
-    world.add(sun);
-    world.add(sun2);
+    world.add(sun.clone());
+    world.add(sun2.clone());
     // world.add(air);
     world.add(bubble);
 
@@ -1495,7 +1474,10 @@ fn space_cows() {
     let defocus_angle = 0.0;
     let focus_dist = 10.0;
     let background = Color::new(0.7, 0.8, 1.0);
-    let mut cam = Camera::new(
+    let mut lights = HittableList::new();
+    lights.add(sun);
+    lights.add(sun2);
+    let cam = Camera::new(
         aspect_ratio,
         image_width,
         samples_per_pixel,
@@ -1509,38 +1491,451 @@ fn space_cows() {
         background,
     );
 
-    cam.render(&boxed_world, path);
+    Scene {
+        camera: cam,
+        world: boxed_world,
+        lights: Arc::new(lights),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
+}
+
+// A herd of 400 cows that all share one mesh; each is an `Instance` with its own turn, size
+// and sometimes material, and a BVH over the instances keeps the hit cost near one cow's.
+fn cow_herd() -> Scene {
+    let path = "output/cow/cow_herd.png";
 
-    // Save the image
-    println!("Output image as \"{}\"\n Author: {}", path, AUTHOR);
+    let grass = Arc::new(Lambertian::new_by_color(Color::new(0.35, 0.5, 0.2)));
+    let gold: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.9, 0.7, 0.3), 0.2));
+    let spot: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(Image_Texture::new(
+        "support/spotCow/spot_texture.png",
+    ))));
+    let cow = load_obj("support/spotCow/spot_triangulated.obj", Some(spot), true).unwrap();
+
+    let mut herd = HittableList::new();
+    for i in 0..20 {
+        for j in 0..20 {
+            let scale = random_between(0.7, 1.2);
+            let offset = Vec3::new(
+                (i as f64 - 9.5) * 2.0 + random_between(-0.5, 0.5),
+                0.737 * scale,
+                j as f64 * 2.0 + random_between(-0.5, 0.5),
+            );
+            let matrix = Mat4::translation(offset)
+                * Mat4::rotation_y(random_between(-180.0, 180.0))
+                * Mat4::scaling(Vec3::new(scale, scale, scale));
+            let mat = if random_double() < 0.04 {
+                Some(gold.clone())
+            } else {
+                None
+            };
+            herd.add(Arc::new(Instance::new(cow.clone(), matrix, mat).unwrap()));
+        }
+    }
+
+    let mut world = HittableList::new();
+    world.add(Arc::new(Sphere::new_static(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Some(grass.clone()),
+    )));
+    world.add(Arc::new(BvhNode::new_by_object_list(&herd)));
+
+    let cam = Camera::new(
+        16.0 / 9.0,
+        800,
+        100,
+        50,
+        40.0,
+        Point3::new(0.0, 8.0, -12.0),
+        Point3::new(0.0, 0.0, 14.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        0.0,
+        10.0,
+        Color::new(0.7, 0.8, 1.0),
+    );
+
+    Scene {
+        camera: cam,
+        world: Arc::new(world),
+        lights: Arc::new(HittableList::new()),
+        path: String::from(path),
+        animation: Animation::default(),
+    }
+}
+
+type SceneFn = fn() -> Scene;
+
+// Built-in scenes, selected with `--scene <name>`.
+const SCENES: [(&str, SceneFn); 15] = [
+    ("bouncing_spheres", bouncing_spheres),
+    ("checkered_spheres", checkered_spheres),
+    ("earth", earth),
+    ("perlin_spheres", perlin_spheres),
+    ("quads", quads),
+    ("simple_light", simple_light),
+    ("cornell_box", cornell_box),
+    ("cornell_smoke", cornell_smoke),
+    ("final_scene", final_scene),
+    ("veach_mis", veach_mis),
+    ("disk", disk),
+    ("snowy_cows", snowy_cows),
+    ("marine_cows", marine_cows),
+    ("space_cows", space_cows),
+    ("cow_herd", cow_herd),
+    // ("triangles", triangles),    triangle function hasn't been updated
+    // ("dick", dick),    don't run that XD
+];
+
+fn apply_tone_map_args(matches: &ArgMatches, tone_map: &mut ToneMap) {
+    if let Some(operator) = matches.get_one::<ToneMapOperator>("tonemap") {
+        tone_map.operator = *operator;
+    }
+    if let Some(exposure) = matches.get_one::<f64>("exposure") {
+        tone_map.exposure = *exposure;
+    }
+    if let Some(white) = matches.get_one::<f64>("white") {
+        tone_map.white_point = *white;
+    }
 }
 
 fn main() {
-    match 14 {
-        1 => bouncing_spheres(),
-        2 => checkered_spheres(),
-        3 => earth(),
-        4 => perlin_spheres(),
-        5 => quads(),
-        6 => simple_light(),
-        7 => cornell_box(),
-        8 => cornell_smoke(),
-        9 => final_scene(800, 10000, 40),
-        // 10 => triangles(),
-        // triangle function hasn't been updated
-        11 => disk(),
-        // 12 => dick(),    don't run that XD
-        13 => test(),
-        14 => snowy_cows(),
-        15 => marine_cows(),
-        16 => space_cows(),
-        _ => final_scene(400, 250, 4),
+    let scene_names: Vec<&str> = SCENES.iter().map(|(name, _)| *name).collect();
+    let matches = Command::new("RayTracer")
+        .about("Renders a built-in scene or a scene description file")
+        .arg(
+            Arg::new("scene")
+                .short('s')
+                .long("scene")
+                .value_name("NAME")
+                .help("Built-in scene to render")
+                .takes_value(true)
+                .default_value("snowy_cows")
+                .value_parser(PossibleValuesParser::new(scene_names)),
+        )
+        .arg(
+            Arg::new("file")
+                .short('f')
+                .long("file")
+                .value_name("PATH")
+                .help("Scene description file to render instead of a built-in scene")
+                .takes_value(true)
+                .conflicts_with("scene"),
+        )
+        .arg(
+            Arg::new("list")
+                .short('l')
+                .long("list")
+                .help("List the built-in scenes and exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("width")
+                .short('w')
+                .long("width")
+                .value_name("PIXELS")
+                .help("Override image_width")
+                .takes_value(true)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("spp")
+                .long("spp")
+                .value_name("N")
+                .help("Override samples_per_pixel")
+                .takes_value(true)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("depth")
+                .short('d')
+                .long("depth")
+                .value_name("N")
+                .help("Override max_depth")
+                .takes_value(true)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("aspect")
+                .short('a')
+                .long("aspect")
+                .value_name("RATIO")
+                .help("Override aspect_ratio, e.g. 1.5 or 16/9")
+                .takes_value(true)
+                .value_parser(parse_number),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("PATH")
+                .help("Override the output path; .hdr, .pfm and .exr keep the linear HDR values")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("N")
+                .help("Number of render threads (default: one per core)")
+                .takes_value(true)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("N")
+                .help("Random seed for building the scene and sampling (default: 0); the same seed renders the same image")
+                .takes_value(true)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("sampler")
+                .long("sampler")
+                .value_name("NAME")
+                .help("Sample pattern: independent, stratified (default), halton, sobol or blue_noise")
+                .takes_value(true)
+                .value_parser(SamplerType::from_name),
+        )
+        .arg(
+            Arg::new("roulette")
+                .long("roulette")
+                .value_name("N")
+                .help("Bounces before Russian roulette may end a path (default: 5)")
+                .takes_value(true)
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("adaptive")
+                .long("adaptive")
+                .value_name("ERROR")
+                .help("Keep sampling pixels until their relative error is below ERROR, e.g. 0.02; --spp becomes the minimum")
+                .takes_value(true)
+                .value_parser(parse_number),
+        )
+        .arg(
+            Arg::new("max-spp")
+                .long("max-spp")
+                .value_name("N")
+                .help("Most samples per pixel with --adaptive (default: 4 times --spp)")
+                .takes_value(true)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("denoise")
+                .long("denoise")
+                .help("Also write a denoised copy of the image as <output>.denoised.<ext>")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("aov")
+                .long("aov")
+                .value_name("PASSES")
+                .help("Also write first-hit passes as <output>.<pass>.<ext>: depth, normal, albedo, uv, object, material or all, comma separated")
+                .takes_value(true)
+                .use_value_delimiter(true)
+                .multiple_values(true),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .value_name("SECONDS")
+                .help("Save progress (and a preview image) at most this often, and resume from it")
+                .takes_value(true)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("tonemap")
+                .long("tonemap")
+                .value_name("OPERATOR")
+                .help("Tone mapping for 8-bit output: clamp (default), reinhard, reinhard_extended or aces")
+                .takes_value(true)
+                .value_parser(ToneMapOperator::from_name),
+        )
+        .arg(
+            Arg::new("exposure")
+                .long("exposure")
+                .value_name("EV")
+                .help("Exposure in stops applied before tone mapping (default: 0)")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .value_parser(parse_number),
+        )
+        .arg(
+            Arg::new("white")
+                .long("white-point")
+                .value_name("LUMINANCE")
+                .help("Luminance mapped to white by reinhard_extended (default: 4)")
+                .takes_value(true)
+                .value_parser(parse_number),
+        )
+        .arg(
+            Arg::new("tonemap-only")
+                .long("tonemap-only")
+                .value_name("HDR")
+                .help("Tone map a saved .hdr, .pfm or .exr image instead of rendering")
+                .takes_value(true)
+                .conflicts_with_all(&["scene", "file"]),
+        )
+        .arg(
+            Arg::new("mis")
+                .long("mis")
+                .value_name("HEURISTIC")
+                .help("How light and material samples are combined: balance, power (default) or off")
+                .takes_value(true)
+                .value_parser(parse_mis_heuristic),
+        )
+        .arg(
+            Arg::new("animate")
+                .long("animate")
+                .value_name("DIR")
+                .help("Render the scene's animation as numbered frames in DIR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("frames")
+                .long("frames")
+                .value_name("N")
+                .help("Override the number of frames with --animate")
+                .takes_value(true)
+                .requires("animate")
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("skip-existing")
+                .long("skip-existing")
+                .help("With --animate, leave frames that are already in DIR")
+                .requires("animate")
+                .action(ArgAction::SetTrue),
+        )
+        .get_matches();
+
+    if *matches.get_one::<bool>("list").unwrap() {
+        for (name, _) in SCENES.iter() {
+            println!("{}", name);
+        }
+        return;
+    }
+
+    if let Some(input) = matches.get_one::<String>("tonemap-only") {
+        let mut tone_map = ToneMap::default();
+        apply_tone_map_args(&matches, &mut tone_map);
+        let output = match matches.get_one::<String>("output") {
+            Some(output) => output.clone(),
+            None => Path::new(input)
+                .with_extension("png")
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let result = FrameBuffer::load(input).and_then(|image| image.save(&output, &tone_map));
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("Output image as \"{}\"", output);
+        return;
     }
 
-    // let output_image: image::DynamicImage = image::DynamicImage::ImageRgb8(img);
-    // let mut output_file: File = File::create(path).unwrap();
-    // match output_image.write_to(&mut output_file, image::ImageOutputFormat::Png) {
-    //     Ok(_) => {}
-    //     Err(_) => println!("Outputting image fails."),
-    // }
+    // Seeded before the scene is built, so random scenes like bouncing_spheres come out the same.
+    let seed = matches.get_one::<u64>("seed").copied();
+    seed_random(hash_seed(&[seed.unwrap_or(0)]));
+    let mut scene = match matches.get_one::<String>("file") {
+        Some(file_path) => match Scene::load(file_path) {
+            Ok(scene) => scene,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let name = matches.get_one::<String>("scene").unwrap();
+            let (_, build) = SCENES.iter().find(|(n, _)| n == name).unwrap();
+            let mut scene = build();
+            scene.camera.scene_id = hash_seed(&[hash_name(name), seed.unwrap_or(0)]);
+            scene
+        }
+    };
+
+    if let Some(width) = matches.get_one::<u32>("width") {
+        scene.camera.image_width = *width;
+    }
+    if let Some(spp) = matches.get_one::<u32>("spp") {
+        scene.camera.samples_per_pixel = *spp;
+    }
+    if let Some(depth) = matches.get_one::<u32>("depth") {
+        scene.camera.max_depth = *depth;
+    }
+    if let Some(aspect) = matches.get_one::<f64>("aspect") {
+        scene.camera.aspect_ratio = *aspect;
+    }
+    if let Some(output) = matches.get_one::<String>("output") {
+        scene.path = output.clone();
+    }
+    if let Some(seed) = seed {
+        scene.camera.seed = seed;
+    }
+    if let Some(sampler) = matches.get_one::<SamplerType>("sampler") {
+        scene.camera.sampler = *sampler;
+    }
+    if let Some(threads) = matches.get_one::<u32>("threads") {
+        scene.camera.threads = *threads as usize;
+    }
+    if let Some(roulette) = matches.get_one::<u32>("roulette") {
+        scene.camera.roulette_depth = *roulette;
+    }
+    if let Some(threshold) = matches.get_one::<f64>("adaptive") {
+        scene.camera.adaptive_threshold = *threshold;
+    }
+    if let Some(max_spp) = matches.get_one::<u32>("max-spp") {
+        scene.camera.max_samples_per_pixel = *max_spp;
+    }
+    if let Some(names) = matches.get_many::<String>("aov") {
+        let names: Vec<&str> = names.map(|name| name.as_str()).collect();
+        scene.camera.aovs = AovSet::parse(&names).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
+    if matches.get_flag("denoise") {
+        scene.camera.denoise = true;
+    }
+    if let Some(interval) = matches.get_one::<u64>("checkpoint") {
+        scene.camera.checkpoint_interval = *interval;
+    }
+    if let Some(heuristic) = matches.get_one::<MisHeuristic>("mis") {
+        scene.camera.mis_heuristic = *heuristic;
+    }
+    apply_tone_map_args(&matches, &mut scene.camera.tone_map);
+    if let Some(frames) = matches.get_one::<u32>("frames") {
+        scene.animation.frames = *frames;
+    }
+
+    if let Some(dir) = matches.get_one::<String>("animate") {
+        if let Err(e) = scene
+            .animation
+            .render(&scene, dir, matches.get_flag("skip-existing"))
+        {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("Output frames in \"{}\"\n Author: {}", dir, AUTHOR);
+        return;
+    }
+
+    if let Some(dir) = Path::new(&scene.path).parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Failed to create {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = scene
+        .camera
+        .render(&scene.world, &scene.lights, &scene.path)
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Save the image
+    println!("Output image as \"{}\"\n Author: {}", scene.path, AUTHOR);
 }
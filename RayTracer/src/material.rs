@@ -1,21 +1,56 @@
 use crate::util::{
-    random_double, reflect, refract, Color, HitRecord, Ray, Solid_Color, Texture, Vec3, Point3,
+    random_double, reflect, refract, Color, CosinePdf, FuzzPdf, HitRecord, Pdf, Point3, Ray,
+    Solid_Color, SpherePdf, Texture, Vec3,
 };
+use std::f64::consts::PI;
 use std::sync::Arc;
 
+// What a material does with an incoming ray. Diffuse materials hand back a pdf to sample the
+// outgoing direction from; specular ones (skip_pdf) pick the direction themselves.
+pub struct ScatterRecord {
+    pub attenuation: Color,
+    pub pdf: Option<Arc<dyn Pdf>>,
+    pub skip_pdf: bool,
+    pub skip_pdf_ray: Ray,
+}
+
+impl ScatterRecord {
+    pub fn default() -> Self {
+        Self {
+            attenuation: Color::new(0.0, 0.0, 0.0),
+            pdf: None,
+            skip_pdf: false,
+            skip_pdf_ray: Ray::new(Point3::zero(), Vec3::zero(), 0.0),
+        }
+    }
+}
+
 pub trait Material: Send + Sync {
-    fn scatter(
-        &self,
-        r_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered: &mut Ray,
-    ) -> bool {
+    fn scatter(&self, _r_in: &Ray, _hit_record: &HitRecord, _srec: &mut ScatterRecord) -> bool {
         false
     }
     fn emitted(&self, u: f64, v: f64, p: &Point3) -> Color {
         Color::new(0.0, 0.0, 0.0)
     }
+    // The material's own scattering density for `scattered`; by default the path weight is
+    // attenuation * scattering_pdf / (pdf the direction was actually sampled with).
+    fn scattering_pdf(&self, _r_in: &Ray, _hit_record: &HitRecord, _scattered: &Ray) -> f64 {
+        0.0
+    }
+    // f * cos, the color `scattered` carries back per unit of incoming light, before dividing by
+    // the pdf it was sampled with. Materials whose reflection changes color with direction
+    // override this; the rest scale `attenuation` by their density.
+    fn scattering_value(
+        &self,
+        r_in: &Ray,
+        hit_record: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Color {
+        srec.attenuation * self.scattering_pdf(r_in, hit_record, scattered)
+    }
+    // Bends the shading normal before the hit is shaded; see `Bumped`.
+    fn perturb_normal(&self, _hit_record: &mut HitRecord) {}
 }
 
 pub struct Lambertian {
@@ -34,22 +69,26 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(
-        &self,
-        r_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered: &mut Ray,
-    ) -> bool {
-        let mut scatter_direction = hit_record.normal + Vec3::random_unit_vector();
-
-        if scatter_direction.near_zero() {
-            scatter_direction = hit_record.normal;
+    fn scatter(&self, _r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = self.tex.filtered_value(
+            hit_record.u,
+            hit_record.v,
+            &hit_record.p,
+            hit_record.footprint,
+        );
+        srec.pdf = Some(Arc::new(CosinePdf::new(hit_record.normal)));
+        srec.skip_pdf = false;
+        true
+    }
+    fn scattering_pdf(&self, _r_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> f64 {
+        let cos_theta = hit_record.normal.dot(&scattered.direction().normalize());
+        // a smooth-shaded surface can send a bounce below itself; it doesn't get through
+        let below = scattered.direction().dot(&hit_record.geometric_normal) <= 0.0;
+        if cos_theta < 0.0 || below {
+            0.0
+        } else {
+            cos_theta / PI
         }
-
-        *scattered = Ray::new(hit_record.p, scatter_direction, r_in.time());
-        *attenuation = self.tex.value(hit_record.u, hit_record.v, &hit_record.p);
-        return true;
     }
 }
 
@@ -65,25 +104,30 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(
-        &self,
-        r_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered: &mut Ray,
-    ) -> bool {
+    fn scatter(&self, r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
         let reflected = reflect(r_in.direction(), hit_record.normal).normalize();
-        let scatter_direction = reflected + Vec3::random_unit_vector() * self.fuzz;
+        srec.attenuation = self.albedo;
 
-        // if scatter_direction.dot(&hit_record.normal) <= 0.0 {
-        //     return false;
-        // }
+        // A perfect mirror has no density to weigh against light sampling.
+        if self.fuzz <= 0.0 {
+            srec.pdf = None;
+            srec.skip_pdf = true;
+            srec.skip_pdf_ray = hit_record.spawn_ray(reflected, r_in.time());
+            return true;
+        }
 
-        *scattered = Ray::new(hit_record.p, scatter_direction, r_in.time());
-        *attenuation = self.albedo;
-        // return scattered.direction().dot(&hit_record.normal) > 0.0;
+        srec.pdf = Some(Arc::new(FuzzPdf::new(reflected, self.fuzz)));
+        srec.skip_pdf = false;
         true
     }
+    fn scattering_pdf(&self, r_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> f64 {
+        // the fuzz lobe can reach below the surface; nothing is reflected there
+        if scattered.direction().dot(&hit_record.geometric_normal) <= 0.0 {
+            return 0.0;
+        }
+        let reflected = reflect(r_in.direction(), hit_record.normal).normalize();
+        FuzzPdf::new(reflected, self.fuzz).value(scattered.direction())
+    }
 }
 
 pub struct Dielectric {
@@ -101,14 +145,10 @@ impl Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(
-        &self,
-        r_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered: &mut Ray,
-    ) -> bool {
-        *attenuation = Color::new(1.0, 1.0, 1.0);
+    fn scatter(&self, r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = Color::new(1.0, 1.0, 1.0);
+        srec.pdf = None;
+        srec.skip_pdf = true;
         let ri = if hit_record.front_face {
             (1.0 / self.refraction_index)
         } else {
@@ -128,7 +168,7 @@ impl Material for Dielectric {
             direction = refract(unit_direction, hit_record.normal, ri);
         }
 
-        *scattered = Ray::new(hit_record.p, direction, r_in.time());
+        srec.skip_pdf_ray = hit_record.spawn_ray(direction, r_in.time());
         true
     }
 }
@@ -169,15 +209,50 @@ impl Isotropic {
 }
 
 impl Material for Isotropic {
-    fn scatter(
-        &self,
-        r_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered: &mut Ray,
-    ) -> bool {
-        *scattered = Ray::new(hit_record.p, Vec3::random_in_unit_sphere(), r_in.time());
-        *attenuation = self.tex.value(hit_record.u, hit_record.v, &hit_record.p);
+    fn scatter(&self, _r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = self.tex.filtered_value(
+            hit_record.u,
+            hit_record.v,
+            &hit_record.p,
+            hit_record.footprint,
+        );
+        srec.pdf = Some(Arc::new(SpherePdf::new()));
+        srec.skip_pdf = false;
         true
     }
-}
\ No newline at end of file
+    fn scattering_pdf(&self, _r_in: &Ray, _hit_record: &HitRecord, _scattered: &Ray) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzzy_metal_reflects_nothing_below_the_surface() {
+        let metal = Metal::new(Color::new(0.8, 0.8, 0.8), 1.0);
+        let mut rec = HitRecord::default();
+        rec.p = Point3::zero();
+        rec.normal = Vec3::new(0.0, 0.0, 1.0);
+        rec.geometric_normal = rec.normal;
+        rec.front_face = true;
+        // a grazing ray, so the fuzz lobe reaches well below the surface
+        let ray = Ray::new(
+            Point3::new(-1.0, 0.0, 0.1),
+            Vec3::new(1.0, 0.0, -0.1).normalize(),
+            0.0,
+        );
+        let mut srec = ScatterRecord::default();
+        assert!(metal.scatter(&ray, &rec, &mut srec));
+
+        let above = rec.spawn_ray(Vec3::new(1.0, 0.0, 0.3), 0.0);
+        assert!(metal.scattering_pdf(&ray, &rec, &above) > 0.0);
+        // a shadow ray towards a light behind the surface
+        let behind = rec.spawn_ray(Vec3::new(1.0, 0.0, -0.3), 0.0);
+        assert!(srec.pdf.as_ref().unwrap().value(behind.direction()) > 0.0);
+        assert_eq!(metal.scattering_pdf(&ray, &rec, &behind), 0.0);
+        let value = metal.scattering_value(&ray, &rec, &srec, &behind);
+        assert_eq!((value.r, value.g, value.b), (0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,284 @@
+use crate::util::{
+    uv_derivatives, Arc, Bvh, HitRecord, Hittable, Interval, Material, Point3, Ray, Vec3, AABB,
+};
+
+// One corner of a face: indices into the positions, normals and UVs of the mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshVertex {
+    pub position: u32,
+    pub normal: Option<u32>,
+    pub uv: Option<u32>,
+}
+
+const LEAF_SIZE: usize = 4;
+
+// Triangles that share their vertices. Positions, normals and UVs are stored once and the
+// faces index into them, and the mesh keeps its own `Bvh` over the faces, so a model costs a
+// few dozen bytes per triangle instead of a `Triangle` and a `BvhNode` leaf each.
+// Faces with vertex normals are shaded smooth; the flat face normal is still what decides the
+// side of the surface and where rays leave it.
+pub struct TriangleMesh {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<[f64; 2]>,
+    faces: Vec<[MeshVertex; 3]>,
+    bvh: Bvh,
+    mat: Option<Arc<dyn Material>>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<[f64; 2]>,
+        faces: Vec<[MeshVertex; 3]>,
+        mat: Option<Arc<dyn Material>>,
+    ) -> Result<Self, String> {
+        if faces.is_empty() {
+            return Err(String::from("mesh has no faces"));
+        }
+        for vertex in faces.iter().flatten() {
+            let in_range =
+                |index: Option<u32>, len: usize| index.is_none_or(|i| (i as usize) < len);
+            if !in_range(Some(vertex.position), positions.len())
+                || !in_range(vertex.normal, normals.len())
+                || !in_range(vertex.uv, uvs.len())
+            {
+                return Err(String::from("mesh face refers to a missing vertex"));
+            }
+        }
+        // the faces are kept in the order of the BVH leaves
+        let boxes: Vec<AABB> = faces
+            .iter()
+            .map(|face| {
+                let [p0, p1, p2] = face.map(|v| positions[v.position as usize]);
+                AABB::new_by_aabb(AABB::new_by_point(p0, p1), AABB::new_by_point(p0, p2))
+            })
+            .collect();
+        let (bvh, order) = Bvh::build(&boxes, LEAF_SIZE, true);
+        Ok(Self {
+            faces: order.iter().map(|&k| faces[k]).collect(),
+            positions,
+            normals,
+            uvs,
+            bvh,
+            mat,
+        })
+    }
+
+    fn corners(&self, face: &[MeshVertex; 3]) -> (Point3, Point3, Point3) {
+        (
+            self.positions[face[0].position as usize],
+            self.positions[face[1].position as usize],
+            self.positions[face[2].position as usize],
+        )
+    }
+
+    // Möller-Trumbore. Fills `rec` only when the face is hit within `ray_t`.
+    fn hit_face(
+        &self,
+        face: &[MeshVertex; 3],
+        ray: Ray,
+        ray_t: Interval,
+        rec: &mut HitRecord,
+    ) -> bool {
+        let (p0, p1, p2) = self.corners(face);
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+        let pvec = ray.direction().cross(&edge2);
+        let det = edge1.dot(&pvec);
+        if det.abs() < 1e-12 {
+            return false;
+        }
+        let inv_det = 1.0 / det;
+        let tvec = ray.origin() - p0;
+        let b1 = tvec.dot(&pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return false;
+        }
+        let qvec = tvec.cross(&edge1);
+        let b2 = ray.direction().dot(&qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return false;
+        }
+        let t = edge2.dot(&qvec) * inv_det;
+        if !ray_t.contain(t) {
+            return false;
+        }
+        let b0 = 1.0 - b1 - b2;
+
+        let cross = edge1.cross(&edge2);
+        rec.t = t;
+        rec.p = ray.at(t);
+        rec.set_face_normal(ray, cross.normalize());
+        if let [Some(n0), Some(n1), Some(n2)] = face.map(|v| v.normal) {
+            let n = self.normals[n0 as usize] * b0
+                + self.normals[n1 as usize] * b1
+                + self.normals[n2 as usize] * b2;
+            if !n.near_zero() {
+                rec.set_shading_normal(n.normalize());
+            }
+        }
+        // without UVs the corners get (0, 0), (1, 0) and (0, 1), the same as `Triangle`
+        match face.map(|v| v.uv) {
+            [Some(t0), Some(t1), Some(t2)] => {
+                let (t0, t1, t2) = (
+                    self.uvs[t0 as usize],
+                    self.uvs[t1 as usize],
+                    self.uvs[t2 as usize],
+                );
+                rec.u = b0 * t0[0] + b1 * t1[0] + b2 * t2[0];
+                rec.v = b0 * t0[1] + b1 * t1[1] + b2 * t2[1];
+                let uv_cross =
+                    (t1[0] - t0[0]) * (t2[1] - t0[1]) - (t1[1] - t0[1]) * (t2[0] - t0[0]);
+                rec.uv_scale = (uv_cross.abs() / cross.length()).sqrt();
+                (rec.dpdu, rec.dpdv) = uv_derivatives(
+                    edge1,
+                    edge2,
+                    [t1[0] - t0[0], t1[1] - t0[1]],
+                    [t2[0] - t0[0], t2[1] - t0[1]],
+                );
+            }
+            _ => {
+                rec.u = b1;
+                rec.v = b2;
+                rec.uv_scale = (1.0 / cross.length()).sqrt();
+                rec.dpdu = edge1;
+                rec.dpdv = edge2;
+            }
+        }
+        rec.mat = self.mat.as_ref().map(Arc::clone);
+        rec.set_object(self);
+        true
+    }
+}
+
+// One normal per position: the sum of the normals of the faces around it, weighted by their
+// area. Positions no face uses get a zero normal.
+pub fn vertex_normals(positions: &[Point3], faces: &[[MeshVertex; 3]]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::zero(); positions.len()];
+    for face in faces {
+        let p0 = positions[face[0].position as usize];
+        let p1 = positions[face[1].position as usize];
+        let p2 = positions[face[2].position as usize];
+        let n = (p1 - p0).cross(&(p2 - p0));
+        for vertex in face {
+            normals[vertex.position as usize] += n;
+        }
+    }
+    normals
+        .into_iter()
+        .map(|n| if n.near_zero() { n } else { n.normalize() })
+        .collect()
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, ray: Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        self.bvh.hit(ray, ray_t, |k, interval| {
+            if self.hit_face(&self.faces[k], ray, interval, rec) {
+                Some(rec.t)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn display(&self) {
+        println!("TriangleMesh ({} faces)", self.faces.len());
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        self.mat.as_ref().map(Arc::clone)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.bvh.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two triangles making the unit square at z = 0, with normals tilted apart along x.
+    fn square() -> TriangleMesh {
+        let positions = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let normals = vec![
+            Vec3::new(-1.0, 0.0, 1.0).normalize(),
+            Vec3::new(1.0, 0.0, 1.0).normalize(),
+        ];
+        let corner = |position: u32, normal: u32| MeshVertex {
+            position,
+            normal: Some(normal),
+            uv: None,
+        };
+        let faces = vec![
+            [corner(0, 0), corner(1, 1), corner(2, 1)],
+            [corner(0, 0), corner(2, 1), corner(3, 0)],
+        ];
+        TriangleMesh::new(positions, normals, Vec::new(), faces, None).unwrap()
+    }
+
+    #[test]
+    fn test_mesh_hit_interpolates_normals() {
+        let mesh = square();
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(Point3::new(0.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(mesh.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 1.0).abs() < 1e-12);
+        assert!(rec.front_face);
+        assert_eq!(rec.geometric_normal.z, 1.0);
+        // halfway between the corners at x = 0 and x = 1
+        assert!(rec.normal.x.abs() < 1e-12 && (rec.normal.z - 1.0).abs() < 1e-12);
+
+        let ray = Ray::new(Point3::new(0.9, 0.1, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(mesh.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!(!rec.front_face);
+        // seen from below, both normals point down
+        assert!(rec.geometric_normal.z < 0.0 && rec.normal.z < 0.0 && rec.normal.x < 0.0);
+
+        let ray = Ray::new(Point3::new(1.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(!mesh.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+    }
+
+    #[test]
+    fn test_mesh_bvh_finds_the_nearest_face() {
+        // a stack of 100 squares, one every 0.1 along z
+        let mut positions = Vec::new();
+        let mut faces = Vec::new();
+        for k in 0..100 {
+            let z = k as f64 * 0.1;
+            let base = positions.len() as u32;
+            positions.push(Point3::new(0.0, 0.0, z));
+            positions.push(Point3::new(1.0, 0.0, z));
+            positions.push(Point3::new(0.0, 1.0, z));
+            let corner = |i: u32| MeshVertex {
+                position: base + i,
+                normal: None,
+                uv: None,
+            };
+            faces.push([corner(0), corner(1), corner(2)]);
+        }
+        let mesh = TriangleMesh::new(positions, Vec::new(), Vec::new(), faces, None).unwrap();
+        assert!((mesh.bounding_box().z.max - 9.9).abs() < 1e-4);
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(Point3::new(0.2, 0.2, 20.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(mesh.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.p.z - 9.9).abs() < 1e-9);
+        assert!((rec.u - 0.2).abs() < 1e-9 && (rec.v - 0.2).abs() < 1e-9);
+
+        let bad = vec![
+            [MeshVertex {
+                position: 7,
+                normal: None,
+                uv: None,
+            }; 3],
+        ];
+        assert!(TriangleMesh::new(Vec::new(), Vec::new(), Vec::new(), bad, None).is_err());
+    }
+}
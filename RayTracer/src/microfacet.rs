@@ -0,0 +1,619 @@
+use crate::util::{
+    random_double, Arc, Color, HitRecord, Material, Onb, Pdf, Ray, ScatterRecord, Solid_Color,
+    Texture, Vec3,
+};
+use std::f64::consts::PI;
+
+// Roughness below this is treated as this; a perfectly smooth GGX lobe has no density to sample.
+const MIN_ALPHA: f64 = 1e-3;
+
+// The GGX (Trowbridge-Reitz) distribution of microfacet normals with the height-correlated
+// Smith masking-shadowing term. Directions are in the local frame of the surface, +z out.
+#[derive(Clone, Copy)]
+struct Ggx {
+    alpha: f64,
+}
+
+impl Ggx {
+    // `roughness` is perceptual: alpha = roughness^2.
+    fn new(roughness: f64) -> Self {
+        Self {
+            alpha: (roughness * roughness).max(MIN_ALPHA),
+        }
+    }
+
+    fn d(&self, h: Vec3) -> f64 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        let t = h.z * h.z * (a2 - 1.0) + 1.0;
+        a2 / (PI * t * t)
+    }
+
+    fn lambda(&self, w: Vec3) -> f64 {
+        let cos2 = w.z * w.z;
+        if cos2 == 0.0 {
+            return f64::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of `sample_visible` picking `h`: the normals `wo` sees, weighted by how much of
+    // them it sees.
+    fn visible_pdf(&self, wo: Vec3, h: Vec3) -> f64 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(&h).max(0.0) * self.d(h) / wo.z
+    }
+
+    // Heitz, "Sampling the GGX Distribution of Visible Normals" (2018): stretch the view
+    // direction to the unit-roughness case, pick a point on the projected hemisphere, and
+    // unstretch the normal there.
+    fn sample_visible(&self, wo: Vec3) -> Vec3 {
+        let vh = Vec3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalize();
+        let len_sq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len_sq > 0.0 {
+            Vec3::new(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+        let r = random_double().sqrt();
+        let phi = 2.0 * PI * random_double();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        Vec3::new(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(0.0)).normalize()
+    }
+}
+
+fn fresnel_schlick(f0: Color, cosine: f64) -> Color {
+    let white = Color::new(1.0, 1.0, 1.0);
+    f0 + (white - f0) * (1.0 - cosine).clamp(0.0, 1.0).powi(5)
+}
+
+// Unpolarized reflectance of a dielectric boundary; `eta` is the index on the far side over
+// the index on the near side. 1 under total internal reflection.
+fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+// Reflectance of a metal with complex index eta + ik, seen from air, for one wavelength.
+fn fresnel_conductor_channel(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_i.clamp(0.0, 1.0) * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+fn fresnel_conductor(cos_i: f64, eta: Color, k: Color) -> Color {
+    Color::new(
+        fresnel_conductor_channel(cos_i, eta.r, k.r),
+        fresnel_conductor_channel(cos_i, eta.g, k.g),
+        fresnel_conductor_channel(cos_i, eta.b, k.b),
+    )
+}
+
+// Complex refractive indices at red, green and blue (about 650, 550 and 450 nm).
+fn named_conductor(name: &str) -> Result<(Color, Color), String> {
+    let (eta, k) = match name {
+        "gold" => ([0.143, 0.374, 1.442], [3.983, 2.386, 1.603]),
+        "silver" => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+        "copper" => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+        "aluminium" => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+        other => {
+            return Err(format!(
+                "unknown metal `{}` (gold, silver, copper, aluminium)",
+                other
+            ))
+        }
+    };
+    Ok((
+        Color::new(eta[0], eta[1], eta[2]),
+        Color::new(k[0], k[1], k[2]),
+    ))
+}
+
+// A GGX microfacet surface in the metallic workflow: `metallic` 0 is a plastic, a diffuse base
+// under a clear coat reflecting 4% head on; 1 is a metal whose reflection is tinted by the base
+// color. In between blends the two, as do textures with both. `roughness` runs from 0, a
+// mirror, to 1. With `conductor`, the reflection instead follows the Fresnel equations of a
+// real metal and the base color is unused.
+pub struct Microfacet {
+    base_color: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+    metallic: Arc<dyn Texture>,
+    // complex index of refraction, eta and k
+    conductor: Option<(Color, Color)>,
+}
+
+// The inputs at one hit point.
+#[derive(Clone, Copy)]
+struct Surface {
+    base_color: Color,
+    ggx: Ggx,
+    metallic: f64,
+    conductor: Option<(Color, Color)>,
+}
+
+impl Surface {
+    fn fresnel(&self, cosine: f64) -> Color {
+        match self.conductor {
+            Some((eta, k)) => fresnel_conductor(cosine, eta, k),
+            None => {
+                let dielectric = Color::new(0.04, 0.04, 0.04);
+                let f0 = dielectric * (1.0 - self.metallic) + self.base_color * self.metallic;
+                fresnel_schlick(f0, cosine)
+            }
+        }
+    }
+
+    // How much is left for the diffuse base, per color.
+    fn diffuse(&self) -> Color {
+        if self.conductor.is_some() {
+            Color::new(0.0, 0.0, 0.0)
+        } else {
+            self.base_color * ((1.0 - self.metallic) / PI)
+        }
+    }
+
+    // How often the specular lobe is sampled rather than the diffuse one: in proportion to
+    // their rough share of the reflected light, seen from `wo`.
+    fn specular_probability(&self, wo: Vec3) -> f64 {
+        let specular = self.fresnel(wo.z).luminance();
+        let diffuse = self.diffuse().luminance() * PI * (1.0 - specular);
+        if diffuse <= 0.0 {
+            1.0
+        } else {
+            (specular / (specular + diffuse)).clamp(0.1, 0.9)
+        }
+    }
+
+    // f * cos(wi), both directions in the local frame.
+    fn value(&self, wo: Vec3, wi: Vec3) -> Color {
+        let black = Color::new(0.0, 0.0, 0.0);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return black;
+        }
+        let h = (wo + wi).normalize();
+        let fresnel = self.fresnel(wo.dot(&h));
+        let specular = fresnel * (self.ggx.d(h) * self.ggx.g(wo, wi) / (4.0 * wo.z));
+        let white = Color::new(1.0, 1.0, 1.0);
+        let diffuse = self.diffuse().element_mul(white - fresnel) * wi.z;
+        specular + diffuse
+    }
+}
+
+impl Microfacet {
+    pub fn new(
+        base_color: Arc<dyn Texture>,
+        roughness: Arc<dyn Texture>,
+        metallic: Arc<dyn Texture>,
+    ) -> Self {
+        Self {
+            base_color,
+            roughness,
+            metallic,
+            conductor: None,
+        }
+    }
+
+    // A metal with complex index of refraction `eta` + i`k`.
+    pub fn conductor(eta: Color, k: Color, roughness: Arc<dyn Texture>) -> Self {
+        Self {
+            base_color: Arc::new(Solid_Color::new_by_f64(1.0, 1.0, 1.0)),
+            roughness,
+            metallic: Arc::new(Solid_Color::new_by_f64(1.0, 1.0, 1.0)),
+            conductor: Some((eta, k)),
+        }
+    }
+
+    // gold, silver, copper or aluminium
+    pub fn named_conductor(name: &str, roughness: Arc<dyn Texture>) -> Result<Self, String> {
+        let (eta, k) = named_conductor(name)?;
+        Ok(Self::conductor(eta, k, roughness))
+    }
+
+    fn surface(&self, rec: &HitRecord) -> Surface {
+        let look_up = |texture: &Arc<dyn Texture>| {
+            texture.filtered_value(rec.u, rec.v, &rec.p, rec.footprint)
+        };
+        Surface {
+            base_color: look_up(&self.base_color),
+            ggx: Ggx::new(look_up(&self.roughness).luminance().clamp(0.0, 1.0)),
+            metallic: look_up(&self.metallic).luminance().clamp(0.0, 1.0),
+            conductor: self.conductor,
+        }
+    }
+}
+
+// Samples the GGX lobe by its visible normals or, with the rest of the probability, the
+// diffuse base by the cosine.
+struct MicrofacetPdf {
+    uvw: Onb,
+    wo: Vec3,
+    ggx: Ggx,
+    specular_probability: f64,
+}
+
+impl Pdf for MicrofacetPdf {
+    fn value(&self, direction: Vec3) -> f64 {
+        let wi = self.uvw.local(direction.normalize());
+        if wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = (self.wo + wi).normalize();
+        let specular = self.ggx.visible_pdf(self.wo, h) / (4.0 * self.wo.dot(&h));
+        let diffuse = wi.z / PI;
+        self.specular_probability * specular + (1.0 - self.specular_probability) * diffuse
+    }
+    fn generate(&self) -> Vec3 {
+        if random_double() < self.specular_probability {
+            let h = self.ggx.sample_visible(self.wo);
+            // reflecting about a facet can point below the surface; `value` gives that 0
+            self.uvw.transform(h * (2.0 * self.wo.dot(&h)) - self.wo)
+        } else {
+            self.uvw.transform(Vec3::random_cosine_direction())
+        }
+    }
+}
+
+// The outgoing direction (toward where the ray came from) in the frame of the shading normal.
+fn local_wo(uvw: &Onb, r_in: &Ray) -> Vec3 {
+    uvw.local(r_in.direction().normalize() * (-1.0))
+}
+
+impl Material for Microfacet {
+    fn scatter(&self, r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let surface = self.surface(hit_record);
+        let uvw = Onb::new(hit_record.normal);
+        let wo = local_wo(&uvw, r_in);
+        if wo.z <= 0.0 {
+            return false;
+        }
+        // for the albedo pass; the path weight comes from `scattering_value`
+        srec.attenuation = match surface.conductor {
+            Some(_) => surface.fresnel(1.0),
+            None => surface.base_color,
+        };
+        srec.pdf = Some(Arc::new(MicrofacetPdf {
+            uvw,
+            wo,
+            ggx: surface.ggx,
+            specular_probability: surface.specular_probability(wo),
+        }));
+        srec.skip_pdf = false;
+        true
+    }
+    // the density it samples with; the reflection itself is `scattering_value`
+    fn scattering_pdf(&self, r_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> f64 {
+        let uvw = Onb::new(hit_record.normal);
+        let wo = local_wo(&uvw, r_in);
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        let surface = self.surface(hit_record);
+        let pdf = MicrofacetPdf {
+            uvw,
+            wo,
+            ggx: surface.ggx,
+            specular_probability: surface.specular_probability(wo),
+        };
+        pdf.value(scattered.direction())
+    }
+    fn scattering_value(
+        &self,
+        r_in: &Ray,
+        hit_record: &HitRecord,
+        _srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Color {
+        // as for `Lambertian`, nothing gets through below the real surface
+        if scattered.direction().dot(&hit_record.geometric_normal) <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let uvw = Onb::new(hit_record.normal);
+        let wo = local_wo(&uvw, r_in);
+        let wi = uvw.local(scattered.direction().normalize());
+        self.surface(hit_record).value(wo, wi)
+    }
+}
+
+// Frosted glass: a GGX rough boundary that both reflects and refracts. The weight of a sampled
+// path is G / G1, so it stays at or below 1. Like `Dielectric`, it doesn't scale radiance by
+// the squared ratio of the indices on the way through; that cancels on leaving the object.
+pub struct RoughDielectric {
+    refraction_index: f64,
+    roughness: Arc<dyn Texture>,
+}
+
+impl RoughDielectric {
+    pub fn new(refraction_index: f64, roughness: Arc<dyn Texture>) -> Self {
+        Self {
+            refraction_index,
+            roughness,
+        }
+    }
+
+    // The local frame, the outgoing direction in it, the distribution, and the index on the far
+    // side over the index on this side.
+    fn setup(&self, r_in: &Ray, rec: &HitRecord) -> (Onb, Vec3, Ggx, f64) {
+        let uvw = Onb::new(rec.normal);
+        let wo = local_wo(&uvw, r_in);
+        let roughness = self
+            .roughness
+            .filtered_value(rec.u, rec.v, &rec.p, rec.footprint)
+            .luminance()
+            .clamp(0.0, 1.0);
+        let eta = if rec.front_face {
+            self.refraction_index
+        } else {
+            1.0 / self.refraction_index
+        };
+        (uvw, wo, Ggx::new(roughness), eta)
+    }
+}
+
+struct RoughDielectricPdf {
+    uvw: Onb,
+    wo: Vec3,
+    ggx: Ggx,
+    eta: f64,
+}
+
+impl RoughDielectricPdf {
+    // The microfacet normal that takes `wo` to `wi` and which way it went: true for a
+    // reflection. None if no facet facing `wo` could.
+    fn half_vector(&self, wi: Vec3) -> Option<(Vec3, bool)> {
+        let reflect = wi.z > 0.0;
+        let h = if reflect {
+            self.wo + wi
+        } else {
+            wi * self.eta + self.wo
+        };
+        if h.near_zero() {
+            return None;
+        }
+        let h = h.normalize();
+        let h = if h.z < 0.0 { h * (-1.0) } else { h };
+        // the facet must face `wo`, and a refraction has to cross it
+        if self.wo.dot(&h) <= 0.0 || (!reflect && wi.dot(&h) >= 0.0) {
+            return None;
+        }
+        Some((h, reflect))
+    }
+
+    // f * cos(wi) and the density of sampling `wi`.
+    fn evaluate(&self, wi: Vec3) -> (f64, f64) {
+        let Some((h, reflect)) = self.half_vector(wi) else {
+            return (0.0, 0.0);
+        };
+        let (wo, ggx) = (self.wo, self.ggx);
+        let fresnel = fresnel_dielectric(wo.dot(&h), self.eta);
+        let d = ggx.d(h);
+        let g = ggx.g(wo, wi);
+        if reflect {
+            let value = fresnel * d * g / (4.0 * wo.z);
+            let pdf = fresnel * ggx.visible_pdf(wo, h) / (4.0 * wo.dot(&h));
+            (value, pdf)
+        } else {
+            let denom = (wi.dot(&h) + wo.dot(&h) / self.eta).powi(2);
+            let jacobian = wi.dot(&h).abs() / denom;
+            let value = (1.0 - fresnel) * d * g * wo.dot(&h) * jacobian / wo.z;
+            let pdf = (1.0 - fresnel) * ggx.visible_pdf(wo, h) * jacobian;
+            (value, pdf)
+        }
+    }
+}
+
+impl Pdf for RoughDielectricPdf {
+    fn value(&self, direction: Vec3) -> f64 {
+        self.evaluate(self.uvw.local(direction.normalize())).1
+    }
+    fn generate(&self) -> Vec3 {
+        let h = self.ggx.sample_visible(self.wo);
+        let cos_i = self.wo.dot(&h);
+        let reflected = h * (2.0 * cos_i) - self.wo;
+        if random_double() < fresnel_dielectric(cos_i, self.eta) {
+            return self.uvw.transform(reflected);
+        }
+        let sin2_t = (1.0 - cos_i * cos_i) / (self.eta * self.eta);
+        let cos_t = (1.0 - sin2_t).max(0.0).sqrt();
+        let refracted = self.wo * (-1.0 / self.eta) + h * (cos_i / self.eta - cos_t);
+        self.uvw.transform(refracted)
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, r_in: &Ray, hit_record: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let (uvw, wo, ggx, eta) = self.setup(r_in, hit_record);
+        if wo.z <= 0.0 {
+            return false;
+        }
+        srec.attenuation = Color::new(1.0, 1.0, 1.0);
+        srec.pdf = Some(Arc::new(RoughDielectricPdf { uvw, wo, ggx, eta }));
+        srec.skip_pdf = false;
+        true
+    }
+    fn scattering_pdf(&self, r_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> f64 {
+        let (uvw, wo, ggx, eta) = self.setup(r_in, hit_record);
+        let pdf = RoughDielectricPdf { uvw, wo, ggx, eta };
+        pdf.evaluate(pdf.uvw.local(scattered.direction().normalize()))
+            .1
+    }
+    fn scattering_value(
+        &self,
+        r_in: &Ray,
+        hit_record: &HitRecord,
+        _srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Color {
+        let (uvw, wo, ggx, eta) = self.setup(r_in, hit_record);
+        if wo.z <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let pdf = RoughDielectricPdf { uvw, wo, ggx, eta };
+        let value = pdf
+            .evaluate(pdf.uvw.local(scattered.direction().normalize()))
+            .0;
+        Color::new(value, value, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Point3;
+
+    fn plain(base_color: Color, roughness: f64, metallic: f64) -> Microfacet {
+        Microfacet::new(
+            Arc::new(Solid_Color::new(base_color)),
+            Arc::new(Solid_Color::new_by_f64(roughness, roughness, roughness)),
+            Arc::new(Solid_Color::new_by_f64(metallic, metallic, metallic)),
+        )
+    }
+
+    fn hit_from_above() -> (Ray, HitRecord) {
+        let mut rec = HitRecord::default();
+        rec.p = Point3::zero();
+        rec.normal = Vec3::new(0.0, 0.0, 1.0);
+        rec.geometric_normal = rec.normal;
+        rec.front_face = true;
+        let ray = Ray::new(
+            Point3::new(-1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, -1.0).normalize(),
+            0.0,
+        );
+        (ray, rec)
+    }
+
+    // The average path weight f cos / pdf over directions the material samples itself: the
+    // share of the light it sends on, which may not be above 1.
+    fn albedo(material: &dyn Material) -> Color {
+        let (ray, rec) = hit_from_above();
+        let mut srec = ScatterRecord::default();
+        assert!(material.scatter(&ray, &rec, &mut srec));
+        let pdf = srec.pdf.clone().unwrap();
+        let n = 100000;
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let scattered = rec.spawn_ray(pdf.generate(), 0.0);
+            let pdf_value = pdf.value(scattered.direction());
+            if pdf_value > 0.0 {
+                let value = material.scattering_value(&ray, &rec, &srec, &scattered);
+                sum = sum + value / pdf_value;
+            }
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn test_ggx_visible_normals_integrate() {
+        let ggx = Ggx::new(0.6);
+        let wo = Vec3::new(0.5, 0.2, 0.7).normalize();
+        // the visible normal density integrates to 1 over the sphere, and sampling follows it
+        let n = 200000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += ggx.visible_pdf(wo, Vec3::random_unit_vector()) * 4.0 * PI;
+        }
+        assert!((sum / n as f64 - 1.0).abs() < 0.03);
+        for _ in 0..1000 {
+            let h = ggx.sample_visible(wo);
+            assert!((h.length() - 1.0).abs() < 1e-9 && ggx.visible_pdf(wo, h) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_fresnel() {
+        // 4% head on for glass, all of it at grazing angles and under total internal reflection
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-12);
+        assert!((fresnel_dielectric(0.0, 1.5) - 1.0).abs() < 1e-12);
+        assert_eq!(fresnel_dielectric(0.3, 1.0 / 1.5), 1.0);
+        // gold reflects red more than blue
+        let (eta, k) = named_conductor("gold").unwrap();
+        let gold = fresnel_conductor(1.0, eta, k);
+        assert!(gold.r > 0.9 && gold.b < 0.5);
+        assert!(fresnel_conductor(0.0, eta, k).b > 0.999);
+        assert!(named_conductor("tin").is_err());
+    }
+
+    #[test]
+    fn test_microfacet_conserves_energy() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        for roughness in [0.05, 0.4, 1.0] {
+            let metal = albedo(&plain(white, roughness, 1.0));
+            let plastic = albedo(&plain(white, roughness, 0.0));
+            assert!(metal.max_component() < 1.01, "{:?}", metal);
+            assert!(plastic.max_component() < 1.01, "{:?}", plastic);
+            // light bouncing between facets more than once is lost, most of all when rough
+            assert!(metal.r > 0.3, "{:?}", metal);
+            assert!(plastic.r > 0.9, "{:?}", plastic);
+        }
+        let black_plastic = plain(Color::new(0.0, 0.0, 0.0), 0.3, 0.0);
+        let albedo = albedo(&black_plastic);
+        assert!(albedo.r > 0.02 && albedo.r < 0.2, "{:?}", albedo);
+    }
+
+    #[test]
+    fn test_rough_glass() {
+        // rough enough that uniform directions can find the lobes
+        for roughness in [0.5, 0.8] {
+            let glass = RoughDielectric::new(
+                1.5,
+                Arc::new(Solid_Color::new_by_f64(roughness, roughness, roughness)),
+            );
+            let albedo = albedo(&glass);
+            assert!(albedo.r < 1.001 && albedo.r > 0.8, "{:?}", albedo);
+
+            // its sampling density covers the sphere once, reflection and refraction together
+            let (ray, rec) = hit_from_above();
+            let mut srec = ScatterRecord::default();
+            assert!(glass.scatter(&ray, &rec, &mut srec));
+            let pdf = srec.pdf.unwrap();
+            let n = 400000;
+            let mut sum = 0.0;
+            let mut below = 0;
+            for _ in 0..n {
+                let direction = Vec3::random_unit_vector();
+                sum += pdf.value(direction) * 4.0 * PI;
+            }
+            for _ in 0..1000 {
+                if pdf.generate().z < 0.0 {
+                    below += 1;
+                }
+            }
+            assert!((sum / n as f64 - 1.0).abs() < 0.05, "{}", sum / n as f64);
+            // most of it goes through
+            assert!(below > 800);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use crate::util::{Color, Image};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    // bilinear on the two mip levels nearest the footprint, blended
+    Trilinear,
+}
+
+impl TextureFilter {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "nearest" => Ok(TextureFilter::Nearest),
+            "bilinear" => Ok(TextureFilter::Bilinear),
+            "bicubic" => Ok(TextureFilter::Bicubic),
+            "trilinear" => Ok(TextureFilter::Trilinear),
+            other => Err(format!(
+                "unknown texture filter `{}` (nearest, bilinear, bicubic, trilinear)",
+                other
+            )),
+        }
+    }
+}
+
+// What happens to UVs outside [0, 1].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureWrap {
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+impl TextureWrap {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "repeat" => Ok(TextureWrap::Repeat),
+            "mirror" => Ok(TextureWrap::Mirror),
+            "clamp" => Ok(TextureWrap::Clamp),
+            other => Err(format!(
+                "unknown texture wrap `{}` (repeat, mirror, clamp)",
+                other
+            )),
+        }
+    }
+
+    // The texel `x` lands on in a row of `n`.
+    fn apply(&self, x: i64, n: usize) -> usize {
+        let n = n as i64;
+        let x = match self {
+            TextureWrap::Repeat => x.rem_euclid(n),
+            TextureWrap::Mirror => {
+                let m = x.rem_euclid(2 * n);
+                if m < n {
+                    m
+                } else {
+                    2 * n - 1 - m
+                }
+            }
+            TextureWrap::Clamp => x.clamp(0, n - 1),
+        };
+        x as usize
+    }
+}
+
+struct Level {
+    width: usize,
+    height: usize,
+    texels: Vec<[f32; 3]>,
+}
+
+impl Level {
+    fn texel(&self, x: i64, y: i64, wrap: TextureWrap) -> Color {
+        let [r, g, b] =
+            self.texels[wrap.apply(y, self.height) * self.width + wrap.apply(x, self.width)];
+        Color::new(r as f64, g as f64, b as f64)
+    }
+
+    // Texel centers sit at half-integer coordinates, so (0.5, 0.5) / size is exactly texel 0.
+    fn nearest(&self, u: f64, v: f64, wrap: TextureWrap) -> Color {
+        let x = (u * self.width as f64).floor() as i64;
+        let y = (v * self.height as f64).floor() as i64;
+        self.texel(x, y, wrap)
+    }
+
+    fn bilinear(&self, u: f64, v: f64, wrap: TextureWrap) -> Color {
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0, wrap) * (1.0 - fx) + self.texel(x0 + 1, y0, wrap) * fx;
+        let bottom =
+            self.texel(x0, y0 + 1, wrap) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1, wrap) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    // Catmull-Rom over the 4x4 texels around the point. It overshoots a little at hard edges;
+    // negative results are cut off.
+    fn bicubic(&self, u: f64, v: f64, wrap: TextureWrap) -> Color {
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (wx, wy) = (catmull_rom(x - x0), catmull_rom(y - y0));
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        for (j, wy) in wy.iter().enumerate() {
+            for (i, wx) in wx.iter().enumerate() {
+                sum = sum + self.texel(x0 + i as i64 - 1, y0 + j as i64 - 1, wrap) * (wx * wy);
+            }
+        }
+        Color::new(sum.r.max(0.0), sum.g.max(0.0), sum.b.max(0.0))
+    }
+
+    // Each texel of the next level is the average of the 2x2 texels under it; a leftover odd
+    // row or column is folded into the last one.
+    fn downsample(&self) -> Level {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut texels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let xs = 2 * x..(2 * x + 2 + usize::from(x == width - 1)).min(self.width);
+                let ys = 2 * y..(2 * y + 2 + usize::from(y == height - 1)).min(self.height);
+                let mut sum = [0.0f32; 3];
+                for sy in ys.clone() {
+                    for sx in xs.clone() {
+                        let texel = self.texels[sy * self.width + sx];
+                        for c in 0..3 {
+                            sum[c] += texel[c];
+                        }
+                    }
+                }
+                let n = (xs.len() * ys.len()) as f32;
+                texels.push(sum.map(|s| s / n));
+            }
+        }
+        Level {
+            width,
+            height,
+            texels,
+        }
+    }
+}
+
+fn catmull_rom(f: f64) -> [f64; 4] {
+    let (f2, f3) = (f * f, f * f * f);
+    [
+        0.5 * (-f + 2.0 * f2 - f3),
+        0.5 * (2.0 - 5.0 * f2 + 3.0 * f3),
+        0.5 * (f + 4.0 * f2 - 3.0 * f3),
+        0.5 * (f3 - f2),
+    ]
+}
+
+// An image and its chain of half-size copies down to 1x1, so a lookup whose footprint covers
+// many texels can read one level where they are already averaged instead of picking one of them.
+pub struct MipMap {
+    levels: Vec<Level>,
+}
+
+impl MipMap {
+    pub fn new(image: &Image) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut texels = Vec::with_capacity(width * height);
+        for j in 0..height as u32 {
+            for i in 0..width as u32 {
+                let (r, g, b) = image.pixel_data(i, j).unwrap_or((0, 0, 0));
+                texels.push([r, g, b].map(|c| c as f32 / 255.0));
+            }
+        }
+        Self::from_texels(width, height, texels)
+    }
+
+    fn from_texels(width: usize, height: usize, texels: Vec<[f32; 3]>) -> Self {
+        let mut levels = vec![Level {
+            width,
+            height,
+            texels,
+        }];
+        if width == 0 || height == 0 {
+            return Self { levels };
+        }
+        loop {
+            let last = levels.last().unwrap();
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    // `u` runs along the rows and `v` down the image, both over [0, 1] for the whole image.
+    // `footprint` is the width of the lookup in the same units; only trilinear filtering uses it.
+    pub fn lookup(
+        &self,
+        u: f64,
+        v: f64,
+        footprint: f64,
+        filter: TextureFilter,
+        wrap: TextureWrap,
+    ) -> Color {
+        let base = &self.levels[0];
+        match filter {
+            TextureFilter::Nearest => base.nearest(u, v, wrap),
+            TextureFilter::Bilinear => base.bilinear(u, v, wrap),
+            TextureFilter::Bicubic => base.bicubic(u, v, wrap),
+            TextureFilter::Trilinear => {
+                let texels = footprint * base.width.max(base.height) as f64;
+                let level = if texels > 1.0 { texels.log2() } else { 0.0 };
+                let last = self.levels.len() - 1;
+                if level >= last as f64 {
+                    return self.levels[last].bilinear(u, v, wrap);
+                }
+                let (fine, t) = (level.floor() as usize, level.fract());
+                let color = self.levels[fine].bilinear(u, v, wrap);
+                if t == 0.0 {
+                    return color;
+                }
+                color * (1.0 - t) + self.levels[fine + 1].bilinear(u, v, wrap) * t
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4x2 image: black and white columns, alternating.
+    fn stripes() -> MipMap {
+        let texels = (0..8)
+            .map(|k| if k % 2 == 0 { [0.0; 3] } else { [1.0; 3] })
+            .collect();
+        MipMap::from_texels(4, 2, texels)
+    }
+
+    #[test]
+    fn test_wrap_modes() {
+        assert_eq!(TextureWrap::Repeat.apply(-1, 4), 3);
+        assert_eq!(TextureWrap::Repeat.apply(9, 4), 1);
+        assert_eq!(TextureWrap::Mirror.apply(-1, 4), 0);
+        assert_eq!(TextureWrap::Mirror.apply(5, 4), 2);
+        assert_eq!(TextureWrap::Clamp.apply(-3, 4), 0);
+        assert_eq!(TextureWrap::Clamp.apply(7, 4), 3);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mipmap = stripes();
+        assert_eq!(mipmap.levels.len(), 3);
+        let (repeat, clamp) = (TextureWrap::Repeat, TextureWrap::Clamp);
+        let lookup = |u: f64, filter, wrap| mipmap.lookup(u, 0.25, 0.0, filter, wrap).r;
+
+        // on a texel center every filter gives the texel itself
+        for filter in [
+            TextureFilter::Nearest,
+            TextureFilter::Bilinear,
+            TextureFilter::Bicubic,
+            TextureFilter::Trilinear,
+        ] {
+            assert!((lookup(0.375, filter, repeat) - 1.0).abs() < 1e-6);
+        }
+        // halfway between a black and a white texel
+        assert!((lookup(0.25, TextureFilter::Bilinear, repeat) - 0.5).abs() < 1e-6);
+        assert_eq!(lookup(0.25, TextureFilter::Nearest, repeat), 1.0);
+        // past the left edge, repeat reaches around to the white last column, clamp does not
+        assert!((lookup(0.0, TextureFilter::Bilinear, repeat) - 0.5).abs() < 1e-6);
+        assert_eq!(lookup(0.0, TextureFilter::Bilinear, clamp), 0.0);
+        assert_eq!(lookup(1.125, TextureFilter::Nearest, repeat), 0.0);
+        assert_eq!(
+            lookup(-0.125, TextureFilter::Nearest, TextureWrap::Mirror),
+            0.0
+        );
+
+        // a footprint as wide as the image reads the 1x1 level, the average
+        let wide = mipmap.lookup(0.375, 0.25, 1.0, TextureFilter::Trilinear, repeat);
+        assert!((wide.r - 0.5).abs() < 1e-6);
+        let far = mipmap.lookup(0.375, 0.25, 100.0, TextureFilter::Trilinear, repeat);
+        assert!((far.r - 0.5).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,460 @@
+use crate::util::{
+    vertex_normals, BvhNode, Color, Dielectric, DiffuseLight, Hittable, HittableList,
+    Image_Texture, Lambertian, Material, MeshVertex, Metal, Point3, Solid_Color, Texture,
+    TriangleMesh, Vec3,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+// Wavefront OBJ models with their MTL materials.
+//
+// Reads `v`, `vt`, `vn`, `f`, `usemtl` and `mtllib`. Faces may have any number of vertices
+// (they are split into a fan) and any of the `v`, `v/vt`, `v//vn` and `v/vt/vn` forms, with
+// negative indices counting back from the last vertex. Objects, groups, smoothing groups, lines
+// and points are skipped. The faces of each material become one `TriangleMesh`.
+
+// A parsed OBJ file. Every face carries the index of its `usemtl` name in `materials`, or
+// `None` before the first `usemtl`.
+struct ObjData {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<[f64; 2]>,
+    faces: Vec<([MeshVertex; 3], Option<usize>)>,
+    materials: Vec<String>,
+    libraries: Vec<String>,
+}
+
+// One `newmtl` of an MTL file, with the defaults of the format for what it leaves out.
+#[derive(Clone, Debug, PartialEq)]
+struct MtlMaterial {
+    kd: Color,
+    map_kd: Option<String>,
+    ks: Color,
+    ns: f64,
+    ni: f64,
+    d: f64,
+    ke: Color,
+}
+
+impl MtlMaterial {
+    fn default() -> Self {
+        Self {
+            kd: Color::new(0.8, 0.8, 0.8),
+            map_kd: None,
+            ks: Color::new(0.0, 0.0, 0.0),
+            ns: 0.0,
+            ni: 1.5,
+            d: 1.0,
+            ke: Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Emissive materials become lights, see-through ones glass, and ones that reflect more
+    // than they scatter metal, with a fuzz that shrinks as the highlight gets sharper.
+    // Everything else is diffuse, textured by `map_Kd` (relative to `dir`) if it has one.
+    fn build(&self, dir: &Path) -> Result<Arc<dyn Material>, String> {
+        if self.ke.max_component() > 0.0 {
+            return Ok(Arc::new(DiffuseLight::new_by_color(self.ke)));
+        }
+        if self.d < 1.0 {
+            return Ok(Arc::new(Dielectric::new(self.ni)));
+        }
+        if self.map_kd.is_none() && self.ks.max_component() > self.kd.max_component() {
+            let fuzz = (2.0 / (self.ns + 2.0)).sqrt();
+            return Ok(Arc::new(Metal::new(self.ks, fuzz)));
+        }
+        let texture: Arc<dyn Texture> = match &self.map_kd {
+            Some(file) => {
+                let path = dir.join(file);
+                Arc::new(Image_Texture::from_file(&path.to_string_lossy())?)
+            }
+            None => Arc::new(Solid_Color::new(self.kd)),
+        };
+        Ok(Arc::new(Lambertian::new(texture)))
+    }
+}
+
+// Loads an OBJ file. With `material` every face uses it; otherwise the materials come from the
+// file's MTL libraries. With `smooth` the vertex normals of the file are interpolated, or made
+// from the faces if it has none; otherwise every face is flat.
+pub fn load_obj(
+    path: &str,
+    material: Option<Arc<dyn Material>>,
+    smooth: bool,
+) -> Result<Arc<dyn Hittable>, String> {
+    let source =
+        fs::read_to_string(path).map_err(|e| format!("failed to open `{}`: {}", path, e))?;
+    let mut data = parse_obj(&source).map_err(|e| format!("{}: {}", path, e))?;
+    if data.faces.is_empty() {
+        return Err(format!("`{}` has no faces", path));
+    }
+
+    // one material per `usemtl` name, plus the one for faces before any `usemtl`
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let default_material: Arc<dyn Material> = match &material {
+        Some(mat) => mat.clone(),
+        None => Arc::new(Lambertian::new_by_color(Color::new(0.8, 0.8, 0.8))),
+    };
+    let mut slots = vec![default_material.clone(); data.materials.len()];
+    if material.is_none() {
+        let mut library = HashMap::new();
+        for file in &data.libraries {
+            let mtl_path = dir.join(file);
+            let mtl_source = fs::read_to_string(&mtl_path)
+                .map_err(|e| format!("failed to open `{}`: {}", mtl_path.display(), e))?;
+            let parsed =
+                parse_mtl(&mtl_source).map_err(|e| format!("{}: {}", mtl_path.display(), e))?;
+            library.extend(parsed);
+        }
+        for (slot, name) in slots.iter_mut().zip(&data.materials) {
+            let mtl = library
+                .get(name)
+                .ok_or_else(|| format!("`{}` uses unknown material `{}`", path, name))?;
+            *slot = mtl.build(dir)?;
+        }
+    }
+
+    if !smooth {
+        data.normals.clear();
+        for (face, _) in data.faces.iter_mut() {
+            face.iter_mut().for_each(|vertex| vertex.normal = None);
+        }
+    } else if data.normals.is_empty() {
+        let faces: Vec<[MeshVertex; 3]> = data.faces.iter().map(|(face, _)| *face).collect();
+        data.normals = vertex_normals(&data.positions, &faces);
+        for (face, _) in data.faces.iter_mut() {
+            face.iter_mut()
+                .for_each(|vertex| vertex.normal = Some(vertex.position));
+        }
+    }
+
+    // faces before any `usemtl` go last
+    let mut groups: Vec<Vec<[MeshVertex; 3]>> = vec![Vec::new(); data.materials.len() + 1];
+    for (face, slot) in &data.faces {
+        groups[slot.unwrap_or(data.materials.len())].push(*face);
+    }
+    let mut meshes = HittableList::new();
+    for (k, faces) in groups.into_iter().enumerate() {
+        if faces.is_empty() {
+            continue;
+        }
+        let mat = slots.get(k).unwrap_or(&default_material).clone();
+        let mesh = compact_mesh(&data, faces, mat).map_err(|e| format!("{}: {}", path, e))?;
+        meshes.add(Arc::new(mesh));
+    }
+    if meshes.objects.len() == 1 {
+        return Ok(meshes.objects.pop().unwrap());
+    }
+    Ok(Arc::new(BvhNode::new_by_object_list(&meshes)))
+}
+
+// A mesh of `faces` that only keeps the vertices they use.
+fn compact_mesh(
+    data: &ObjData,
+    mut faces: Vec<[MeshVertex; 3]>,
+    mat: Arc<dyn Material>,
+) -> Result<TriangleMesh, String> {
+    fn keep<T: Copy>(index: u32, all: &[T], kept: &mut Vec<T>, map: &mut HashMap<u32, u32>) -> u32 {
+        *map.entry(index).or_insert_with(|| {
+            kept.push(all[index as usize]);
+            kept.len() as u32 - 1
+        })
+    }
+    let (mut positions, mut normals, mut uvs) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut position_map, mut normal_map, mut uv_map) =
+        (HashMap::new(), HashMap::new(), HashMap::new());
+    for vertex in faces.iter_mut().flatten() {
+        vertex.position = keep(
+            vertex.position,
+            &data.positions,
+            &mut positions,
+            &mut position_map,
+        );
+        vertex.normal = vertex
+            .normal
+            .map(|n| keep(n, &data.normals, &mut normals, &mut normal_map));
+        vertex.uv = vertex.uv.map(|t| keep(t, &data.uvs, &mut uvs, &mut uv_map));
+    }
+    TriangleMesh::new(positions, normals, uvs, faces, Some(mat))
+}
+
+fn parse_numbers<const N: usize>(tokens: &[&str], at_least: usize) -> Result<[f64; N], String> {
+    if tokens.len() < at_least || tokens.len() > N {
+        return Err(format!(
+            "expected {} numbers, found {}",
+            if at_least == N {
+                N.to_string()
+            } else {
+                format!("{} to {}", at_least, N)
+            },
+            tokens.len()
+        ));
+    }
+    let mut values = [0.0; N];
+    for (value, token) in values.iter_mut().zip(tokens) {
+        *value = token
+            .parse()
+            .map_err(|_| format!("`{}` is not a number", token))?;
+    }
+    Ok(values)
+}
+
+// OBJ indices start at 1; negative ones count back from the last of the `count` so far.
+fn parse_index(token: &str, count: usize, what: &str) -> Result<u32, String> {
+    let index: i64 = token
+        .parse()
+        .map_err(|_| format!("`{}` is not a {} index", token, what))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        count as i64 + index
+    };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!(
+            "{} index {} is out of range ({} defined)",
+            what, index, count
+        ));
+    }
+    Ok(resolved as u32)
+}
+
+fn parse_obj(source: &str) -> Result<ObjData, String> {
+    let mut data = ObjData {
+        positions: Vec::new(),
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        faces: Vec::new(),
+        materials: Vec::new(),
+        libraries: Vec::new(),
+    };
+    let mut current_material = None;
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+        let values = &tokens[1..];
+        let result: Result<(), String> = (|| {
+            match tokens[0] {
+                "v" => {
+                    // an optional fourth (w) coordinate is ignored
+                    let [x, y, z, _] = parse_numbers::<4>(values, 3)?;
+                    data.positions.push(Point3::new(x, y, z));
+                }
+                "vn" => {
+                    let [x, y, z] = parse_numbers::<3>(values, 3)?;
+                    data.normals.push(Vec3::new(x, y, z));
+                }
+                "vt" => {
+                    let [u, v, _] = parse_numbers::<3>(values, 1)?;
+                    data.uvs.push([u, v]);
+                }
+                "f" => {
+                    if values.len() < 3 {
+                        return Err(format!(
+                            "a face needs at least 3 vertices, found {}",
+                            values.len()
+                        ));
+                    }
+                    let mut corners = Vec::with_capacity(values.len());
+                    for value in values {
+                        let parts: Vec<&str> = value.split('/').collect();
+                        if parts.len() > 3 || parts[0].is_empty() {
+                            return Err(format!("bad face vertex `{}`", value));
+                        }
+                        let optional = |k: usize, count: usize, what: &str| match parts.get(k) {
+                            Some(part) if !part.is_empty() => {
+                                parse_index(part, count, what).map(Some)
+                            }
+                            _ => Ok(None),
+                        };
+                        corners.push(MeshVertex {
+                            position: parse_index(parts[0], data.positions.len(), "vertex")?,
+                            uv: optional(1, data.uvs.len(), "texture coordinate")?,
+                            normal: optional(2, data.normals.len(), "normal")?,
+                        });
+                    }
+                    for k in 1..corners.len() - 1 {
+                        data.faces
+                            .push(([corners[0], corners[k], corners[k + 1]], current_material));
+                    }
+                }
+                "usemtl" => {
+                    let name = values.join(" ");
+                    if name.is_empty() {
+                        return Err(String::from("`usemtl` needs a material name"));
+                    }
+                    let slot = match data.materials.iter().position(|m| *m == name) {
+                        Some(slot) => slot,
+                        None => {
+                            data.materials.push(name);
+                            data.materials.len() - 1
+                        }
+                    };
+                    current_material = Some(slot);
+                }
+                "mtllib" => {
+                    if values.is_empty() {
+                        return Err(String::from("`mtllib` needs a file name"));
+                    }
+                    data.libraries
+                        .extend(values.iter().map(|file| file.to_string()));
+                }
+                _ => {}
+            }
+            Ok(())
+        })();
+        result.map_err(|e| format!("line {}: {}", index + 1, e))?;
+    }
+    Ok(data)
+}
+
+fn parse_mtl(source: &str) -> Result<HashMap<String, MtlMaterial>, String> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+        let values = &tokens[1..];
+        let result: Result<(), String> = (|| {
+            if tokens[0] == "newmtl" {
+                if values.is_empty() {
+                    return Err(String::from("`newmtl` needs a material name"));
+                }
+                if let Some((name, mtl)) = current.take() {
+                    materials.insert(name, mtl);
+                }
+                current = Some((values.join(" "), MtlMaterial::default()));
+                return Ok(());
+            }
+            let mtl = match current.as_mut() {
+                Some((_, mtl)) => mtl,
+                None => return Err(format!("`{}` before any `newmtl`", tokens[0])),
+            };
+            let color = |values: &[&str]| {
+                parse_numbers::<3>(values, 3).map(|[r, g, b]| Color::new(r, g, b))
+            };
+            match tokens[0] {
+                "Kd" => mtl.kd = color(values)?,
+                "Ks" => mtl.ks = color(values)?,
+                "Ke" => mtl.ke = color(values)?,
+                "Ns" => mtl.ns = parse_numbers::<1>(values, 1)?[0],
+                "Ni" => mtl.ni = parse_numbers::<1>(values, 1)?[0],
+                "d" => mtl.d = parse_numbers::<1>(values, 1)?[0],
+                "Tr" => mtl.d = 1.0 - parse_numbers::<1>(values, 1)?[0],
+                // options such as `-s 1 1 1` come before the file name
+                "map_Kd" => match values.last() {
+                    Some(file) => mtl.map_kd = Some(file.to_string()),
+                    None => return Err(String::from("`map_Kd` needs a file name")),
+                },
+                _ => {}
+            }
+            Ok(())
+        })();
+        result.map_err(|e| format!("line {}: {}", index + 1, e))?;
+    }
+    if let Some((name, mtl)) = current {
+        materials.insert(name, mtl);
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_obj() {
+        let source = "
+            mtllib box.mtl
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0 1.0
+            vt 0 0
+            vt 1
+            vn 0 0 1
+            f 1 2 3 4            # a quad becomes two triangles
+            usemtl red
+            f -4/1 -3/2/1 -2//1  # relative indices
+            usemtl blue
+            f 1 2 3
+            usemtl red
+            f 2 3 4
+        ";
+        let data = parse_obj(source).unwrap();
+        assert_eq!(data.libraries, vec!["box.mtl"]);
+        assert_eq!(data.positions.len(), 4);
+        assert_eq!(data.uvs[1], [1.0, 0.0]);
+        assert_eq!(data.materials, vec!["red", "blue"]);
+        let slots: Vec<Option<usize>> = data.faces.iter().map(|(_, slot)| *slot).collect();
+        assert_eq!(slots, vec![None, None, Some(0), Some(1), Some(0)]);
+        assert_eq!(data.faces[1].0.map(|v| v.position), [0, 2, 3]);
+        let [a, b, c] = data.faces[2].0;
+        assert_eq!((a.position, a.uv, a.normal), (0, Some(0), None));
+        assert_eq!((b.position, b.uv, b.normal), (1, Some(1), Some(0)));
+        assert_eq!((c.position, c.uv, c.normal), (2, None, Some(0)));
+    }
+
+    #[test]
+    fn test_malformed_obj_is_an_error() {
+        for (source, message) in [
+            ("v 0 0\n", "line 1: expected 3 to 4 numbers, found 2"),
+            ("v 0 0 x\n", "line 1: `x` is not a number"),
+            (
+                "v 0 0 0\nv 1 0 0\nf 1 2\n",
+                "line 3: a face needs at least 3 vertices",
+            ),
+            (
+                "v 0 0 0\nf 1 2 3\n",
+                "line 2: vertex index 2 is out of range (1 defined)",
+            ),
+            (
+                "v 0 0 0\nf 1/1 1 1\n",
+                "line 2: texture coordinate index 1 is out of range",
+            ),
+            (
+                "v 0 0 0\nf 0 1 1\n",
+                "line 2: vertex index 0 is out of range",
+            ),
+            (
+                "v 0 0 0\nf 1/2/3/4 1 1\n",
+                "line 2: bad face vertex `1/2/3/4`",
+            ),
+        ] {
+            let error = parse_obj(source).err().unwrap();
+            assert!(error.starts_with(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_parse_mtl() {
+        let source = "
+            newmtl cow
+            Kd 0.5 0.4 0.3
+            map_Kd -s 1 1 1 spot texture.png
+            newmtl glass
+            Ni 1.45
+            Tr 0.9
+            newmtl lamp
+            Ke 4 4 4
+        ";
+        let materials = parse_mtl(source).unwrap();
+        assert_eq!(materials.len(), 3);
+        assert_eq!(materials["cow"].kd, Color::new(0.5, 0.4, 0.3));
+        assert_eq!(materials["cow"].map_kd.as_deref(), Some("texture.png"));
+        assert_eq!(materials["glass"].ni, 1.45);
+        assert!((materials["glass"].d - 0.1).abs() < 1e-12);
+        assert_eq!(materials["lamp"].ke, Color::new(4.0, 4.0, 4.0));
+        assert_eq!(materials["lamp"].kd, MtlMaterial::default().kd);
+
+        assert!(parse_mtl("Kd 1 1 1\n").is_err());
+        assert!(parse_mtl("newmtl a\nKs 1 1\n").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::util::{random_double, Hittable, Point3, Vec3};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Orthonormal basis around a normal, used to turn directions sampled around +z into world space.
+pub struct Onb {
+    axis: [Vec3; 3],
+}
+
+impl Onb {
+    pub fn new(n: Vec3) -> Self {
+        let w = n.normalize();
+        let a = if w.x.abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = w.cross(&a).normalize();
+        let u = v.cross(&w);
+        Self { axis: [u, v, w] }
+    }
+    pub fn w(&self) -> Vec3 {
+        self.axis[2]
+    }
+    pub fn transform(&self, v: Vec3) -> Vec3 {
+        self.axis[0] * v.x + self.axis[1] * v.y + self.axis[2] * v.z
+    }
+    // The inverse of `transform`: world to local coordinates.
+    pub fn local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(
+            v.dot(&self.axis[0]),
+            v.dot(&self.axis[1]),
+            v.dot(&self.axis[2]),
+        )
+    }
+}
+
+pub trait Pdf: Send + Sync {
+    // Density with respect to solid angle of sampling `direction`.
+    fn value(&self, direction: Vec3) -> f64;
+    fn generate(&self) -> Vec3;
+}
+
+pub struct SpherePdf {}
+
+impl SpherePdf {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Pdf for SpherePdf {
+    fn value(&self, _direction: Vec3) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+    fn generate(&self) -> Vec3 {
+        Vec3::random_unit_vector()
+    }
+}
+
+pub struct CosinePdf {
+    uvw: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(w: Vec3) -> Self {
+        Self { uvw: Onb::new(w) }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: Vec3) -> f64 {
+        let cosine_theta = direction.normalize().dot(&self.uvw.w());
+        (cosine_theta / PI).max(0.0)
+    }
+    fn generate(&self) -> Vec3 {
+        self.uvw.transform(Vec3::random_cosine_direction())
+    }
+}
+
+// Directions of a fuzzy reflection: `reflected` (unit length) plus a random point on a sphere of
+// radius `fuzz`, which is how Metal scatters.
+pub struct FuzzPdf {
+    reflected: Vec3,
+    fuzz: f64,
+}
+
+impl FuzzPdf {
+    pub fn new(reflected: Vec3, fuzz: f64) -> Self {
+        Self { reflected, fuzz }
+    }
+}
+
+impl Pdf for FuzzPdf {
+    fn value(&self, direction: Vec3) -> f64 {
+        // The direction crosses the fuzz sphere at up to two distances t. Each crossing
+        // converts the uniform area density 1/(4 pi fuzz^2) to solid angle: t^2 / (cos * area),
+        // and the cosine there is sqrt(discriminant) / fuzz.
+        let direction = direction.normalize();
+        let b = direction.dot(&self.reflected);
+        let discriminant = b * b - self.reflected.squared_length() + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let h = discriminant.sqrt();
+        let mut sum = 0.0;
+        for t in [b - h, b + h] {
+            if t > 0.0 {
+                sum += t * t;
+            }
+        }
+        sum / (4.0 * PI * self.fuzz * h)
+    }
+    fn generate(&self) -> Vec3 {
+        self.reflected + Vec3::random_unit_vector() * self.fuzz
+    }
+}
+
+// Samples directions from `origin` towards the objects, e.g. the lights of a scene.
+pub struct HittablePdf {
+    objects: Arc<dyn Hittable>,
+    origin: Point3,
+}
+
+impl HittablePdf {
+    pub fn new(objects: Arc<dyn Hittable>, origin: Point3) -> Self {
+        Self { objects, origin }
+    }
+}
+
+impl Pdf for HittablePdf {
+    fn value(&self, direction: Vec3) -> f64 {
+        self.objects.pdf_value(self.origin, direction)
+    }
+    fn generate(&self) -> Vec3 {
+        self.objects.random(self.origin)
+    }
+}
+
+// Picks either pdf with equal probability.
+pub struct MixturePdf {
+    p: [Arc<dyn Pdf>; 2],
+}
+
+impl MixturePdf {
+    pub fn new(p0: Arc<dyn Pdf>, p1: Arc<dyn Pdf>) -> Self {
+        Self { p: [p0, p1] }
+    }
+}
+
+impl Pdf for MixturePdf {
+    fn value(&self, direction: Vec3) -> f64 {
+        0.5 * self.p[0].value(direction) + 0.5 * self.p[1].value(direction)
+    }
+    fn generate(&self) -> Vec3 {
+        if random_double() < 0.5 {
+            self.p[0].generate()
+        } else {
+            self.p[1].generate()
+        }
+    }
+}
+
+// How light sampling and material sampling are weighted when both can produce the same path.
+// `Off` doesn't sample the lights separately: half the bounces are aimed at them instead (a
+// `MixturePdf`), and whatever light a bounce hits counts in full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MisHeuristic {
+    Balance,
+    Power,
+    Off,
+}
+
+impl MisHeuristic {
+    // Weight of the strategy that sampled with `pdf` against the other one.
+    pub fn weight(&self, pdf: f64, other_pdf: f64) -> f64 {
+        let (a, b) = match self {
+            MisHeuristic::Balance => (pdf, other_pdf),
+            MisHeuristic::Power => (pdf * pdf, other_pdf * other_pdf),
+            MisHeuristic::Off => (1.0, 0.0),
+        };
+        if a + b <= 0.0 {
+            return 0.0;
+        }
+        a / (a + b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{Disk, Quad, Sphere, Triangle};
+
+    #[test]
+    fn test_onb_is_orthonormal() {
+        let uvw = Onb::new(Vec3::new(1.0, 2.0, 3.0));
+        let u = uvw.transform(Vec3::new(1.0, 0.0, 0.0));
+        let v = uvw.transform(Vec3::new(0.0, 1.0, 0.0));
+        assert!((uvw.w().length() - 1.0).abs() < 1e-12);
+        assert!((u.length() - 1.0).abs() < 1e-12);
+        assert!(u.dot(&v).abs() < 1e-12);
+        assert!(v.dot(&uvw.w()).abs() < 1e-12);
+        assert!(u.cross(&v).dot(&uvw.w()) > 0.0);
+    }
+
+    #[test]
+    fn test_cosine_pdf_integrates_to_one() {
+        // Monte Carlo estimate of the integral over the sphere using uniform directions.
+        let pdf = CosinePdf::new(Vec3::new(0.0, 1.0, 0.0));
+        let n = 200000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += pdf.value(Vec3::random_unit_vector()) * 4.0 * PI;
+        }
+        assert!((sum / n as f64 - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_cosine_pdf_generates_upper_hemisphere() {
+        let normal = Vec3::new(0.3, -1.0, 0.2);
+        let pdf = CosinePdf::new(normal);
+        for _ in 0..1000 {
+            let d = pdf.generate();
+            assert!(d.dot(&normal) >= 0.0);
+            assert!((d.length() - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_light_pdfs_integrate_to_one() {
+        let lights: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(Quad::new(
+                Point3::new(-1.0, 1.0, -1.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 2.0),
+                None,
+            )),
+            Arc::new(Triangle::new(
+                Point3::new(-1.0, 1.0, -1.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 2.0),
+                [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+                None,
+            )),
+            Arc::new(Disk::new(
+                Point3::new(0.0, 1.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                None,
+            )),
+            Arc::new(Sphere::new_static(Point3::new(0.0, 2.0, 0.0), 1.0, None)),
+        ];
+        let n = 200000;
+        for light in lights {
+            let pdf = HittablePdf::new(light, Point3::zero());
+            let mut sum = 0.0;
+            for _ in 0..n {
+                sum += pdf.value(Vec3::random_unit_vector()) * 4.0 * PI;
+            }
+            assert!((sum / n as f64 - 1.0).abs() < 0.03);
+            // every generated direction must actually reach the light
+            for _ in 0..1000 {
+                assert!(pdf.value(pdf.generate()) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mixture_pdf_integrates_to_one() {
+        let light = Arc::new(Sphere::new_static(Point3::new(0.0, 2.0, 0.0), 1.0, None));
+        let mixture = MixturePdf::new(
+            Arc::new(HittablePdf::new(light, Point3::zero())),
+            Arc::new(CosinePdf::new(Vec3::new(0.0, 0.0, 1.0))),
+        );
+        let n = 200000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += mixture.value(Vec3::random_unit_vector()) * 4.0 * PI;
+        }
+        assert!((sum / n as f64 - 1.0).abs() < 0.03);
+        for _ in 0..1000 {
+            assert!(mixture.value(mixture.generate()) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_pdf_integrates_to_one() {
+        let reflected = Vec3::new(1.0, 1.0, 0.0).normalize();
+        let n = 200000;
+        for fuzz in [0.5, 1.5] {
+            let pdf = FuzzPdf::new(reflected, fuzz);
+            let mut sum = 0.0;
+            for _ in 0..n {
+                sum += pdf.value(Vec3::random_unit_vector()) * 4.0 * PI;
+            }
+            assert!((sum / n as f64 - 1.0).abs() < 0.03);
+        }
+    }
+
+    #[test]
+    fn test_mis_weights_sum_to_one() {
+        for heuristic in [MisHeuristic::Balance, MisHeuristic::Power] {
+            let w = heuristic.weight(0.3, 2.0) + heuristic.weight(2.0, 0.3);
+            assert!((w - 1.0).abs() < 1e-12);
+            assert_eq!(heuristic.weight(0.7, 0.0), 1.0);
+        }
+        assert!(MisHeuristic::Power.weight(2.0, 1.0) > MisHeuristic::Balance.weight(2.0, 1.0));
+        // without MIS a strategy counts in full
+        assert_eq!(MisHeuristic::Off.weight(0.3, 2.0), 1.0);
+    }
+}
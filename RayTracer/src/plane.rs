@@ -1,12 +1,43 @@
 use crate::util::{
-    Arc, HitRecord, Hittable, HittableList, Interval, Material, Point3, Ray, Vec3, AABB,
+    random_double, Arc, HitRecord, Hittable, HittableList, Interval, Material, Point3, Ray, Vec3,
+    AABB,
 };
+use std::f64::consts::PI;
 
 trait plane: Send + Sync {
     fn set_bounding_box(&mut self);
     fn is_interior(a: f64, b: f64, rec: &mut HitRecord) -> bool;
 }
 
+// Points are picked uniformly over the area, so the density w.r.t. solid angle is
+// dist^2 / (cos * area) at the point the direction hits.
+fn solid_angle_pdf(object: &dyn Hittable, area: f64, origin: Point3, direction: Vec3) -> f64 {
+    let mut rec = HitRecord::default();
+    if !object.hit(
+        Ray::new(origin, direction, 0.0),
+        Interval::new(0.001, f64::INFINITY),
+        &mut rec,
+    ) {
+        return 0.0;
+    }
+    let distance_squared = rec.t * rec.t * direction.squared_length();
+    let cosine = (direction.dot(&rec.geometric_normal) / direction.length()).abs();
+    distance_squared / (cosine * area)
+}
+
+// d(p)/du and d(p)/dv on a triangle with edges `e1` and `e2` whose UVs change by `t1` and
+// `t2` along them. Zero when the UVs don't span an area.
+pub fn uv_derivatives(e1: Vec3, e2: Vec3, t1: [f64; 2], t2: [f64; 2]) -> (Vec3, Vec3) {
+    let det = t1[0] * t2[1] - t1[1] * t2[0];
+    if det.abs() < 1e-12 {
+        return (Vec3::zero(), Vec3::zero());
+    }
+    (
+        (e1 * t2[1] - e2 * t1[1]) / det,
+        (e2 * t1[0] - e1 * t2[0]) / det,
+    )
+}
+
 pub struct Quad {
     bbox: AABB,
     Q: Point3,
@@ -16,6 +47,7 @@ pub struct Quad {
     normal: Vec3,
     D: f64,
     w: Vec3,
+    area: f64,
 }
 
 impl Quad {
@@ -30,6 +62,7 @@ impl Quad {
             normal: n.normalize(),
             D: n.normalize().dot(&(Q.to_vec3())),
             w: n / n.dot(&n),
+            area: n.length(),
         };
         ret.set_bounding_box();
         ret
@@ -71,10 +104,14 @@ impl Hittable for Quad {
             return false;
         }
 
+        rec.uv_scale = 1.0 / self.area.sqrt();
+        rec.dpdu = self.u;
+        rec.dpdv = self.v;
         rec.t = t;
         rec.p = intersection;
         rec.mat = self.mat.as_ref().map(Arc::clone);
         rec.set_face_normal(ray, self.normal);
+        rec.set_object(self);
         true
     }
 
@@ -89,6 +126,15 @@ impl Hittable for Quad {
     fn bounding_box(&self) -> AABB {
         return self.bbox;
     }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        solid_angle_pdf(self, self.area, origin, direction)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let p = self.Q + self.u * random_double() + self.v * random_double();
+        p - origin
+    }
 }
 
 pub struct Triangle {
@@ -101,6 +147,7 @@ pub struct Triangle {
     normal: Vec3,
     D: f64,
     w: Vec3,
+    area: f64,
 }
 
 impl Triangle {
@@ -122,6 +169,7 @@ impl Triangle {
             normal: n.normalize(),
             D: n.normalize().dot(&(Q.to_vec3())),
             w: n / n.dot(&n),
+            area: 0.5 * n.length(),
         };
         ret.set_bounding_box();
         ret
@@ -169,13 +217,18 @@ impl Hittable for Triangle {
         // compute the texture coordinates
         let tex_a_vec = Triangle::vector_sub(self.tex_ordinates[1], self.tex_ordinates[0]);
         let tex_b_vec = Triangle::vector_sub(self.tex_ordinates[2], self.tex_ordinates[0]);
-        rec.u = self.tex_ordinates[0][0] + rec.u * tex_a_vec[0] + rec.v * tex_b_vec[0];
-        rec.v = self.tex_ordinates[0][1] + rec.u * tex_a_vec[1] + rec.v * tex_b_vec[1];
+        let (a, b) = (rec.u, rec.v);
+        rec.u = self.tex_ordinates[0][0] + a * tex_a_vec[0] + b * tex_b_vec[0];
+        rec.v = self.tex_ordinates[0][1] + a * tex_a_vec[1] + b * tex_b_vec[1];
+        let tex_area = 0.5 * (tex_a_vec[0] * tex_b_vec[1] - tex_a_vec[1] * tex_b_vec[0]).abs();
+        rec.uv_scale = (tex_area / self.area).sqrt();
+        (rec.dpdu, rec.dpdv) = uv_derivatives(self.u, self.v, tex_a_vec, tex_b_vec);
 
         rec.t = t;
         rec.p = intersection;
         rec.mat = self.mat.as_ref().map(Arc::clone);
         rec.set_face_normal(ray, self.normal);
+        rec.set_object(self);
         true
     }
 
@@ -190,6 +243,22 @@ impl Hittable for Triangle {
     fn bounding_box(&self) -> AABB {
         return self.bbox;
     }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        solid_angle_pdf(self, self.area, origin, direction)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let mut a = random_double();
+        let mut b = random_double();
+        // fold the far half of the parallelogram back onto the triangle
+        if a + b > 1.0 {
+            a = 1.0 - a;
+            b = 1.0 - b;
+        }
+        let p = self.Q + self.u * a + self.v * b;
+        p - origin
+    }
 }
 
 // u and v should be orthogonal
@@ -203,6 +272,7 @@ pub struct Disk {
     normal: Vec3,
     D: f64,
     w: Vec3,
+    area: f64,
 }
 
 impl Disk {
@@ -217,6 +287,7 @@ impl Disk {
             normal: n.normalize(),
             D: n.normalize().dot(&(Q.to_vec3())),
             w: n / n.dot(&n),
+            area: PI * n.length(),
         };
         ret.set_bounding_box();
         ret
@@ -259,10 +330,15 @@ impl Hittable for Disk {
             return false;
         }
 
+        // the unit disk in UV covers the whole area
+        rec.uv_scale = (PI / self.area).sqrt();
+        rec.dpdu = self.u;
+        rec.dpdv = self.v;
         rec.t = t;
         rec.p = intersection;
         rec.mat = self.mat.as_ref().map(Arc::clone);
         rec.set_face_normal(ray, self.normal);
+        rec.set_object(self);
         true
     }
 
@@ -277,6 +353,16 @@ impl Hittable for Disk {
     fn bounding_box(&self) -> AABB {
         return self.bbox;
     }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        solid_angle_pdf(self, self.area, origin, direction)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let p = Vec3::random_in_unit_disk();
+        let p = self.Q + self.u * p.x + self.v * p.y;
+        p - origin
+    }
 }
 
 pub fn get_box(a: Point3, b: Point3, mat: Option<Arc<dyn Material>>) -> Arc<HittableList> {
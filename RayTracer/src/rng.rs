@@ -0,0 +1,117 @@
+use crate::util::Sampler;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+// Every random number comes from the current thread. While rendering, `start_sample` points it
+// at one sample of a `Sampler` and each call takes the next dimension, so what a sample sees
+// doesn't depend on which thread runs it or in what order. Otherwise (building the scene, the
+// feature pass) it is a SplitMix64 stream set by `seed_random`.
+thread_local! {
+    static STATE: RefCell<Stream> = RefCell::new(Stream {
+        sampler: None,
+        pixel: 0,
+        index: 0,
+        dimension: 0,
+        state: 0,
+    });
+}
+
+struct Stream {
+    sampler: Option<Arc<dyn Sampler>>,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+    state: u64,
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Hashes the keys into one seed, so nearby pixels or samples get unrelated streams.
+pub fn hash_seed(keys: &[u64]) -> u64 {
+    keys.iter().fold(0, |h, k| {
+        mix(h.wrapping_add(GOLDEN_GAMMA) ^ k.wrapping_mul(GOLDEN_GAMMA))
+    })
+}
+
+// `hash_seed` of the bytes of `text`, e.g. to tell scenes apart by their name or source.
+pub fn hash_name(text: &str) -> u64 {
+    let bytes: Vec<u64> = text.bytes().map(u64::from).collect();
+    hash_seed(&bytes)
+}
+
+pub fn seed_random(seed: u64) {
+    STATE.with(|stream| {
+        let mut stream = stream.borrow_mut();
+        stream.sampler = None;
+        stream.state = seed;
+    });
+}
+
+pub fn start_sample(sampler: &Arc<dyn Sampler>, pixel: u64, index: u32) {
+    STATE.with(|stream| {
+        let mut stream = stream.borrow_mut();
+        let same = matches!(&stream.sampler, Some(current) if Arc::ptr_eq(current, sampler));
+        if !same {
+            stream.sampler = Some(Arc::clone(sampler));
+        }
+        stream.pixel = pixel;
+        stream.index = index;
+        stream.dimension = 0;
+    });
+}
+
+// Uniform in [0, 1).
+pub fn random_double() -> f64 {
+    STATE.with(|stream| {
+        let mut stream = stream.borrow_mut();
+        let dimension = stream.dimension;
+        stream.dimension += 1;
+        match &stream.sampler {
+            Some(sampler) => sampler.get(stream.pixel, stream.index, dimension),
+            None => {
+                stream.state = stream.state.wrapping_add(GOLDEN_GAMMA);
+                (mix(stream.state) >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::SamplerType;
+
+    #[test]
+    fn test_seeded_streams_repeat() {
+        let draw = |seed: u64| -> Vec<f64> {
+            seed_random(seed);
+            (0..4).map(|_| random_double()).collect()
+        };
+        assert_eq!(draw(hash_seed(&[7, 3, 1])), draw(hash_seed(&[7, 3, 1])));
+        assert_ne!(draw(hash_seed(&[7, 3, 1])), draw(hash_seed(&[7, 4, 1])));
+        assert_ne!(hash_seed(&[1, 2]), hash_seed(&[2, 1]));
+
+        seed_random(hash_seed(&[42]));
+        let n = 100000;
+        let mean = (0..n).map(|_| random_double()).sum::<f64>() / n as f64;
+        assert!((mean - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_samples_draw_from_the_sampler() {
+        let sampler = SamplerType::Sobol.build(0, 16, 8);
+        start_sample(&sampler, 4, 2);
+        let first: Vec<f64> = (0..3).map(|_| random_double()).collect();
+        assert_eq!(first[1], sampler.get(4, 2, 1));
+        start_sample(&sampler, 4, 2);
+        assert_eq!(random_double(), first[0]);
+        seed_random(0);
+        assert_ne!(random_double(), sampler.get(4, 2, 1));
+    }
+}
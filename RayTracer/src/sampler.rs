@@ -0,0 +1,452 @@
+use crate::util::hash_seed;
+use lazy_static::lazy_static;
+use std::sync::Arc;
+
+// Where the random numbers of a sample come from. A sampler is a fixed function of (pixel,
+// sample index, dimension): `start_sample` points the thread at a sample, and then every
+// `random_double()` call takes the next dimension, so the pixel jitter, lens, time, bounces
+// and light picks of a path all draw from it without being passed a sampler.
+pub trait Sampler: Send + Sync {
+    // Coordinate `dimension` of sample `index` in `pixel`, in [0, 1).
+    fn get(&self, pixel: u64, index: u32, dimension: u32) -> f64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl SamplerType {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "independent" => Ok(SamplerType::Independent),
+            "stratified" => Ok(SamplerType::Stratified),
+            "halton" => Ok(SamplerType::Halton),
+            "sobol" => Ok(SamplerType::Sobol),
+            "blue_noise" => Ok(SamplerType::BlueNoise),
+            other => Err(format!(
+                "unknown sampler `{}` (independent, stratified, halton, sobol, blue_noise)",
+                other
+            )),
+        }
+    }
+
+    // `samples` is the planned number of samples per pixel, which only the stratified sampler
+    // needs; `width` is the image width, for the blue-noise sampler to find pixels on screen.
+    pub fn build(&self, seed: u64, samples: u32, width: u32) -> Arc<dyn Sampler> {
+        match self {
+            SamplerType::Independent => Arc::new(IndependentSampler { seed }),
+            SamplerType::Stratified => Arc::new(StratifiedSampler {
+                seed,
+                samples: samples.max(1),
+            }),
+            SamplerType::Halton => Arc::new(HaltonSampler { seed }),
+            SamplerType::Sobol => Arc::new(SobolSampler { seed }),
+            SamplerType::BlueNoise => Arc::new(BlueNoiseSampler {
+                seed,
+                width: width.max(1) as u64,
+            }),
+        }
+    }
+}
+
+fn to_unit(bits: u64) -> f64 {
+    (bits >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+// Plain random numbers.
+pub struct IndependentSampler {
+    seed: u64,
+}
+
+impl Sampler for IndependentSampler {
+    fn get(&self, pixel: u64, index: u32, dimension: u32) -> f64 {
+        to_unit(hash_seed(&[
+            self.seed,
+            pixel,
+            index as u64,
+            dimension as u64,
+        ]))
+    }
+}
+
+// Correlated multi-jittered sampling (Kensler 2013): each pair of dimensions is jittered on an
+// m x n grid with m * n >= samples, so any sample count, not only squares, is stratified in 2D
+// and in both 1D projections. Samples past `samples` (adaptive sampling) start a new pattern.
+pub struct StratifiedSampler {
+    seed: u64,
+    samples: u32,
+}
+
+impl StratifiedSampler {
+    fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+        let mut w = l - 1;
+        w |= w >> 1;
+        w |= w >> 2;
+        w |= w >> 4;
+        w |= w >> 8;
+        w |= w >> 16;
+        loop {
+            i ^= p;
+            i = i.wrapping_mul(0xe170893d);
+            i ^= p >> 16;
+            i ^= (i & w) >> 4;
+            i ^= p >> 8;
+            i = i.wrapping_mul(0x0929eb3f);
+            i ^= p >> 23;
+            i ^= (i & w) >> 1;
+            i = i.wrapping_mul(1 | p >> 27);
+            i = i.wrapping_mul(0x6935fa69);
+            i ^= (i & w) >> 11;
+            i = i.wrapping_mul(0x74dcb303);
+            i ^= (i & w) >> 2;
+            i = i.wrapping_mul(0x9e501cc3);
+            i ^= (i & w) >> 2;
+            i = i.wrapping_mul(0xc860a3df);
+            i &= w;
+            i ^= i >> 5;
+            if i < l {
+                break;
+            }
+        }
+        (i.wrapping_add(p)) % l
+    }
+
+    fn rand_float(mut i: u32, p: u32) -> f64 {
+        i ^= p;
+        i ^= i >> 17;
+        i ^= i >> 10;
+        i = i.wrapping_mul(0xb36534e5);
+        i ^= i >> 12;
+        i ^= i >> 21;
+        i = i.wrapping_mul(0x93fc4795);
+        i ^= 0xdf6e307f;
+        i ^= i >> 17;
+        i = i.wrapping_mul(1 | p >> 18);
+        i as f64 / 4294967296.0
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn get(&self, pixel: u64, index: u32, dimension: u32) -> f64 {
+        let n_samples = self.samples;
+        let round = (index / n_samples) as u64;
+        let p = hash_seed(&[self.seed, pixel, (dimension / 2) as u64, round]) as u32;
+        let m = ((n_samples as f64).sqrt() as u32).max(1);
+        let n = n_samples.div_ceil(m);
+
+        let s = Self::permute(index % n_samples, n_samples, p.wrapping_mul(0x51633e2d));
+        if dimension.is_multiple_of(2) {
+            let sx = Self::permute(s % m, m, p.wrapping_mul(0x68bc21eb));
+            let sy = Self::permute(s / m, n, p.wrapping_mul(0x02e5be93));
+            let jx = Self::rand_float(s, p.wrapping_mul(0x967a889b));
+            (sx as f64 + (sy as f64 + jx) / n as f64) / m as f64
+        } else {
+            let jy = Self::rand_float(s, p.wrapping_mul(0x368cc8b7));
+            (s as f64 + jy) / n_samples as f64
+        }
+    }
+}
+
+// The Halton sequence, one prime base per dimension, shifted by a random offset per pixel and
+// dimension (Cranley-Patterson rotation) so neighbouring pixels don't share a pattern. Past the
+// prime table the dimensions are independent random numbers.
+pub struct HaltonSampler {
+    seed: u64,
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+impl HaltonSampler {
+    fn radical_inverse(base: u32, mut index: u32) -> f64 {
+        let inverse_base = 1.0 / base as f64;
+        let mut scale = inverse_base;
+        let mut result = 0.0;
+        while index > 0 {
+            result += (index % base) as f64 * scale;
+            index /= base;
+            scale *= inverse_base;
+        }
+        result
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn get(&self, pixel: u64, index: u32, dimension: u32) -> f64 {
+        let hash = hash_seed(&[self.seed, pixel, dimension as u64]);
+        match PRIMES.get(dimension as usize) {
+            Some(base) => (Self::radical_inverse(*base, index) + to_unit(hash)).fract(),
+            None => to_unit(hash_seed(&[hash, index as u64])),
+        }
+    }
+}
+
+// Owen-scrambled Sobol points (Burley 2020, "Practical Hash-based Owen Scrambling"). Every pair
+// of dimensions uses the first two Sobol dimensions with its own shuffle and scramble, so no
+// table of direction numbers is needed however long the path. Power-of-two sample counts are
+// stratified best.
+pub struct SobolSampler {
+    seed: u64,
+}
+
+impl SobolSampler {
+    fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+        x = x.wrapping_add(seed);
+        x ^= x.wrapping_mul(0x6c50b47c);
+        x ^= x.wrapping_mul(0xb82f1e52);
+        x ^= x.wrapping_mul(0xc7afe638);
+        x ^= x.wrapping_mul(0x8d22f6e6);
+        x
+    }
+
+    fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+        Self::laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+    }
+
+    // Sobol dimension 0 is the van der Corput sequence; dimension 1 comes from the polynomial
+    // x + 1, whose direction numbers are v_k = v_(k-1) ^ (v_(k-1) >> 1).
+    fn sobol(mut index: u32, dimension: u32) -> u32 {
+        if dimension == 0 {
+            return index.reverse_bits();
+        }
+        let mut v = 1u32 << 31;
+        let mut result = 0;
+        while index != 0 {
+            if index & 1 != 0 {
+                result ^= v;
+            }
+            index >>= 1;
+            v ^= v >> 1;
+        }
+        result
+    }
+
+    // Coordinate `dimension` of point `index`, shuffled and scrambled by `hash`.
+    fn scrambled(index: u32, dimension: u32, hash: u64) -> f64 {
+        let shuffled = Self::nested_uniform_scramble(index, hash as u32);
+        let x = Self::sobol(shuffled, dimension % 2);
+        let scrambled = Self::nested_uniform_scramble(x, ((hash >> 32) as u32) ^ (dimension % 2));
+        scrambled as f64 / 4294967296.0
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn get(&self, pixel: u64, index: u32, dimension: u32) -> f64 {
+        let hash = hash_seed(&[self.seed, pixel, (dimension / 2) as u64]);
+        Self::scrambled(index, dimension, hash)
+    }
+}
+
+// Sobol points that are the same in every pixel, rotated per pixel (Cranley-Patterson) by a
+// blue-noise tile laid over the screen (Georgiev and Fajardo 2016, "Blue-noise dithered
+// sampling"). Neighbouring pixels get offsets far apart, so the error left over is a fine grain
+// rather than blotches. Each dimension reads the tile at its own toroidal shift.
+pub struct BlueNoiseSampler {
+    seed: u64,
+    width: u64,
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+lazy_static! {
+    static ref BLUE_NOISE: Vec<f64> = void_and_cluster(BLUE_NOISE_SIZE);
+}
+
+// A `size` x `size` tile of blue noise by void and cluster (Ulichney 1993): starting from a few
+// evened-out points, points are taken out of the tightest cluster one by one, or put into the
+// largest void, and the order they go in is the value of their cell, spread over [0, 1).
+fn void_and_cluster(size: usize) -> Vec<f64> {
+    let n = size * size;
+    // the energy a point adds at each toroidal offset from it, a Gaussian of sigma 1.5
+    let mut kernel = vec![0.0; n];
+    for dy in 0..size {
+        for dx in 0..size {
+            let (x, y) = (dx.min(size - dx) as f64, dy.min(size - dy) as f64);
+            kernel[dy * size + dx] = (-(x * x + y * y) / 4.5).exp();
+        }
+    }
+    let splat = |energy: &mut [f64], p: usize, sign: f64| {
+        let (px, py) = (p % size, p / size);
+        for (k, e) in energy.iter_mut().enumerate() {
+            let (dx, dy) = ((k % size + size - px) % size, (k / size + size - py) % size);
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // the point in the tightest cluster (`set`) or the empty cell in the largest void
+    let extreme = |energy: &[f64], points: &[bool], set: bool| {
+        let cells = (0..n).filter(|&k| points[k] == set);
+        if set {
+            cells.max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        } else {
+            cells.min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        }
+        .unwrap()
+    };
+
+    let mut points = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let mut count = 0;
+    let mut i = 0;
+    while count < n / 10 {
+        let p = (hash_seed(&[i]) % n as u64) as usize;
+        if !points[p] {
+            points[p] = true;
+            splat(&mut energy, p, 1.0);
+            count += 1;
+        }
+        i += 1;
+    }
+    // move points from clusters to voids until the one taken out is the one put back
+    for _ in 0..n {
+        let cluster = extreme(&energy, &points, true);
+        points[cluster] = false;
+        splat(&mut energy, cluster, -1.0);
+        let void = extreme(&energy, &points, false);
+        points[void] = true;
+        splat(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; n];
+    let (initial_points, initial_energy) = (points.clone(), energy.clone());
+    for r in (0..count).rev() {
+        let p = extreme(&energy, &points, true);
+        points[p] = false;
+        splat(&mut energy, p, -1.0);
+        rank[p] = r;
+    }
+    let (mut points, mut energy) = (initial_points, initial_energy);
+    for r in count..n {
+        let p = extreme(&energy, &points, false);
+        points[p] = true;
+        splat(&mut energy, p, 1.0);
+        rank[p] = r;
+    }
+    rank.into_iter().map(|r| r as f64 / n as f64).collect()
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn get(&self, pixel: u64, index: u32, dimension: u32) -> f64 {
+        let hash = hash_seed(&[self.seed, (dimension / 2) as u64]);
+        let shift = hash_seed(&[self.seed, dimension as u64, 1]);
+        let size = BLUE_NOISE_SIZE as u64;
+        let x = (pixel % self.width + shift % size) % size;
+        let y = (pixel / self.width + (shift >> 32) % size) % size;
+        let offset = BLUE_NOISE[(y * size + x) as usize];
+        (SobolSampler::scrambled(index, dimension, hash) + offset).fract()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samplers_are_uniform() {
+        for sampler_type in [
+            SamplerType::Independent,
+            SamplerType::Stratified,
+            SamplerType::Halton,
+            SamplerType::Sobol,
+            SamplerType::BlueNoise,
+        ] {
+            let sampler = sampler_type.build(1, 250, 8);
+            for dimension in [0, 1, 5, 40] {
+                let mut sum = 0.0;
+                for pixel in 0..40 {
+                    for index in 0..250 {
+                        let x = sampler.get(pixel, index, dimension);
+                        assert!((0.0..1.0).contains(&x));
+                        sum += x;
+                    }
+                }
+                let mean = sum / 10000.0;
+                assert!((mean - 0.5).abs() < 0.02, "{:?} {}", sampler_type, mean);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stratification() {
+        // 250 stratified samples on a 15 x 17 grid: the second dimension of a pair has one sample
+        // in each 1/250, the first at most one in each 1/255
+        let sampler = SamplerType::Stratified.build(3, 250, 16);
+        for (dimension, cells) in [(0, 255), (1, 250), (6, 255), (7, 250)] {
+            let mut strata = vec![0; cells];
+            for index in 0..250 {
+                strata[(sampler.get(9, index, dimension) * cells as f64) as usize] += 1;
+            }
+            assert!(strata.iter().all(|&n| n <= 1), "dimension {}", dimension);
+        }
+
+        // 64 Sobol samples: one in each 1/64 of a dimension and in each cell of an 8x8 grid
+        let sampler = SamplerType::Sobol.build(3, 64, 16);
+        for pair in [0, 3] {
+            let mut strata = vec![0; 64];
+            let mut grid = vec![0; 64];
+            for index in 0..64 {
+                let x = sampler.get(5, index, 2 * pair);
+                let y = sampler.get(5, index, 2 * pair + 1);
+                strata[(x * 64.0) as usize] += 1;
+                grid[(y * 8.0) as usize * 8 + (x * 8.0) as usize] += 1;
+            }
+            assert!(strata.iter().all(|&n| n == 1));
+            assert!(grid.iter().all(|&n| n == 1));
+        }
+    }
+
+    #[test]
+    fn test_blue_noise() {
+        // every value once
+        let mut values = BLUE_NOISE.clone();
+        values.sort_by(f64::total_cmp);
+        let n = values.len();
+        assert!(values
+            .iter()
+            .enumerate()
+            .all(|(k, &v)| v == k as f64 / n as f64));
+
+        // little low-frequency content: the means of 4x4 blocks vary far less than the 1/192
+        // of white noise
+        let size = BLUE_NOISE_SIZE;
+        let mut variance = 0.0;
+        for by in (0..size).step_by(4) {
+            for bx in (0..size).step_by(4) {
+                let mut mean = 0.0;
+                for y in by..by + 4 {
+                    for x in bx..bx + 4 {
+                        mean += BLUE_NOISE[y * size + x] / 16.0;
+                    }
+                }
+                variance += (mean - 0.5) * (mean - 0.5) / (size * size / 16) as f64;
+            }
+        }
+        assert!(variance < 1.0 / 192.0 / 4.0, "{}", variance);
+
+        // and the sampler's neighbouring pixels are further apart than independent numbers,
+        // which are 1/4 apart on average, wrapping around 1
+        let sampler = SamplerType::BlueNoise.build(2, 16, 64);
+        for (index, dimension) in [(0, 0), (5, 1), (9, 6)] {
+            let mut sum = 0.0;
+            for y in 0..64 {
+                for x in 0..63 {
+                    let a = sampler.get(y * 64 + x, index, dimension);
+                    let b = sampler.get(y * 64 + x + 1, index, dimension);
+                    let d = (a - b).abs();
+                    sum += d.min(1.0 - d);
+                }
+            }
+            let mean = sum / (64.0 * 63.0);
+            assert!(mean > 0.28, "{}", mean);
+        }
+    }
+}
@@ -0,0 +1,1144 @@
+// Scene description files.
+//
+// A scene file is plain text with one directive per line. Everything after `#` is a comment.
+// Vectors and colors are written as three numbers, and a number may be a fraction like `16/9`.
+//
+//   output <path>                          where the image is written (default: output/<file>.png);
+//                                          .hdr, .pfm and .exr keep the HDR values
+//   camera <field> <value...>              aspect_ratio, image_width, samples_per_pixel, max_depth,
+//                                          vfov, lookfrom, lookat, vup, defocus_angle, focus_dist,
+//                                          background, mis_heuristic (balance | power | off),
+//                                          roulette_depth, tonemap (clamp | reinhard |
+//                                          reinhard_extended | aces), exposure, white_point,
+//                                          checkpoint_interval (seconds), adaptive_threshold,
+//                                          max_samples_per_pixel, denoise (on | off),
+//                                          aov <depth normal albedo uv object material | all>,
+//                                          seed, sampler (independent | stratified | halton |
+//                                          sobol), shutter_open, shutter_close (ray times,
+//                                          default 0 and 1), lookfrom_end, lookat_end (where
+//                                          a moving camera is when the shutter closes)
+//
+//   animation frames <n>                   frames rendered by --animate
+//   animation time <start> <end>           frame k is at start + (end - start) * k / frames
+//                                          (default 0 and 1)
+//   animation shutter <fraction>           of a frame the shutter is open (default 0.5)
+//   animation key <time> <field> <value...>
+//                                          keyframe for lookfrom, lookat, vfov or focus_dist;
+//                                          the camera passes smoothly through the keys
+//
+//   texture <name> solid <r g b>
+//   texture <name> checker <scale> <odd texture> <even texture>
+//   texture <name> image <path> [filter] [wrap] [tile <u v>]
+//                                          filter: nearest, bilinear, bicubic or trilinear
+//                                          (default, mipmapped); wrap: repeat (default),
+//                                          mirror or clamp; tile repeats the image u by v times
+//   texture <name> noise <scale>
+//
+//   material <name> lambertian <texture | r g b>
+//   material <name> metal <r g b> <fuzz>
+//   material <name> dielectric <refraction index>
+//   material <name> diffuse_light <texture | r g b>
+//   material <name> isotropic <texture | r g b>
+//   material <name> normal_map <material> <texture> [strength]
+//                                          a tangent-space normal map over another material
+//   material <name> bump <material> <texture> <scale>
+//                                          a height map (the texture's luminance times scale)
+//   material <name> microfacet <texture | r g b> <roughness> <metallic>
+//                                          GGX in the metallic workflow; roughness and
+//                                          metallic are numbers in [0, 1] or texture names
+//   material <name> conductor <gold | silver | copper | aluminium> <roughness>
+//   material <name> conductor <eta r g b> <k r g b> <roughness>
+//                                          a rough metal from its complex refractive index
+//   material <name> rough_dielectric <refraction index> <roughness>
+//                                          frosted glass
+//
+//   sphere <center> <radius> <material>
+//   moving_sphere <center1> <center2> <radius> <material>
+//   quad <Q> <u> <v> <material>
+//   triangle <p0> <p1> <p2> <material>
+//   disk <center> <u> <v> <material>
+//   box <corner a> <corner b> <material>
+//   obj <path> <material | mtl> [smooth | flat]
+//                                          triangle mesh, kept in its own BVH; `mtl` takes the
+//                                          materials from the file's MTL libraries, smooth (the
+//                                          default) interpolates the vertex normals
+//
+//   begin ... end                          groups objects; blocks may nest
+//   translate <offset>                     these apply to everything added so far in the
+//   rotate_y <degrees>                     enclosing block, in the order they are written
+//   rotate_x <degrees>
+//   rotate_z <degrees>
+//   rotate <axis> <degrees>
+//   euler <x y z degrees>                  around x first, then y, then z
+//   scale <factor | x y z>
+//   look_at <from> <to> <up>               moves the origin to `from`, turns +z toward `to`
+//   matrix <16 numbers>                    a 4x4 matrix, row by row
+//   medium <density> <texture | r g b>     turns the block into a constant medium boundary
+//   bvh                                    puts the block into its own BVH
+//   keyframe <time> [<transform> <numbers>...]
+//                                          the pose of the block at `time`, from translate,
+//                                          rotate, rotate_x/y/z, euler and scale (scaled, then
+//                                          rotated, then moved); with several, the block moves
+//                                          through them, applied after its other transforms
+//
+//   define <name> ... end                  builds the objects in between once, instead of
+//                                          adding them to the scene
+//   instance <name> [material <material>] [<transform> <numbers>...]
+//                                          places a defined object, sharing its geometry;
+//                                          the transforms are those above, applied in order
+//
+// Spheres, quads, triangles and disks with a diffuse_light material that sit directly in the world
+// (outside any block) are also used as lights, so the renderer samples them directly instead of
+// waiting to hit them.
+//
+// Paths are relative to the working directory, the same as the built-in scenes.
+
+use crate::util::{
+    get_box, hash_name, load_obj, Animation, AovSet, BumpMap, Bumped, BvhNode, Camera,
+    Checker_Texture, Color, ConstantMedium, Dielectric, DiffuseLight, Disk, Hittable, HittableList,
+    Image_Texture, Instance, Isotropic, Keyframe, Lambertian, Mat4, Material, Metal, Microfacet,
+    MisHeuristic, Motion, Noise_Texture, Point3, Quad, Quat, RotateY, RoughDielectric, SamplerType,
+    Solid_Color, Sphere, Texture, TextureFilter, TextureWrap, ToneMapOperator, Transform,
+    Translate, Triangle, Vec3,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+pub struct Scene {
+    pub camera: Camera,
+    pub world: Arc<dyn Hittable>,
+    pub lights: Arc<HittableList>,
+    pub path: String,
+    // frames for `--animate`; not animated unless the scene sets some up
+    pub animation: Animation,
+}
+
+impl Scene {
+    pub fn load(file_path: &str) -> Result<Self, String> {
+        let source = fs::read_to_string(file_path)
+            .map_err(|e| format!("Failed to open scene {}: {}", file_path, e))?;
+        let stem = Path::new(file_path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("scene"));
+        let mut scene = Self::parse(&source, &format!("output/{}.png", stem))
+            .map_err(|e| format!("{}: {}", file_path, e))?;
+        scene.camera.scene_id = hash_name(&source);
+        Ok(scene)
+    }
+
+    pub fn parse(source: &str, default_path: &str) -> Result<Self, String> {
+        let mut parser = SceneParser::new(default_path);
+        for (index, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            parser
+                .directive(index + 1, &tokens)
+                .map_err(|e| format!("line {}: {}", index + 1, e))?;
+        }
+        parser.finish()
+    }
+}
+
+struct SceneParser {
+    camera: Camera,
+    path: String,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    // names of the diffuse_light materials
+    emissive: HashSet<String>,
+    lights: HittableList,
+    // open `begin` blocks; the first entry is the world
+    blocks: Vec<Block>,
+    // objects made by `define`, to be placed with `instance`
+    definitions: HashMap<String, Arc<dyn Hittable>>,
+    animation: Animation,
+}
+
+impl SceneParser {
+    fn new(default_path: &str) -> Self {
+        Self {
+            camera: Camera::default(),
+            path: String::from(default_path),
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            emissive: HashSet::new(),
+            lights: HittableList::new(),
+            blocks: vec![Block::new(0, None)],
+            definitions: HashMap::new(),
+            animation: Animation::default(),
+        }
+    }
+
+    fn directive(&mut self, line: usize, tokens: &[&str]) -> Result<(), String> {
+        let args = Args {
+            name: tokens[0],
+            values: &tokens[1..],
+        };
+        match tokens[0] {
+            "output" => {
+                args.expect(1)?;
+                self.path = String::from(args.values[0]);
+            }
+            "camera" => self.camera_field(&args)?,
+            "animation" => self.animation_field(&args)?,
+            "texture" => {
+                args.expect_at_least(2)?;
+                let texture = self.texture(args.values[1], &args.after(1))?;
+                self.textures.insert(String::from(args.values[0]), texture);
+            }
+            "material" => {
+                args.expect_at_least(2)?;
+                let material = self.material(args.values[1], &args.after(1))?;
+                let name = String::from(args.values[0]);
+                if args.values[1] == "diffuse_light" {
+                    self.emissive.insert(name.clone());
+                } else {
+                    self.emissive.remove(&name);
+                }
+                self.materials.insert(name, material);
+            }
+            "sphere" => {
+                args.expect(5)?;
+                let mat = self.material_ref(args.values[4])?;
+                let sphere = Arc::new(Sphere::new_static(
+                    args.point(0)?,
+                    args.number(3)?,
+                    Some(mat),
+                ));
+                self.add_maybe_light(sphere, args.values[4]);
+            }
+            "moving_sphere" => {
+                args.expect(8)?;
+                let mat = self.material_ref(args.values[7])?;
+                self.add(Arc::new(Sphere::new(
+                    args.point(0)?,
+                    args.number(6)?,
+                    Some(mat),
+                    args.point(3)?,
+                )));
+            }
+            "quad" => {
+                args.expect(10)?;
+                let mat = self.material_ref(args.values[9])?;
+                let quad = Arc::new(Quad::new(
+                    args.point(0)?,
+                    args.vec3(3)?,
+                    args.vec3(6)?,
+                    Some(mat),
+                ));
+                self.add_maybe_light(quad, args.values[9]);
+            }
+            "triangle" => {
+                args.expect(10)?;
+                let mat = self.material_ref(args.values[9])?;
+                let p0 = args.point(0)?;
+                let triangle = Arc::new(Triangle::new(
+                    p0,
+                    args.point(3)? - p0,
+                    args.point(6)? - p0,
+                    [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+                    Some(mat),
+                ));
+                self.add_maybe_light(triangle, args.values[9]);
+            }
+            "disk" => {
+                args.expect(10)?;
+                let mat = self.material_ref(args.values[9])?;
+                let disk = Arc::new(Disk::new(
+                    args.point(0)?,
+                    args.vec3(3)?,
+                    args.vec3(6)?,
+                    Some(mat),
+                ));
+                self.add_maybe_light(disk, args.values[9]);
+            }
+            "box" => {
+                args.expect(7)?;
+                let mat = self.material_ref(args.values[6])?;
+                self.add(get_box(args.point(0)?, args.point(3)?, Some(mat)));
+            }
+            "obj" => {
+                args.expect_at_least(2)?;
+                let smooth = match args.values.get(2) {
+                    None | Some(&"smooth") => true,
+                    Some(&"flat") => false,
+                    Some(other) => {
+                        return Err(format!("unknown shading `{}` (smooth, flat)", other));
+                    }
+                };
+                if args.values.len() > 3 {
+                    args.expect(3)?;
+                }
+                let mat = match args.values[1] {
+                    "mtl" => None,
+                    name => Some(self.material_ref(name)?),
+                };
+                self.add(load_obj(args.values[0], mat, smooth)?);
+            }
+            "begin" => {
+                args.expect(0)?;
+                self.blocks.push(Block::new(line, None));
+            }
+            "define" => {
+                args.expect(1)?;
+                let name = String::from(args.values[0]);
+                self.blocks.push(Block::new(line, Some(name)));
+            }
+            "end" => {
+                args.expect(0)?;
+                if self.blocks.len() == 1 {
+                    return Err(String::from("`end` without a matching `begin`"));
+                }
+                let block = self.blocks.pop().unwrap();
+                if block.objects.objects.is_empty() {
+                    return Err(String::from("block has no objects"));
+                }
+                match block.name {
+                    Some(name) => {
+                        if !block.keyframes.is_empty() {
+                            return Err(String::from("`keyframe` in a `define` block"));
+                        }
+                        // built once, with its own BVH, however many times it is placed
+                        let object: Arc<dyn Hittable> = if block.objects.objects.len() == 1 {
+                            Self::into_object(block.objects)
+                        } else {
+                            Arc::new(BvhNode::new_by_object_list(&block.objects))
+                        };
+                        self.definitions.insert(name, object);
+                    }
+                    None => {
+                        let mut object = Self::into_object(block.objects);
+                        if !block.keyframes.is_empty() {
+                            object = Arc::new(Motion::new(object, block.keyframes)?);
+                        }
+                        self.add(object);
+                    }
+                }
+            }
+            "keyframe" => {
+                args.expect_at_least(1)?;
+                let keyframe = Self::keyframe(args.number(0)?, &args.skip(1))?;
+                self.current_block(&args)?;
+                self.blocks.last_mut().unwrap().keyframes.push(keyframe);
+            }
+            "instance" => {
+                args.expect_at_least(1)?;
+                let object = match self.definitions.get(args.values[0]) {
+                    Some(object) => object.clone(),
+                    None => return Err(format!("unknown object `{}`", args.values[0])),
+                };
+                let (matrix, mat) = self.placement(&args.skip(1))?;
+                self.add(Arc::new(Instance::new(object, matrix, mat)?));
+            }
+            "translate" => {
+                args.expect(3)?;
+                let offset = args.vec3(0)?;
+                self.wrap(&args, |object| Ok(Arc::new(Translate::new(object, offset))))?;
+            }
+            "rotate_y" => {
+                args.expect(1)?;
+                let angle = args.number(0)?;
+                self.wrap(&args, |object| Ok(Arc::new(RotateY::new(object, angle))))?;
+            }
+            "rotate_x" | "rotate_z" | "rotate" | "euler" | "scale" | "look_at" | "matrix" => {
+                let matrix = Self::matrix(&args)?;
+                self.wrap(&args, |object| {
+                    Ok(Arc::new(Transform::new(object, matrix)?))
+                })?;
+            }
+            "medium" => {
+                args.expect_at_least(2)?;
+                let density = args.number(0)?;
+                let tex = self.texture_or_color(&args.skip(1))?;
+                self.wrap(&args, |object| {
+                    Ok(Arc::new(ConstantMedium::new_by_tex(object, density, tex)))
+                })?;
+            }
+            "bvh" => {
+                args.expect(0)?;
+                let block = self.current_block(&args)?;
+                let bvh = Arc::new(BvhNode::new_by_object_list(block));
+                block.clear();
+                block.add(bvh);
+            }
+            other => return Err(format!("unknown directive `{}`", other)),
+        }
+        Ok(())
+    }
+
+    // The matrix of one of the general transform directives.
+    fn matrix(args: &Args) -> Result<Mat4, String> {
+        let matrix = match args.name {
+            "rotate_x" => Mat4::rotation_x(args.single_number()?),
+            "rotate_z" => Mat4::rotation_z(args.single_number()?),
+            "rotate" => {
+                args.expect(4)?;
+                Mat4::rotation(args.vec3(0)?, args.number(3)?)
+            }
+            "euler" => {
+                args.expect(3)?;
+                Mat4::euler(args.vec3(0)?)
+            }
+            "scale" => {
+                if args.values.len() == 1 {
+                    let s = args.number(0)?;
+                    Mat4::scaling(Vec3::new(s, s, s))
+                } else {
+                    args.expect(3)?;
+                    Mat4::scaling(args.vec3(0)?)
+                }
+            }
+            "look_at" => {
+                args.expect(9)?;
+                Mat4::look_at(args.point(0)?, args.point(3)?, args.vec3(6)?)
+            }
+            "translate" => {
+                args.expect(3)?;
+                Mat4::translation(args.vec3(0)?)
+            }
+            "rotate_y" => Mat4::rotation_y(args.single_number()?),
+            "matrix" => {
+                args.expect(16)?;
+                let mut m = [[0.0; 4]; 4];
+                for (i, value) in m.iter_mut().flatten().enumerate() {
+                    *value = args.number(i)?;
+                }
+                Mat4::new(m)
+            }
+            other => return Err(format!("unknown transform `{}`", other)),
+        };
+        Ok(matrix)
+    }
+
+    // The words after `instance <name>`: an optional `material <name>` and any number of
+    // transforms, each followed by its numbers and applied in the order they are written.
+    fn placement(&self, args: &Args) -> Result<(Mat4, Option<Arc<dyn Material>>), String> {
+        let mut matrix = Mat4::identity();
+        let mut mat = None;
+        for part in args.parts() {
+            if part.name == "material" {
+                part.expect(1)?;
+                mat = Some(self.material_ref(part.values[0])?);
+            } else {
+                matrix = Self::matrix(&part)? * matrix;
+            }
+        }
+        Ok((matrix, mat))
+    }
+
+    // The words after `keyframe <time>`: translate, rotate (axis and degrees), rotate_x,
+    // rotate_y, rotate_z, euler and scale. Whatever order they come in, the keyframe scales,
+    // then rotates, then moves.
+    fn keyframe(time: f64, args: &Args) -> Result<Keyframe, String> {
+        let mut keyframe = Keyframe::new(time);
+        for part in args.parts() {
+            let turn = match part.name {
+                "translate" => {
+                    part.expect(3)?;
+                    keyframe.translation += part.vec3(0)?;
+                    continue;
+                }
+                "scale" => {
+                    let factors = if part.values.len() == 1 {
+                        let s = part.number(0)?;
+                        Vec3::new(s, s, s)
+                    } else {
+                        part.expect(3)?;
+                        part.vec3(0)?
+                    };
+                    keyframe.scale = keyframe.scale.element_mul(&factors);
+                    continue;
+                }
+                "rotate" => {
+                    part.expect(4)?;
+                    Quat::from_axis_angle(part.vec3(0)?, part.number(3)?)
+                }
+                "rotate_x" => {
+                    Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), part.single_number()?)
+                }
+                "rotate_y" => {
+                    Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), part.single_number()?)
+                }
+                "rotate_z" => {
+                    Quat::from_axis_angle(Vec3::new(0.0, 0.0, 1.0), part.single_number()?)
+                }
+                "euler" => {
+                    part.expect(3)?;
+                    Quat::from_euler(part.vec3(0)?)
+                }
+                other => return Err(format!("unknown keyframe transform `{}`", other)),
+            };
+            keyframe.rotation = turn * keyframe.rotation;
+        }
+        Ok(keyframe)
+    }
+
+    fn camera_field(&mut self, args: &Args) -> Result<(), String> {
+        args.expect_at_least(1)?;
+        let value = args.after(0);
+        let camera = &mut self.camera;
+        match args.values[0] {
+            "aspect_ratio" => camera.aspect_ratio = value.single_number()?,
+            "image_width" => camera.image_width = value.single_integer()?,
+            "samples_per_pixel" => camera.samples_per_pixel = value.single_integer()?,
+            "max_depth" => camera.max_depth = value.single_integer()?,
+            "vfov" => camera.vfov = value.single_number()?,
+            "lookfrom" => camera.lookfrom = value.single_point()?,
+            "lookat" => camera.lookat = value.single_point()?,
+            "vup" => camera.vup = value.single_point()?.to_vec3(),
+            "defocus_angle" => camera.defocus_angle = value.single_number()?,
+            "focus_dist" => camera.focus_dist = value.single_number()?,
+            "shutter_open" => camera.shutter_open = value.single_number()?,
+            "shutter_close" => camera.shutter_close = value.single_number()?,
+            "lookfrom_end" => camera.lookfrom_end = Some(value.single_point()?),
+            "lookat_end" => camera.lookat_end = Some(value.single_point()?),
+            "background" => camera.background = value.single_color()?,
+            "roulette_depth" => camera.roulette_depth = value.single_integer()?,
+            "seed" => camera.seed = value.single_integer()? as u64,
+            "sampler" => {
+                value.expect(1)?;
+                camera.sampler = SamplerType::from_name(value.values[0])?;
+            }
+            "tonemap" => {
+                value.expect(1)?;
+                camera.tone_map.operator = ToneMapOperator::from_name(value.values[0])?;
+            }
+            "exposure" => camera.tone_map.exposure = value.single_number()?,
+            "white_point" => camera.tone_map.white_point = value.single_number()?,
+            "checkpoint_interval" => camera.checkpoint_interval = value.single_integer()? as u64,
+            "adaptive_threshold" => camera.adaptive_threshold = value.single_number()?,
+            "max_samples_per_pixel" => camera.max_samples_per_pixel = value.single_integer()?,
+            "aov" => {
+                value.expect_at_least(1)?;
+                camera.aovs = AovSet::parse(value.values)?;
+            }
+            "denoise" => {
+                value.expect(1)?;
+                camera.denoise = match value.values[0] {
+                    "on" => true,
+                    "off" => false,
+                    other => return Err(format!("expected on or off, found `{}`", other)),
+                };
+            }
+            "mis_heuristic" => {
+                value.expect(1)?;
+                camera.mis_heuristic = parse_mis_heuristic(value.values[0])?;
+            }
+            other => return Err(format!("unknown camera field `{}`", other)),
+        }
+        Ok(())
+    }
+
+    fn animation_field(&mut self, args: &Args) -> Result<(), String> {
+        args.expect_at_least(1)?;
+        let value = args.after(0);
+        let animation = &mut self.animation;
+        match args.values[0] {
+            "frames" => animation.frames = value.single_integer()?,
+            "time" => {
+                value.expect(2)?;
+                animation.start = value.number(0)?;
+                animation.end = value.number(1)?;
+            }
+            "shutter" => animation.shutter = value.single_number()?,
+            "key" => {
+                value.expect_at_least(2)?;
+                let time = value.number(0)?;
+                let key = value.after(1);
+                match value.values[1] {
+                    "lookfrom" => {
+                        let p = key.single_point()?;
+                        animation.lookfrom.add(time, [p.x, p.y, p.z]);
+                    }
+                    "lookat" => {
+                        let p = key.single_point()?;
+                        animation.lookat.add(time, [p.x, p.y, p.z]);
+                    }
+                    "vfov" => animation.vfov.add(time, [key.single_number()?]),
+                    "focus_dist" => animation.focus_dist.add(time, [key.single_number()?]),
+                    other => return Err(format!("unknown camera key `{}`", other)),
+                }
+            }
+            other => return Err(format!("unknown animation field `{}`", other)),
+        }
+        Ok(())
+    }
+
+    fn texture(&self, kind: &str, args: &Args) -> Result<Arc<dyn Texture>, String> {
+        let texture: Arc<dyn Texture> = match kind {
+            "solid" => Arc::new(Solid_Color::new(args.single_color()?)),
+            "checker" => {
+                args.expect(3)?;
+                Arc::new(Checker_Texture::new(
+                    self.texture_ref(args.values[1])?,
+                    self.texture_ref(args.values[2])?,
+                    args.number(0)?,
+                ))
+            }
+            "image" => {
+                args.expect_at_least(1)?;
+                let mut texture = Image_Texture::from_file(args.values[0])?;
+                let mut options = args.values[1..].iter();
+                while let Some(&option) = options.next() {
+                    if option == "tile" {
+                        let mut number = || {
+                            let value = options.next().ok_or("`tile` needs two numbers")?;
+                            parse_number(value)
+                        };
+                        texture.tile = [number()?, number()?];
+                    } else if let Ok(filter) = TextureFilter::from_name(option) {
+                        texture.filter = filter;
+                    } else {
+                        texture.wrap = TextureWrap::from_name(option)
+                            .map_err(|_| format!("unknown image texture option `{}`", option))?;
+                    }
+                }
+                Arc::new(texture)
+            }
+            "noise" => Arc::new(Noise_Texture::new(args.single_number()?)),
+            other => return Err(format!("unknown texture kind `{}`", other)),
+        };
+        Ok(texture)
+    }
+
+    fn material(&self, kind: &str, args: &Args) -> Result<Arc<dyn Material>, String> {
+        let material: Arc<dyn Material> = match kind {
+            "lambertian" => Arc::new(Lambertian::new(self.texture_or_color(args)?)),
+            "metal" => {
+                args.expect(4)?;
+                Arc::new(Metal::new(args.color(0)?, args.number(3)?))
+            }
+            "dielectric" => Arc::new(Dielectric::new(args.single_number()?)),
+            "diffuse_light" => Arc::new(DiffuseLight::new(self.texture_or_color(args)?)),
+            "isotropic" => Arc::new(Isotropic::new(self.texture_or_color(args)?)),
+            "normal_map" => {
+                let strength = match args.values.len() {
+                    2 => 1.0,
+                    3 => args.number(2)?,
+                    n => {
+                        return Err(format!(
+                            "`{}` expects 2 or 3 arguments, found {}",
+                            args.name, n
+                        ))
+                    }
+                };
+                let map = BumpMap::Normal {
+                    texture: self.texture_ref(args.values[1])?,
+                    strength,
+                };
+                Arc::new(Bumped::new(self.material_ref(args.values[0])?, map))
+            }
+            "bump" => {
+                args.expect(3)?;
+                let map = BumpMap::Height {
+                    texture: self.texture_ref(args.values[1])?,
+                    scale: args.number(2)?,
+                };
+                Arc::new(Bumped::new(self.material_ref(args.values[0])?, map))
+            }
+            "microfacet" => {
+                let n = args.values.len();
+                if n != 3 && n != 5 {
+                    return Err(format!(
+                        "`{}` expects 3 or 5 arguments, found {}",
+                        args.name, n
+                    ));
+                }
+                let base_color = self.texture_or_color(&Args {
+                    name: args.name,
+                    values: &args.values[..n - 2],
+                })?;
+                Arc::new(Microfacet::new(
+                    base_color,
+                    self.number_or_texture(args.values[n - 2])?,
+                    self.number_or_texture(args.values[n - 1])?,
+                ))
+            }
+            "conductor" => match args.values.len() {
+                2 => Arc::new(Microfacet::named_conductor(
+                    args.values[0],
+                    self.number_or_texture(args.values[1])?,
+                )?),
+                7 => Arc::new(Microfacet::conductor(
+                    args.color(0)?,
+                    args.color(3)?,
+                    self.number_or_texture(args.values[6])?,
+                )),
+                n => {
+                    return Err(format!(
+                        "`{}` expects 2 or 7 arguments, found {}",
+                        args.name, n
+                    ))
+                }
+            },
+            "rough_dielectric" => {
+                args.expect(2)?;
+                Arc::new(RoughDielectric::new(
+                    args.number(0)?,
+                    self.number_or_texture(args.values[1])?,
+                ))
+            }
+            other => return Err(format!("unknown material kind `{}`", other)),
+        };
+        Ok(material)
+    }
+
+    // A number is the same value everywhere; anything else names a texture.
+    fn number_or_texture(&self, word: &str) -> Result<Arc<dyn Texture>, String> {
+        match parse_number(word) {
+            Ok(x) => Ok(Arc::new(Solid_Color::new_by_f64(x, x, x))),
+            Err(_) => self.texture_ref(word),
+        }
+    }
+
+    // A single argument names a texture, three arguments are a solid color.
+    fn texture_or_color(&self, args: &Args) -> Result<Arc<dyn Texture>, String> {
+        if args.values.len() == 1 {
+            self.texture_ref(args.values[0])
+        } else {
+            Ok(Arc::new(Solid_Color::new(args.single_color()?)))
+        }
+    }
+
+    fn texture_ref(&self, name: &str) -> Result<Arc<dyn Texture>, String> {
+        self.textures
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown texture `{}`", name))
+    }
+
+    fn material_ref(&self, name: &str) -> Result<Arc<dyn Material>, String> {
+        self.materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown material `{}`", name))
+    }
+
+    fn add(&mut self, object: Arc<dyn Hittable>) {
+        self.blocks.last_mut().unwrap().objects.add(object);
+    }
+
+    // Lights are only picked up at the top level, where the object is in world space.
+    fn add_maybe_light(&mut self, object: Arc<dyn Hittable>, material: &str) {
+        if self.blocks.len() == 1 && self.emissive.contains(material) {
+            self.lights.add(object.clone());
+        }
+        self.add(object);
+    }
+
+    // The innermost begin/end block, for directives that act on what it holds so far.
+    fn current_block(&mut self, args: &Args) -> Result<&mut HittableList, String> {
+        if self.blocks.len() == 1 {
+            return Err(format!("`{}` must be inside a begin/end block", args.name));
+        }
+        let block = &mut self.blocks.last_mut().unwrap().objects;
+        if block.objects.is_empty() {
+            return Err(format!("`{}` in a block with no objects", args.name));
+        }
+        Ok(block)
+    }
+
+    // Replaces the contents of the innermost block with `f` applied to them.
+    fn wrap<F>(&mut self, args: &Args, f: F) -> Result<(), String>
+    where
+        F: FnOnce(Arc<dyn Hittable>) -> Result<Arc<dyn Hittable>, String>,
+    {
+        let block = self.current_block(args)?;
+        let contents = std::mem::replace(block, HittableList::new());
+        block.add(f(Self::into_object(contents))?);
+        Ok(())
+    }
+
+    fn into_object(mut list: HittableList) -> Arc<dyn Hittable> {
+        if list.objects.len() == 1 {
+            list.objects.pop().unwrap()
+        } else {
+            Arc::new(list)
+        }
+    }
+
+    fn finish(mut self) -> Result<Scene, String> {
+        if self.blocks.len() > 1 {
+            let line = self.blocks.last().unwrap().line;
+            return Err(format!("line {}: `begin` is never closed", line));
+        }
+        let world = self.blocks.pop().unwrap().objects;
+        if world.objects.is_empty() {
+            return Err(String::from("scene has no objects"));
+        }
+        Ok(Scene {
+            camera: self.camera,
+            world: Arc::new(BvhNode::new_by_object_list(&world)),
+            lights: Arc::new(self.lights),
+            path: self.path,
+            animation: self.animation,
+        })
+    }
+}
+
+// Numbers in scene files (and on the command line) may be written as fractions like `16/9`.
+pub fn parse_number(token: &str) -> Result<f64, String> {
+    let parsed = match token.split_once('/') {
+        Some((a, b)) => a
+            .parse::<f64>()
+            .and_then(|a| b.parse::<f64>().map(|b| a / b)),
+        None => token.parse::<f64>(),
+    };
+    parsed.map_err(|_| format!("`{}` is not a number", token))
+}
+
+pub fn parse_mis_heuristic(token: &str) -> Result<MisHeuristic, String> {
+    match token {
+        "balance" => Ok(MisHeuristic::Balance),
+        "power" => Ok(MisHeuristic::Power),
+        "off" => Ok(MisHeuristic::Off),
+        other => Err(format!("unknown MIS heuristic `{}`", other)),
+    }
+}
+
+// An open begin/end block: where it started, its name if it is a `define`, what it holds so
+// far and the keyframes it moves through.
+struct Block {
+    line: usize,
+    name: Option<String>,
+    objects: HittableList,
+    keyframes: Vec<Keyframe>,
+}
+
+impl Block {
+    fn new(line: usize, name: Option<String>) -> Self {
+        Self {
+            line,
+            name,
+            objects: HittableList::new(),
+            keyframes: Vec::new(),
+        }
+    }
+}
+
+// The arguments of one directive, with helpers that turn them into numbers or report why not.
+struct Args<'a> {
+    name: &'a str,
+    values: &'a [&'a str],
+}
+
+impl<'a> Args<'a> {
+    fn skip(&self, n: usize) -> Args<'a> {
+        Args {
+            name: self.name,
+            values: &self.values[n.min(self.values.len())..],
+        }
+    }
+
+    // The arguments after `values[i]`, reported under that word, e.g. the `metal` in
+    // `material steel metal 0.8 0.8 0.9 0`.
+    fn after(&self, i: usize) -> Args<'a> {
+        Args {
+            name: self.values[i],
+            values: &self.values[i + 1..],
+        }
+    }
+
+    // Splits words like `material gold scale 2 translate 0 1 0` into one `Args` per word, each
+    // with the numbers after it (or, for `material`, the one name).
+    fn parts(&self) -> Vec<Args<'a>> {
+        let mut parts = Vec::new();
+        let mut i = 0;
+        while i < self.values.len() {
+            let mut end = i + 1;
+            if self.values[i] == "material" {
+                end = (i + 2).min(self.values.len());
+            }
+            while end < self.values.len() && parse_number(self.values[end]).is_ok() {
+                end += 1;
+            }
+            parts.push(Args {
+                name: self.values[i],
+                values: &self.values[i + 1..end],
+            });
+            i = end;
+        }
+        parts
+    }
+
+    fn expect(&self, n: usize) -> Result<(), String> {
+        if self.values.len() != n {
+            return Err(format!(
+                "`{}` expects {} arguments, found {}",
+                self.name,
+                n,
+                self.values.len()
+            ));
+        }
+        Ok(())
+    }
+
+    fn expect_at_least(&self, n: usize) -> Result<(), String> {
+        if self.values.len() < n {
+            return Err(format!(
+                "`{}` expects at least {} arguments, found {}",
+                self.name,
+                n,
+                self.values.len()
+            ));
+        }
+        Ok(())
+    }
+
+    fn number(&self, i: usize) -> Result<f64, String> {
+        parse_number(self.values[i])
+    }
+
+    fn vec3(&self, i: usize) -> Result<Vec3, String> {
+        Ok(Vec3::new(
+            self.number(i)?,
+            self.number(i + 1)?,
+            self.number(i + 2)?,
+        ))
+    }
+
+    fn point(&self, i: usize) -> Result<Point3, String> {
+        Ok(self.vec3(i)?.to_point3())
+    }
+
+    fn color(&self, i: usize) -> Result<Color, String> {
+        let v = self.vec3(i)?;
+        Ok(Color::new(v.x, v.y, v.z))
+    }
+
+    fn single_number(&self) -> Result<f64, String> {
+        self.expect(1)?;
+        self.number(0)
+    }
+
+    fn single_integer(&self) -> Result<u32, String> {
+        self.expect(1)?;
+        self.values[0]
+            .parse::<u32>()
+            .map_err(|_| format!("`{}` is not a positive integer", self.values[0]))
+    }
+
+    fn single_point(&self) -> Result<Point3, String> {
+        self.expect(3)?;
+        self.point(0)
+    }
+
+    fn single_color(&self) -> Result<Color, String> {
+        self.expect(3)?;
+        self.color(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_camera_and_objects() {
+        let source = "
+            # a sphere and a box
+            camera aspect_ratio 16/9
+            camera image_width 200
+            camera lookfrom 13 2 3
+            material ground lambertian 0.5 0.5 0.5
+            sphere 0 0 0 1 ground
+            begin
+              box 0 0 0 1 1 1 ground
+              translate 0 0 5
+            end
+        ";
+        let scene = Scene::parse(source, "output/test.png").unwrap();
+        assert_eq!(scene.camera.aspect_ratio, 16.0 / 9.0);
+        assert_eq!(scene.camera.image_width, 200);
+        assert_eq!(scene.camera.lookfrom, Point3::new(13.0, 2.0, 3.0));
+        assert_eq!(scene.path, "output/test.png");
+        assert!((scene.world.bounding_box().z.max - 6.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_error_names_line() {
+        let source = "material white lambertian 1 1 1\n\nsphere 0 0 0 1 red\n";
+        let err = Scene::parse(source, "").err().unwrap();
+        assert_eq!(err, "line 3: unknown material `red`");
+        let err = Scene::parse("quad 0 0 0 1 0 0 0 1", "").err().unwrap();
+        assert_eq!(err, "line 1: `quad` expects 10 arguments, found 8");
+        let err = Scene::parse("material steel metal 1 1 1", "")
+            .err()
+            .unwrap();
+        assert_eq!(err, "line 1: `metal` expects 4 arguments, found 3");
+    }
+
+    #[test]
+    fn test_top_level_lights() {
+        let source = "
+            material light diffuse_light 15 15 15
+            material white lambertian 1 1 1
+            quad 0 5 0 1 0 0 0 0 1 light
+            quad 0 0 0 1 0 0 0 0 1 white
+            sphere 0 10 0 1 light
+            begin
+              quad 0 0 0 1 0 0 0 0 1 light
+              translate 0 1 0
+            end
+        ";
+        let scene = Scene::parse(source, "").unwrap();
+        assert_eq!(scene.lights.objects.len(), 2);
+    }
+
+    #[test]
+    fn test_general_transforms() {
+        let source = "
+            material white lambertian 1 1 1
+            begin
+              box 0 0 0 1 1 1 white
+              scale 2 1 1
+              rotate_x 90
+              translate 0 0 3
+            end
+        ";
+        let scene = Scene::parse(source, "").unwrap();
+        let bbox = scene.world.bounding_box();
+        assert!((bbox.x.max - 2.0).abs() < 1e-3);
+        assert!((bbox.z.min - 3.0).abs() < 1e-3 && (bbox.z.max - 4.0).abs() < 1e-3);
+        assert!((bbox.y.min + 1.0).abs() < 1e-3);
+
+        let source = "material white lambertian 1 1 1\nbegin\nsphere 0 0 0 1 white\nscale 0\nend\n";
+        let err = Scene::parse(source, "").err().unwrap();
+        assert_eq!(err, "line 4: transform matrix is not invertible");
+    }
+
+    #[test]
+    fn test_define_and_instance() {
+        let source = "
+            material white lambertian 1 1 1
+            material gold metal 0.9 0.7 0.3 0.1
+            define pair
+              sphere 0 0 0 1 white
+              sphere 3 0 0 1 white
+            end
+            instance pair
+            instance pair material gold scale 2 translate 0 0 10
+        ";
+        let scene = Scene::parse(source, "").unwrap();
+        let bbox = scene.world.bounding_box();
+        assert!((bbox.x.max - 8.0).abs() < 1e-3 && (bbox.z.max - 12.0).abs() < 1e-3);
+
+        let err = Scene::parse("instance cow", "").err().unwrap();
+        assert_eq!(err, "line 1: unknown object `cow`");
+        let source = "material white lambertian 1 1 1\ndefine ball\nsphere 0 0 0 1 white\nend\ninstance ball spin 3\n";
+        let err = Scene::parse(source, "").err().unwrap();
+        assert_eq!(err, "line 5: unknown transform `spin`");
+    }
+
+    #[test]
+    fn test_keyframes_and_shutter() {
+        let source = "
+            camera shutter_open 0.25
+            camera shutter_close 0.75
+            camera lookfrom_end 1 2 3
+            material white lambertian 1 1 1
+            begin
+              sphere 0 0 0 1 white
+              keyframe 0 translate -5 0 0
+              keyframe 1 translate 5 0 0 scale 2
+            end
+        ";
+        let scene = Scene::parse(source, "").unwrap();
+        assert_eq!(
+            (scene.camera.shutter_open, scene.camera.shutter_close),
+            (0.25, 0.75)
+        );
+        assert_eq!(scene.camera.lookfrom_end, Some(Point3::new(1.0, 2.0, 3.0)));
+        assert_eq!(scene.camera.lookat_end, None);
+        let bbox = scene.world.bounding_box();
+        assert!(bbox.x.min <= -6.0 && bbox.x.max >= 7.0 && bbox.y.max >= 2.0);
+
+        let source = "material white lambertian 1 1 1\nbegin\nsphere 0 0 0 1 white\nkeyframe 1\nkeyframe 0\nend\n";
+        let err = Scene::parse(source, "").err().unwrap();
+        assert_eq!(err, "line 6: keyframe times must increase");
+    }
+
+    #[test]
+    fn test_animation() {
+        let source = "
+            camera vfov 30
+            animation frames 24
+            animation time 0 2
+            animation shutter 0
+            animation key 0 lookfrom 0 0 10
+            animation key 2 lookfrom 10 0 0
+            animation key 1 vfov 50
+            material white lambertian 1 1 1
+            sphere 0 0 0 1 white
+        ";
+        let scene = Scene::parse(source, "").unwrap();
+        let animation = &scene.animation;
+        assert_eq!(
+            (animation.frames, animation.start, animation.end),
+            (24, 0.0, 2.0)
+        );
+        assert_eq!(animation.frame_time(6), 0.5);
+        let camera = animation.camera(&scene.camera, 12);
+        assert_eq!(camera.lookfrom, Point3::new(5.0, 0.0, 5.0));
+        assert_eq!(camera.vfov, 50.0);
+        assert_eq!(camera.shutter_open, camera.shutter_close);
+
+        let err = Scene::parse("animation key 0 up 0 1 0", "").err().unwrap();
+        assert_eq!(err, "line 1: unknown camera key `up`");
+    }
+
+    #[test]
+    fn test_image_texture_options() {
+        let parse = |line: &str| {
+            let source = format!("{}\nsphere 0 0 0 1 white\n", line);
+            Scene::parse(&source, "").err().unwrap()
+        };
+        // the options are fine, so the error is only the missing material
+        let err = parse("texture earth image support/earthmap.jpg bicubic mirror tile 4 2");
+        assert_eq!(err, "line 2: unknown material `white`");
+        let err = parse("texture earth image support/earthmap.jpg sideways");
+        assert_eq!(err, "line 1: unknown image texture option `sideways`");
+        let err = parse("texture earth image support/earthmap.jpg tile 4");
+        assert_eq!(err, "line 1: `tile` needs two numbers");
+    }
+
+    #[test]
+    fn test_microfacet_materials() {
+        let source = "
+            texture marble noise 4
+            material plastic microfacet 0.8 0.1 0.1 0.3 0
+            material veined microfacet marble marble 1
+            material gold conductor gold 0.2
+            material custom conductor 0.2 0.9 1.1 3.9 2.4 2.1 marble
+            material frosted rough_dielectric 1.5 0.4
+            sphere 0 0 0 1 plastic
+        ";
+        assert!(Scene::parse(source, "").is_ok());
+        let parse = |line: &str| Scene::parse(line, "").err().unwrap();
+        let err = parse("material tin conductor tin 0.2");
+        assert_eq!(
+            err,
+            "line 1: unknown metal `tin` (gold, silver, copper, aluminium)"
+        );
+        let err = parse("material plastic microfacet 1 1 1 0.5");
+        assert_eq!(
+            err,
+            "line 1: `microfacet` expects 3 or 5 arguments, found 4"
+        );
+        let err = parse("material plastic microfacet 1 1 1 rough 0");
+        assert_eq!(err, "line 1: unknown texture `rough`");
+    }
+
+    #[test]
+    fn test_unclosed_block() {
+        let source = "material white lambertian 1 1 1\nbegin\nsphere 0 0 0 1 white\n";
+        let err = Scene::parse(source, "").err().unwrap();
+        assert_eq!(err, "line 2: `begin` is never closed");
+    }
+}
@@ -1,5 +1,6 @@
 // Module: Vec3,Point3,Ray,Interval
 
+use std::f64::consts::PI;
 use std::fmt;
 use std::ops::{Add, AddAssign};
 
@@ -216,6 +217,15 @@ impl Vec3 {
             }
         }
     }
+    pub fn random_cosine_direction() -> Vec3 {
+        let r1 = random_double();
+        let r2 = random_double();
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * r2.sqrt();
+        let y = phi.sin() * r2.sqrt();
+        let z = (1.0 - r2).sqrt();
+        Vec3::new(x, y, z)
+    }
 }
 
 pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
@@ -336,11 +346,38 @@ pub struct Ray {
     orig: Point3,
     dir: Vec3,
     tm: f64,
+    // The ray stands for a thin cone: `width` across at the origin, growing by `spread`
+    // (radians) per unit of distance. Texture filtering reads it; it is 0 unless set.
+    width: f64,
+    spread: f64,
 }
 
 impl Ray {
     pub fn new(orig: Point3, dir: Vec3, tm: f64) -> Self {
-        Self { orig, dir, tm }
+        Self {
+            orig,
+            dir,
+            tm,
+            width: 0.0,
+            spread: 0.0,
+        }
+    }
+
+    pub fn with_cone(self, width: f64, spread: f64) -> Self {
+        Self {
+            width,
+            spread,
+            ..self
+        }
+    }
+
+    // How wide the cone is at `at(t)`.
+    pub fn cone_width(&self, t: f64) -> f64 {
+        self.width + self.spread * t * self.dir.length()
+    }
+
+    pub fn spread(&self) -> f64 {
+        self.spread
     }
 
     pub fn at(&self, t: f64) -> Point3 {
@@ -1,13 +1,20 @@
 use crate::util::Color;
 use crate::util::Image;
-use crate::util::Interval;
+use crate::util::MipMap;
 use crate::util::Perlin;
 use crate::util::Point3;
+use crate::util::TextureFilter;
+use crate::util::TextureWrap;
 use crate::util::Vec3;
 use crate::Arc;
 
 pub trait Texture: Sync + Send {
     fn value(&self, u: f64, v: f64, p: &Point3) -> Color;
+    // `footprint` is how much of the surface the lookup covers, in UV units (see
+    // `HitRecord::set_footprint`); 0 for a point. Image textures use it to pick a mip level.
+    fn filtered_value(&self, u: f64, v: f64, p: &Point3, _footprint: f64) -> Color {
+        self.value(u, v, p)
+    }
 }
 
 pub struct Solid_Color {
@@ -54,57 +61,71 @@ impl Checker_Texture {
     }
 }
 
+impl Checker_Texture {
+    fn is_even(&self, p: &Point3) -> bool {
+        let x_integer = (self.inv_scale * p.x).floor() as i32;
+        let y_integer = (self.inv_scale * p.y).floor() as i32;
+        let z_integer = (self.inv_scale * p.z).floor() as i32;
+        (x_integer + y_integer + z_integer) % 2 == 0
+    }
+}
+
 impl Texture for Checker_Texture {
     fn value(&self, u: f64, v: f64, p: &Point3) -> Color {
-        let xInteger = (self.inv_scale * p.x).floor() as i32;
-        let yInteger = (self.inv_scale * p.y).floor() as i32;
-        let zInteger = (self.inv_scale * p.z).floor() as i32;
-        let is_even = (xInteger + yInteger + zInteger) % 2 == 0;
-        return if is_even {
+        if self.is_even(p) {
             self.even.value(u, v, p)
         } else {
             self.odd.value(u, v, p)
-        };
+        }
+    }
+    fn filtered_value(&self, u: f64, v: f64, p: &Point3, footprint: f64) -> Color {
+        if self.is_even(p) {
+            self.even.filtered_value(u, v, p, footprint)
+        } else {
+            self.odd.filtered_value(u, v, p, footprint)
+        }
     }
 }
 
+// An image looked up by UV, (0, 0) at the bottom left. `tile` repeats the image that many times
+// over [0, 1]; what lies outside the image is up to `wrap`.
 pub struct Image_Texture {
-    image: Image,
+    mipmap: MipMap,
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+    pub tile: [f64; 2],
 }
 
 impl Image_Texture {
     pub fn new(filepath: &str) -> Self {
-        Self {
-            image: Image::new(filepath).unwrap(),
-        }
+        Self::from_file(filepath).unwrap()
+    }
+    pub fn from_file(filepath: &str) -> Result<Self, String> {
+        Ok(Self {
+            mipmap: MipMap::new(&Image::new(filepath)?),
+            filter: TextureFilter::Trilinear,
+            wrap: TextureWrap::Repeat,
+            tile: [1.0, 1.0],
+        })
     }
 }
 
 impl Texture for Image_Texture {
     fn value(&self, u: f64, v: f64, p: &Point3) -> Color {
-        if self.image.width() == 0 || self.image.height() == 0 {
+        self.filtered_value(u, v, p, 0.0)
+    }
+    fn filtered_value(&self, u: f64, v: f64, _p: &Point3, footprint: f64) -> Color {
+        if self.mipmap.width() == 0 || self.mipmap.height() == 0 {
             return Color::new(0.0, 1.0, 1.0);
         }
-        let u = Interval::new(0.0, 1.0).clamp(u);
-        let v = 1.0 - Interval::new(0.0, 1.0).clamp(v);
-
-        let mut i = (u * self.image.width() as f64) as u32;
-        let mut j = (v * self.image.height() as f64) as u32;
-        if i == self.image.width() {
-            i = i - 1;
-        }
-        if j == self.image.height() {
-            j = j - 1;
-        }
-        // println!("i: {}, j: {}", i, j);
-        let pixel = self.image.pixel_data(i, j);
-
-        let color_scale = 1.0 / 255.0;
-        return Color::new(
-            color_scale * pixel.unwrap().0 as f64,
-            color_scale * pixel.unwrap().1 as f64,
-            color_scale * pixel.unwrap().2 as f64,
-        );
+        // image rows run top to bottom
+        self.mipmap.lookup(
+            u * self.tile[0],
+            1.0 - v * self.tile[1],
+            footprint * self.tile[0].abs().max(self.tile[1].abs()),
+            self.filter,
+            self.wrap,
+        )
     }
 }
 
@@ -0,0 +1,119 @@
+use crate::util::Color;
+
+// Display transforms applied when the linear framebuffer is written to an 8-bit image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapOperator {
+    Clamp,
+    Reinhard,
+    ExtendedReinhard,
+    Aces,
+}
+
+impl ToneMapOperator {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "clamp" => Ok(ToneMapOperator::Clamp),
+            "reinhard" => Ok(ToneMapOperator::Reinhard),
+            "reinhard_extended" => Ok(ToneMapOperator::ExtendedReinhard),
+            "aces" => Ok(ToneMapOperator::Aces),
+            other => Err(format!(
+                "unknown tone mapping operator `{}` (clamp, reinhard, reinhard_extended, aces)",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMap {
+    pub operator: ToneMapOperator,
+    // in stops: every +1 doubles the radiance before the operator
+    pub exposure: f64,
+    // luminance that maps to white with the extended Reinhard operator
+    pub white_point: f64,
+}
+
+impl ToneMap {
+    pub fn new(operator: ToneMapOperator, exposure: f64, white_point: f64) -> Self {
+        Self {
+            operator,
+            exposure,
+            white_point,
+        }
+    }
+    // Clamp at 0 EV is what the renderer always did.
+    pub fn default() -> Self {
+        Self::new(ToneMapOperator::Clamp, 0.0, 4.0)
+    }
+
+    // Maps linear radiance to linear display values in [0, 1]; gamma is left to `Color::to_rgb`.
+    pub fn apply(&self, color: Color) -> Color {
+        let c = color * 2f64.powf(self.exposure);
+        let mapped = match self.operator {
+            ToneMapOperator::Clamp => c,
+            ToneMapOperator::Reinhard => Self::scale_luminance(c, |l| l / (1.0 + l)),
+            ToneMapOperator::ExtendedReinhard => {
+                let white_squared = self.white_point * self.white_point;
+                Self::scale_luminance(c, |l| l * (1.0 + l / white_squared) / (1.0 + l))
+            }
+            ToneMapOperator::Aces => Color::new(Self::aces(c.r), Self::aces(c.g), Self::aces(c.b)),
+        };
+        Color::new(
+            mapped.r.clamp(0.0, 1.0),
+            mapped.g.clamp(0.0, 1.0),
+            mapped.b.clamp(0.0, 1.0),
+        )
+    }
+
+    // Reinhard works on luminance so saturated colors keep their hue.
+    fn scale_luminance<F: Fn(f64) -> f64>(c: Color, f: F) -> Color {
+        let l = c.luminance();
+        if l <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        c * (f(l) / l)
+    }
+
+    // Narkowicz's fit of the ACES filmic curve.
+    fn aces(x: f64) -> f64 {
+        let x = x.max(0.0);
+        (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators_stay_in_display_range() {
+        for operator in [
+            ToneMapOperator::Clamp,
+            ToneMapOperator::Reinhard,
+            ToneMapOperator::ExtendedReinhard,
+            ToneMapOperator::Aces,
+        ] {
+            let tone_map = ToneMap::new(operator, 0.0, 4.0);
+            let mut last = -1.0;
+            for k in 0..100 {
+                let v = k as f64 * 0.25;
+                let c = tone_map.apply(Color::new(v, v, v));
+                assert!(c.r >= 0.0 && c.r <= 1.0);
+                assert!(c.r >= last);
+                last = c.r;
+            }
+        }
+    }
+
+    #[test]
+    fn test_exposure_and_white_point() {
+        let clamp = ToneMap::new(ToneMapOperator::Clamp, 1.0, 4.0);
+        assert_eq!(
+            clamp.apply(Color::new(0.25, 0.1, 0.0)),
+            Color::new(0.5, 0.2, 0.0)
+        );
+        let extended = ToneMap::new(ToneMapOperator::ExtendedReinhard, 0.0, 4.0);
+        let white = extended.apply(Color::new(4.0, 4.0, 4.0));
+        assert!((white.g - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,794 @@
+use crate::util::{Arc, HitRecord, Hittable, Interval, Material, Point3, Ray, Vec3, AABB};
+
+// A 4x4 matrix for affine transforms, stored row by row and applied to column vectors, so
+// `a * b` does `b` first and then `a`. Angles are in degrees, as in `RotateY`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mat4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Mat4 {
+    pub fn new(m: [[f64; 4]; 4]) -> Self {
+        Self { m }
+    }
+
+    pub fn identity() -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Self { m }
+    }
+
+    pub fn translation(offset: Vec3) -> Self {
+        let mut ret = Self::identity();
+        ret.m[0][3] = offset.x;
+        ret.m[1][3] = offset.y;
+        ret.m[2][3] = offset.z;
+        ret
+    }
+
+    pub fn scaling(factors: Vec3) -> Self {
+        let mut ret = Self::identity();
+        ret.m[0][0] = factors.x;
+        ret.m[1][1] = factors.y;
+        ret.m[2][2] = factors.z;
+        ret
+    }
+
+    pub fn rotation_x(angle: f64) -> Self {
+        Self::rotation(Vec3::new(1.0, 0.0, 0.0), angle)
+    }
+
+    pub fn rotation_y(angle: f64) -> Self {
+        Self::rotation(Vec3::new(0.0, 1.0, 0.0), angle)
+    }
+
+    pub fn rotation_z(angle: f64) -> Self {
+        Self::rotation(Vec3::new(0.0, 0.0, 1.0), angle)
+    }
+
+    // Counterclockwise by `angle` around `axis` (looking down the axis toward the origin).
+    pub fn rotation(axis: Vec3, angle: f64) -> Self {
+        let a = axis.normalize();
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let c = 1.0 - cos;
+        Self::new([
+            [
+                cos + a.x * a.x * c,
+                a.x * a.y * c - a.z * sin,
+                a.x * a.z * c + a.y * sin,
+                0.0,
+            ],
+            [
+                a.y * a.x * c + a.z * sin,
+                cos + a.y * a.y * c,
+                a.y * a.z * c - a.x * sin,
+                0.0,
+            ],
+            [
+                a.z * a.x * c - a.y * sin,
+                a.z * a.y * c + a.x * sin,
+                cos + a.z * a.z * c,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    // Rotates around x first, then y, then z.
+    pub fn euler(angles: Vec3) -> Self {
+        Self::rotation_z(angles.z) * Self::rotation_y(angles.y) * Self::rotation_x(angles.x)
+    }
+
+    // Moves the origin to `from` and turns the local +z axis toward `to`, keeping +y as close
+    // to `up` as it can. Handy for pointing an object (a quad light, a cow) at something.
+    pub fn look_at(from: Point3, to: Point3, up: Vec3) -> Self {
+        let w = (to - from).normalize();
+        let u = up.cross(&w).normalize();
+        let v = w.cross(&u);
+        let frame = Self::new([
+            [u.x, v.x, w.x, 0.0],
+            [u.y, v.y, w.y, 0.0],
+            [u.z, v.z, w.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        Self::translation(from.to_vec3()) * frame
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.m[j][i];
+            }
+        }
+        Self { m }
+    }
+
+    // Of the upper-left 3x3 part, the one that acts on vectors: how much it scales volumes.
+    pub fn determinant(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    // Gauss-Jordan elimination with partial pivoting; `None` if the matrix is singular.
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = self.m;
+        let mut inv = Self::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+                .unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0..4 {
+                if row == col || a[row][col] == 0.0 {
+                    continue;
+                }
+                let factor = a[row][col];
+                for j in 0..4 {
+                    a[row][j] -= factor * a[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+        Some(Self { m: inv })
+    }
+
+    pub fn transform_point(&self, p: Point3) -> Point3 {
+        let m = &self.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 {
+            Point3::new(x, y, z)
+        } else {
+            Point3::new(x / w, y / w, z / w)
+        }
+    }
+
+    // Directions ignore the translation.
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    // The box around the eight transformed corners of `bbox`.
+    pub fn transform_box(&self, bbox: AABB) -> AABB {
+        let mut ret = AABB::default();
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 { bbox.x.min } else { bbox.x.max },
+                if i & 2 == 0 { bbox.y.min } else { bbox.y.max },
+                if i & 4 == 0 { bbox.z.min } else { bbox.z.max },
+            );
+            let p = self.transform_point(corner);
+            ret = AABB::new_by_aabb(ret, AABB::new_by_point(p, p));
+        }
+        ret
+    }
+}
+
+impl std::ops::Mul for Mat4 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Self { m }
+    }
+}
+
+// Places an object with any affine matrix: rays are taken into the object's space by the
+// inverse, and hits come back out by the matrix itself. Normals go through the inverse
+// transpose, so they stay perpendicular to the surface under non-uniform scale and shear.
+pub struct Transform {
+    object: Arc<dyn Hittable>,
+    matrix: Mat4,
+    inverse: Mat4,
+    normal_matrix: Mat4,
+    // of the 3x3 part of `matrix`
+    determinant: f64,
+    bbox: AABB,
+}
+
+impl Transform {
+    pub fn new(object: Arc<dyn Hittable>, matrix: Mat4) -> Result<Self, String> {
+        let inverse = matrix
+            .inverse()
+            .ok_or_else(|| String::from("transform matrix is not invertible"))?;
+        let bbox = matrix.transform_box(object.bounding_box());
+        Ok(Self {
+            object,
+            matrix,
+            inverse,
+            normal_matrix: inverse.transpose(),
+            determinant: matrix.determinant(),
+            bbox,
+        })
+    }
+}
+
+impl Hittable for Transform {
+    fn hit(&self, r: Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        // the direction is not renormalized, so `t` means the same on both sides
+        let local_r = Ray::new(
+            self.inverse.transform_point(r.origin()),
+            self.inverse.transform_vector(r.direction()),
+            r.time(),
+        );
+        if !self.object.hit(local_r, ray_t, rec) {
+            return false;
+        }
+        rec.p = self.matrix.transform_point(rec.p);
+        // det(M) |M^-T n| is how much areas grow on a surface with unit normal n
+        let normal = self.normal_matrix.transform_vector(rec.geometric_normal);
+        rec.scale_uv(self.determinant.abs() * normal.length());
+        rec.normal = self.normal_matrix.transform_vector(rec.normal).normalize();
+        rec.dpdu = self.matrix.transform_vector(rec.dpdu);
+        rec.dpdv = self.matrix.transform_vector(rec.dpdv);
+        rec.geometric_normal = self
+            .normal_matrix
+            .transform_vector(rec.geometric_normal)
+            .normalize();
+        rec.set_object(self);
+        true
+    }
+    fn bounding_box(&self) -> AABB {
+        self.bbox
+    }
+    fn display(&self) {
+        println!("Transform");
+    }
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+// One placement of a shared object, such as a mesh loaded once for a whole herd: its own
+// matrix and, optionally, a material that replaces the one the object was built with. Only
+// the matrices are per instance, the geometry and its BVH are not copied; put the instances
+// in a `BvhNode` to get a top-level BVH over them.
+pub struct Instance {
+    transform: Transform,
+    mat: Option<Arc<dyn Material>>,
+}
+
+impl Instance {
+    pub fn new(
+        object: Arc<dyn Hittable>,
+        matrix: Mat4,
+        mat: Option<Arc<dyn Material>>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            transform: Transform::new(object, matrix)?,
+            mat,
+        })
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, r: Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        if !self.transform.hit(r, ray_t, rec) {
+            return false;
+        }
+        if let Some(mat) = &self.mat {
+            rec.mat = Some(mat.clone());
+        }
+        rec.set_object(self);
+        true
+    }
+    fn bounding_box(&self) -> AABB {
+        self.transform.bounding_box()
+    }
+    fn display(&self) {
+        println!("Instance");
+    }
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        self.mat.clone()
+    }
+}
+
+// A unit quaternion for rotations that have to be blended, such as the keyframes of a
+// `Motion`. Products compose like matrices: `a * b` turns by `b` first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quat {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quat {
+    pub fn identity() -> Self {
+        Self {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    // The same turn as `Mat4::rotation(axis, angle)`.
+    pub fn from_axis_angle(axis: Vec3, angle: f64) -> Self {
+        let a = axis.normalize();
+        let (sin, cos) = (angle.to_radians() / 2.0).sin_cos();
+        Self {
+            w: cos,
+            x: a.x * sin,
+            y: a.y * sin,
+            z: a.z * sin,
+        }
+    }
+
+    // The same turn as `Mat4::euler(angles)`.
+    pub fn from_euler(angles: Vec3) -> Self {
+        Self::from_axis_angle(Vec3::new(0.0, 0.0, 1.0), angles.z)
+            * Self::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), angles.y)
+            * Self::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), angles.x)
+    }
+
+    fn dot(&self, other: &Self) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    fn scale(&self, k: f64) -> Self {
+        Self {
+            w: self.w * k,
+            x: self.x * k,
+            y: self.y * k,
+            z: self.z * k,
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Self {
+            w: self.w + other.w,
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+
+    pub fn normalize(&self) -> Self {
+        self.scale(1.0 / self.dot(self).sqrt())
+    }
+
+    pub fn conjugate(&self) -> Self {
+        Self {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    // Spherical interpolation along the shorter arc: constant angular speed from `self` at
+    // `t = 0` to `other` at `t = 1`.
+    pub fn slerp(&self, other: &Self, t: f64) -> Self {
+        let mut cos = self.dot(other);
+        let mut other = *other;
+        if cos < 0.0 {
+            cos = -cos;
+            other = other.scale(-1.0);
+        }
+        if cos > 0.9995 {
+            // nearly the same rotation; a straight blend is as good and avoids dividing by ~0
+            return self.scale(1.0 - t).add(&other.scale(t)).normalize();
+        }
+        let theta = cos.acos();
+        let sin = theta.sin();
+        let a = ((1.0 - t) * theta).sin() / sin;
+        let b = (t * theta).sin() / sin;
+        self.scale(a).add(&other.scale(b))
+    }
+
+    pub fn rotate(&self, v: Vec3) -> Vec3 {
+        let q = Vec3::new(self.x, self.y, self.z);
+        let t = q.cross(&v) * 2.0;
+        v + t * self.w + q.cross(&t)
+    }
+}
+
+impl std::ops::Mul for Quat {
+    type Output = Self;
+
+    fn mul(self, o: Self) -> Self {
+        Self {
+            w: self.w * o.w - self.x * o.x - self.y * o.y - self.z * o.z,
+            x: self.w * o.x + self.x * o.w + self.y * o.z - self.z * o.y,
+            y: self.w * o.y - self.x * o.z + self.y * o.w + self.z * o.x,
+            z: self.w * o.z + self.x * o.y - self.y * o.x + self.z * o.w,
+        }
+    }
+}
+
+// The pose of an object at one time: scaled first, then rotated, then moved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time: f64,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Keyframe {
+    pub fn new(time: f64) -> Self {
+        Self {
+            time,
+            translation: Vec3::zero(),
+            rotation: Quat::identity(),
+            scale: Vec3::ones(),
+        }
+    }
+
+    fn object_to_world(&self, p: Point3) -> Point3 {
+        let scaled = p.to_vec3().element_mul(&self.scale);
+        (self.rotation.rotate(scaled) + self.translation).to_point3()
+    }
+
+    fn world_to_object(&self, p: Point3) -> Point3 {
+        self.direction_to_object(p - self.translation.to_point3())
+            .to_point3()
+    }
+
+    fn direction_to_world(&self, v: Vec3) -> Vec3 {
+        self.rotation.rotate(v.element_mul(&self.scale))
+    }
+
+    fn direction_to_object(&self, v: Vec3) -> Vec3 {
+        let turned = self.rotation.conjugate().rotate(v);
+        Vec3::new(
+            turned.x / self.scale.x,
+            turned.y / self.scale.y,
+            turned.z / self.scale.z,
+        )
+    }
+
+    // How much areas grow on a surface with unit normal `n`, the same as for `Transform`.
+    fn area_scale(&self, n: Vec3) -> f64 {
+        let s = self.scale;
+        let scaled = Vec3::new(n.x / s.x, n.y / s.y, n.z / s.z);
+        (s.x * s.y * s.z).abs() * scaled.length()
+    }
+
+    // Normals take the inverse transpose: the inverse scale, then the rotation.
+    fn normal_to_world(&self, n: Vec3) -> Vec3 {
+        let scaled = Vec3::new(n.x / self.scale.x, n.y / self.scale.y, n.z / self.scale.z);
+        self.rotation.rotate(scaled).normalize()
+    }
+}
+
+// Moves any object through a list of keyframes over the shutter time: the translation and
+// scale are blended linearly and the rotation by slerp. Before the first keyframe and after the
+// last the object holds still. The box covers every pose in between.
+pub struct Motion {
+    object: Arc<dyn Hittable>,
+    keyframes: Vec<Keyframe>,
+    bbox: AABB,
+}
+
+// Poses checked between two keyframes when bounding the motion.
+const BOUND_STEPS: usize = 32;
+
+impl Motion {
+    pub fn new(object: Arc<dyn Hittable>, keyframes: Vec<Keyframe>) -> Result<Self, String> {
+        if keyframes.is_empty() {
+            return Err(String::from("motion needs at least one keyframe"));
+        }
+        if keyframes
+            .windows(2)
+            .any(|pair| pair[0].time >= pair[1].time)
+        {
+            return Err(String::from("keyframe times must increase"));
+        }
+        if keyframes
+            .iter()
+            .any(|k| k.scale.x * k.scale.y * k.scale.z == 0.0)
+        {
+            return Err(String::from("keyframe scale must not be zero"));
+        }
+        let mut motion = Self {
+            object,
+            keyframes,
+            bbox: AABB::default(),
+        };
+        motion.bbox = motion.motion_box();
+        Ok(motion)
+    }
+
+    pub fn at(&self, time: f64) -> Keyframe {
+        let first = &self.keyframes[0];
+        let last = &self.keyframes[self.keyframes.len() - 1];
+        if time <= first.time {
+            return *first;
+        }
+        if time >= last.time {
+            return *last;
+        }
+        let next = self.keyframes.partition_point(|k| k.time <= time);
+        let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        Keyframe {
+            time,
+            translation: a.translation * (1.0 - t) + b.translation * t,
+            rotation: a.rotation.slerp(&b.rotation, t),
+            scale: a.scale * (1.0 - t) + b.scale * t,
+        }
+    }
+
+    // The object's box at a row of poses along each segment, grown by the farthest any of its
+    // corners moves from one pose to the next so the path between them is covered too.
+    fn motion_box(&self) -> AABB {
+        let object_box = self.object.bounding_box();
+        let corners: Vec<Point3> = (0..8)
+            .map(|i| {
+                Point3::new(
+                    if i & 1 == 0 {
+                        object_box.x.min
+                    } else {
+                        object_box.x.max
+                    },
+                    if i & 2 == 0 {
+                        object_box.y.min
+                    } else {
+                        object_box.y.max
+                    },
+                    if i & 4 == 0 {
+                        object_box.z.min
+                    } else {
+                        object_box.z.max
+                    },
+                )
+            })
+            .collect();
+        let mut times = vec![self.keyframes[0].time];
+        for pair in self.keyframes.windows(2) {
+            for step in 1..=BOUND_STEPS {
+                let t = step as f64 / BOUND_STEPS as f64;
+                times.push(pair[0].time * (1.0 - t) + pair[1].time * t);
+            }
+        }
+
+        let mut bbox = AABB::default();
+        let mut previous: Option<Vec<Point3>> = None;
+        let mut step_length: f64 = 0.0;
+        for time in times {
+            let pose = self.at(time);
+            let moved: Vec<Point3> = corners.iter().map(|&c| pose.object_to_world(c)).collect();
+            for &p in &moved {
+                bbox = AABB::new_by_aabb(bbox, AABB::new_by_point(p, p));
+            }
+            if let Some(previous) = previous {
+                for (a, b) in previous.iter().zip(&moved) {
+                    step_length = step_length.max((*b - *a).length());
+                }
+            }
+            previous = Some(moved);
+        }
+        AABB::new(
+            bbox.x.expand(step_length),
+            bbox.y.expand(step_length),
+            bbox.z.expand(step_length),
+        )
+    }
+}
+
+impl Hittable for Motion {
+    fn hit(&self, r: Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        let pose = self.at(r.time());
+        let local_r = Ray::new(
+            pose.world_to_object(r.origin()),
+            pose.direction_to_object(r.direction()),
+            r.time(),
+        );
+        if !self.object.hit(local_r, ray_t, rec) {
+            return false;
+        }
+        rec.p = pose.object_to_world(rec.p);
+        rec.scale_uv(pose.area_scale(rec.geometric_normal));
+        rec.normal = pose.normal_to_world(rec.normal);
+        rec.dpdu = pose.direction_to_world(rec.dpdu);
+        rec.dpdv = pose.direction_to_world(rec.dpdv);
+        rec.geometric_normal = pose.normal_to_world(rec.geometric_normal);
+        rec.set_object(self);
+        true
+    }
+    fn bounding_box(&self) -> AABB {
+        self.bbox
+    }
+    fn display(&self) {
+        println!("Motion ({} keyframes)", self.keyframes.len());
+    }
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{Color, HittableList, Lambertian, Metal, Sphere};
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_mat4_compose_and_invert() {
+        let m = Mat4::translation(Vec3::new(1.0, 2.0, 3.0))
+            * Mat4::euler(Vec3::new(30.0, -45.0, 60.0))
+            * Mat4::scaling(Vec3::new(2.0, 0.5, 3.0));
+        let product = m * m.inverse().unwrap();
+        for i in 0..4 {
+            for j in 0..4 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((product.m[i][j] - expected).abs() < 1e-12);
+            }
+        }
+        assert!(Mat4::scaling(Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+
+        // a quarter turn around z takes x to y, and around the diagonal cycles the axes
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        assert_near(
+            Mat4::rotation_z(90.0).transform_vector(x),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        let diagonal = Mat4::rotation(Vec3::new(1.0, 1.0, 1.0), 120.0);
+        assert_near(diagonal.transform_vector(x), Vec3::new(0.0, 1.0, 0.0));
+
+        let look = Mat4::look_at(
+            Point3::new(1.0, 1.0, 1.0),
+            Point3::new(1.0, 1.0, -4.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        let p = look.transform_point(Point3::new(0.0, 0.0, 2.0));
+        assert_near(p.to_vec3(), Vec3::new(1.0, 1.0, -1.0));
+        assert_near(
+            look.transform_vector(Vec3::new(0.0, 1.0, 0.0)),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_instances_share_the_object() {
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new_by_color(Color::new(1.0, 0.0, 0.0)));
+        let sphere: Arc<dyn Hittable> =
+            Arc::new(Sphere::new_static(Point3::zero(), 1.0, Some(red.clone())));
+        let metal: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.8, 0.8, 0.8), 0.0));
+        let a = Instance::new(
+            sphere.clone(),
+            Mat4::translation(Vec3::new(-3.0, 0.0, 0.0)),
+            None,
+        )
+        .unwrap();
+        let b = Instance::new(
+            sphere.clone(),
+            Mat4::translation(Vec3::new(3.0, 0.0, 0.0)),
+            Some(metal.clone()),
+        )
+        .unwrap();
+        assert_eq!(Arc::strong_count(&sphere), 3);
+
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(Point3::new(-3.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(a.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!(Arc::ptr_eq(rec.mat.as_ref().unwrap(), &red));
+        let first = rec.object_id;
+        assert!(!b.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+
+        let ray = Ray::new(Point3::new(3.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(b.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!(Arc::ptr_eq(rec.mat.as_ref().unwrap(), &metal));
+        assert_ne!(rec.object_id, first);
+    }
+
+    #[test]
+    fn test_quat_matches_mat4() {
+        let axis = Vec3::new(1.0, -2.0, 0.5);
+        let v = Vec3::new(0.3, 0.7, -1.1);
+        let q = Quat::from_axis_angle(axis, 70.0);
+        assert_near(q.rotate(v), Mat4::rotation(axis, 70.0).transform_vector(v));
+        let angles = Vec3::new(30.0, -45.0, 60.0);
+        assert_near(
+            Quat::from_euler(angles).rotate(v),
+            Mat4::euler(angles).transform_vector(v),
+        );
+
+        // halfway from no turn to a half turn around y is a quarter turn, the short way round
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let half = Quat::identity().slerp(&Quat::from_axis_angle(y, 180.0), 0.5);
+        assert_near(
+            half.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        let short = Quat::from_axis_angle(y, 10.0).slerp(&Quat::from_axis_angle(y, 350.0), 0.5);
+        assert_near(
+            short.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_motion_follows_keyframes() {
+        // a small sphere at x = 2, swung a quarter turn around y while moving up by 4
+        let sphere = Arc::new(Sphere::new_static(Point3::new(2.0, 0.0, 0.0), 0.5, None));
+        let start = Keyframe::new(0.0);
+        let mut end = Keyframe::new(1.0);
+        end.translation = Vec3::new(0.0, 4.0, 0.0);
+        end.rotation = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 90.0);
+        let motion = Motion::new(sphere, vec![start, end]).unwrap();
+
+        let mut rec = HitRecord::default();
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let ray = Ray::new(Point3::new(2.0, 10.0, 0.0), down, 0.0);
+        assert!(motion.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 9.5).abs() < 1e-9);
+        // halfway, the sphere is at 45 degrees and y = 2
+        let s = 2.0 * 0.5f64.sqrt();
+        let ray = Ray::new(Point3::new(s, 10.0, -s), down, 0.5);
+        assert!(motion.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 7.5).abs() < 1e-9);
+        assert_near(rec.normal, Vec3::new(0.0, 1.0, 0.0));
+        let ray = Ray::new(Point3::new(2.0, 10.0, 0.0), down, 0.5);
+        assert!(!motion.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+
+        // the box covers the arc, not just the two ends
+        let bbox = motion.bounding_box();
+        assert!(bbox.x.min <= -0.5 && bbox.x.max >= 2.5);
+        assert!(bbox.z.min <= -2.5 && bbox.z.max >= 0.5);
+        assert!(bbox.y.min <= -0.5 && bbox.y.max >= 4.5);
+
+        let still = Keyframe::new(0.0);
+        assert!(Motion::new(Arc::new(HittableList::new()), vec![still, still]).is_err());
+    }
+
+    #[test]
+    fn test_transform_scaled_sphere() {
+        // the unit sphere stretched to an ellipsoid with semi-axes 2, 1, 1, then moved up by 5
+        let sphere = Arc::new(Sphere::new_static(Point3::zero(), 1.0, None));
+        let m =
+            Mat4::translation(Vec3::new(0.0, 5.0, 0.0)) * Mat4::scaling(Vec3::new(2.0, 1.0, 1.0));
+        let ellipsoid = Transform::new(sphere, m).unwrap();
+        let bbox = ellipsoid.bounding_box();
+        assert!((bbox.x.max - 2.0).abs() < 1e-3 && (bbox.y.min - 4.0).abs() < 1e-3);
+
+        let mut rec = HitRecord::default();
+        let ray = Ray::new(Point3::new(-10.0, 5.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(ellipsoid.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 8.0).abs() < 1e-9);
+        assert_near(rec.normal, Vec3::new(-1.0, 0.0, 0.0));
+
+        // at 45 degrees around the ellipse the normal leans toward y, as the inverse
+        // transpose gives, not toward the point itself
+        let s = 0.5f64.sqrt();
+        let ray = Ray::new(
+            Point3::new(2.0 * s, 10.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            0.0,
+        );
+        assert!(ellipsoid.hit(ray, Interval::new(0.001, f64::INFINITY), &mut rec));
+        assert_near(rec.p.to_vec3(), Vec3::new(2.0 * s, 5.0 + s, 0.0));
+        assert_near(rec.normal, Vec3::new(1.0, 2.0, 0.0).normalize());
+    }
+}
@@ -10,22 +10,71 @@ pub use perlin::Perlin;
 #[path = "./plane.rs"]
 mod plane;
 pub use plane::*;
+#[path = "./animation.rs"]
+mod animation;
+#[path = "./aov.rs"]
+mod aov;
+#[path = "./bump.rs"]
+mod bump;
+#[path = "./bvh.rs"]
+mod bvh;
+#[path = "./checkpoint.rs"]
+mod checkpoint;
+#[path = "./denoise.rs"]
+mod denoise;
+#[path = "./framebuffer.rs"]
+mod framebuffer;
 #[path = "./material.rs"]
 mod material;
+#[path = "./mesh.rs"]
+mod mesh;
+#[path = "./microfacet.rs"]
+mod microfacet;
+#[path = "./mipmap.rs"]
+mod mipmap;
+#[path = "./obj_loader.rs"]
+mod obj_loader;
+#[path = "./pdf.rs"]
+mod pdf;
+#[path = "./rng.rs"]
+mod rng;
+#[path = "./sampler.rs"]
+mod sampler;
+#[path = "./scene.rs"]
+mod scene;
 #[path = "./sup.rs"]
 mod sup;
-use crate::File;
-use crossbeam::thread;
-use image::ImageBuffer;
+#[path = "./tonemap.rs"]
+mod tonemap;
+#[path = "./transform.rs"]
+mod transform;
+pub use animation::*;
+pub use aov::*;
+pub use bump::*;
+pub use bvh::*;
+pub use checkpoint::*;
+pub use denoise::*;
+pub use framebuffer::*;
 use indicatif::ProgressBar;
 pub use material::*;
-use rand::random;
+pub use mesh::*;
+pub use microfacet::*;
+pub use mipmap::*;
+pub use obj_loader::*;
+pub use pdf::*;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+pub use rng::*;
+pub use sampler::*;
+pub use scene::*;
 use std::f64::consts::PI;
-use std::sync::atomic::Ordering;
-use std::sync::Condvar;
-use std::sync::Mutex;
-use std::sync::{atomic::AtomicUsize, Arc};
-pub use sup::*; //接收render传回来的图片，在main中文件输出
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+pub use sup::*;
+pub use tonemap::*;
+pub use transform::*; //接收render传回来的图片，在main中文件输出
 
 // Note that currently it cannot distinguish whether object is in front of the camera or behind the camera.
 // pub fn hit_sphere(center: Point3, radius: f64, r: Ray) -> f64{
@@ -65,17 +114,39 @@ pub trait Hittable: Send + Sync {
     fn display(&self);
     fn get_material(&self) -> Option<Arc<dyn Material>>;
     fn bounding_box(&self) -> AABB;
+    // Density (w.r.t. solid angle) of `random` picking `direction` from `origin`.
+    // Only objects that can be sampled as lights need to override these two.
+    fn pdf_value(&self, _origin: Point3, _direction: Vec3) -> f64 {
+        0.0
+    }
+    fn random(&self, _origin: Point3) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
 }
 
 #[derive(Clone)]
 pub struct HitRecord {
     pub p: Point3,
+    // the shading normal; see `set_shading_normal`
     pub normal: Vec3,
+    // the normal of the surface itself, on the side the ray came from
+    pub geometric_normal: Vec3,
     pub t: f64,
     pub front_face: bool,
     pub mat: Option<Arc<dyn Material>>, // Change the lifetime to 'static
     pub u: f64,
     pub v: f64,
+    // UV units per unit of length along the surface, from the object that was hit; 0 if it
+    // doesn't say
+    pub uv_scale: f64,
+    // see `set_footprint`
+    pub footprint: f64,
+    // how the hit point moves with u and with v, for bump and normal maps; zero if the object
+    // doesn't say
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // tells objects apart in the object ID pass, see `set_object`
+    pub object_id: usize,
 }
 impl HitRecord {
     pub fn new(
@@ -90,24 +161,41 @@ impl HitRecord {
         Self {
             p,
             normal,
+            geometric_normal: normal,
             t,
             front_face,
             mat,
             u,
             v,
+            uv_scale: 0.0,
+            footprint: 0.0,
+            dpdu: Vec3::zero(),
+            dpdv: Vec3::zero(),
+            object_id: 0,
         }
     }
     pub fn default() -> Self {
         Self {
             p: Point3::zero(),
             normal: Vec3::zero(),
+            geometric_normal: Vec3::zero(),
             t: 0.0,
             front_face: false,
             mat: None,
             u: 0.0,
             v: 0.0,
+            uv_scale: 0.0,
+            footprint: 0.0,
+            dpdu: Vec3::zero(),
+            dpdv: Vec3::zero(),
+            object_id: 0,
         }
     }
+    // Primitives mark the hit as theirs; transforms and media mark it again on the way out, so
+    // a rotated box counts as one object rather than six quads.
+    pub fn set_object<T>(&mut self, object: &T) {
+        self.object_id = object as *const T as usize;
+    }
     pub fn set_face_normal(&mut self, r: Ray, outward_normal: Vec3) {
         self.front_face = r.direction().dot(&outward_normal) < 0.0;
         self.normal = if self.front_face {
@@ -115,6 +203,38 @@ impl HitRecord {
         } else {
             outward_normal * (-1.0)
         };
+        self.geometric_normal = self.normal;
+    }
+    // Replaces the normal used for shading (e.g. one interpolated from vertex normals), turned
+    // to the side `set_face_normal` picked. The geometric normal stays as it was.
+    pub fn set_shading_normal(&mut self, outward_normal: Vec3) {
+        self.normal = if self.front_face {
+            outward_normal
+        } else {
+            outward_normal * (-1.0)
+        };
+    }
+    // How wide the cone of `r` is where it hit, in UV units. On a slanted surface the cone
+    // leaves an ellipse, longer by 1 / cos one way only; the width taken is that of a circle
+    // with the same area, so the filter blurs less across the slant than it could.
+    pub fn set_footprint(&mut self, r: &Ray) {
+        let cosine = r.direction().normalize().dot(&self.geometric_normal).abs();
+        self.footprint = r.cone_width(self.t) * self.uv_scale / cosine.max(1e-4).sqrt();
+    }
+    // After a transform, whose matrix grows areas on this surface by `area_scale`.
+    pub fn scale_uv(&mut self, area_scale: f64) {
+        self.uv_scale /= area_scale.sqrt();
+    }
+    // A ray leaving the hit point, started a hair off the surface on the side it heads to.
+    pub fn spawn_ray(&self, direction: Vec3, time: f64) -> Ray {
+        let scale = self.p.x.abs().max(self.p.y.abs()).max(self.p.z.abs());
+        let offset = self.geometric_normal * (1e-7 * (1.0 + scale));
+        let origin = if direction.dot(&self.geometric_normal) < 0.0 {
+            self.p - offset
+        } else {
+            self.p + offset
+        };
+        Ray::new(origin, direction, time)
     }
 }
 
@@ -167,6 +287,28 @@ impl Sphere {
         *u = phi / (2.0 * PI);
         *v = theta / PI;
     }
+    // d(p)/du and d(p)/dv for `get_sphere_uv`, where p = center + radius * n. Both vanish at
+    // the poles.
+    fn sphere_derivatives(n: Vec3, radius: f64) -> (Vec3, Vec3) {
+        let dpdu = Vec3::new(n.z, 0.0, -n.x) * (2.0 * PI * radius);
+        let s = (n.x * n.x + n.z * n.z).sqrt();
+        if s < 1e-12 {
+            return (dpdu, Vec3::zero());
+        }
+        let dpdv = Vec3::new(-n.x * n.y / s, s, -n.y * n.z / s) * (PI * radius);
+        (dpdu, dpdv)
+    }
+    // A direction uniformly distributed in the cone (around +z) that the sphere subtends.
+    fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
+        let r1 = random_double();
+        let r2 = random_double();
+        let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
+
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * (1.0 - z * z).sqrt();
+        let y = phi.sin() * (1.0 - z * z).sqrt();
+        Vec3::new(x, y, z)
+    }
 }
 
 impl Hittable for Sphere {
@@ -201,7 +343,11 @@ impl Hittable for Sphere {
         let outward_normal = (rec.p - center) / self.radius;
         rec.set_face_normal(ray, outward_normal);
         Self::get_sphere_uv(outward_normal.to_point3(), &mut rec.u, &mut rec.v);
+        // the UV square spread evenly over the surface, 4 pi r^2
+        rec.uv_scale = 1.0 / (2.0 * self.radius.abs() * PI.sqrt());
+        (rec.dpdu, rec.dpdv) = Self::sphere_derivatives(outward_normal, self.radius);
         rec.mat = self.get_material();
+        rec.set_object(self);
 
         return true;
     }
@@ -216,6 +362,35 @@ impl Hittable for Sphere {
     fn bounding_box(&self) -> AABB {
         return self.bbox;
     }
+
+    // Lights are sampled at time 0, so only static spheres should be used as lights.
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        let mut rec = HitRecord::default();
+        if !self.hit(
+            Ray::new(origin, direction, 0.0),
+            Interval::new(0.001, INFINITY),
+            &mut rec,
+        ) {
+            return 0.0;
+        }
+        let distance_squared = (self.center - origin).squared_length();
+        if distance_squared <= self.radius * self.radius {
+            return 1.0 / (4.0 * PI);
+        }
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let direction = self.center - origin;
+        let distance_squared = direction.squared_length();
+        if distance_squared <= self.radius * self.radius {
+            return Vec3::random_unit_vector();
+        }
+        let uvw = Onb::new(direction);
+        uvw.transform(Self::random_to_sphere(self.radius, distance_squared))
+    }
 }
 
 pub struct HittableList {
@@ -267,106 +442,20 @@ impl Hittable for HittableList {
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
-}
-
-pub struct BvhNode {
-    left: Arc<dyn Hittable>,
-    right: Arc<dyn Hittable>,
-    bbox: AABB,
-}
-
-impl BvhNode {
-    pub fn new(objects: &mut Vec<Arc<dyn Hittable>>, start: usize, end: usize) -> Self {
-        let mut bbox = AABB::default();
-        for object_index in start..end {
-            bbox = AABB::new_by_aabb(bbox, objects[object_index].bounding_box());
-        }
-
-        let axis = bbox.longest_axis();
-        let comparator = match axis {
-            0 => BvhNode::box_x_compare,
-            1 => BvhNode::box_y_compare,
-            2 => BvhNode::box_z_compare,
-            _ => panic!("Invalid axis"),
-        };
-        let object_span = end - start;
-        // println!("start: {}, end: {}", start, end);
-        // println!("object_span: {:?}", object_span);
-        let mut left: Arc<dyn Hittable> = objects[start].clone();
-        let mut right: Arc<dyn Hittable> = objects[start].clone();
-
-        if object_span == 1 {
-            left = objects[start].clone();
-            right = objects[start].clone();
-        } else if object_span == 2 {
-            left = objects[start].clone();
-            right = objects[start + 1].clone();
-        } else {
-            let comparator_closure =
-                |a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>| -> std::cmp::Ordering {
-                    if comparator(a, b) {
-                        std::cmp::Ordering::Less
-                    } else if comparator(b, a) {
-                        std::cmp::Ordering::Greater
-                    } else {
-                        std::cmp::Ordering::Equal
-                    }
-                };
-            objects[start..end].sort_unstable_by(comparator_closure);
-            let mid = start + object_span / 2;
-            left = Arc::new(Self::new(objects, start, mid));
-            right = Arc::new(Self::new(objects, mid, end));
-        }
-
-        Self {
-            left: left,
-            right: right,
-            bbox: bbox,
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.0;
         }
-    }
-    pub fn new_by_object_list(list: &HittableList) -> Self {
-        let mut objects = list.objects.clone();
-        let number = objects.len();
-        // println!("number: {:?}", number);
-        Self::new(&mut objects, 0, number)
-    }
-    fn box_compare(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>, axis_index: usize) -> bool {
-        let box_a_interval = a.bounding_box().axis_interval(axis_index);
-        let box_b_interval = b.bounding_box().axis_interval(axis_index);
-        return box_a_interval.min < box_b_interval.min;
-    }
-    fn box_x_compare(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>) -> bool {
-        Self::box_compare(a, b, 0)
-    }
-    fn box_y_compare(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>) -> bool {
-        Self::box_compare(a, b, 1)
-    }
-    fn box_z_compare(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>) -> bool {
-        Self::box_compare(a, b, 2)
-    }
-}
-
-impl Hittable for BvhNode {
-    fn hit(&self, r: Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
-        if !self.bbox.hit(r, ray_t) {
-            return false;
+        let weight = 1.0 / self.objects.len() as f64;
+        let mut sum = 0.0;
+        for object in self.objects.iter() {
+            sum += weight * object.pdf_value(origin, direction);
         }
-        let hit_left = self.left.hit(r, ray_t, rec);
-        let hit_right = self.right.hit(
-            r,
-            Interval::new(ray_t.min, if hit_left { rec.t } else { ray_t.max }),
-            rec,
-        );
-        return hit_left || hit_right;
-    }
-    fn bounding_box(&self) -> AABB {
-        self.bbox
+        sum
     }
-    fn display(&self) {
-        println!("BvhNode");
-    }
-    fn get_material(&self) -> Option<Arc<dyn Material>> {
-        None
+    fn random(&self, origin: Point3) -> Vec3 {
+        let size = self.objects.len() as i32;
+        self.objects[random_int(0, size - 1) as usize].random(origin)
     }
 }
 
@@ -394,6 +483,7 @@ impl Hittable for Translate {
             return false;
         }
         rec.p = rec.p + self.offset;
+        rec.set_object(self);
         return true;
     }
     fn bounding_box(&self) -> AABB {
@@ -468,13 +558,21 @@ impl Hittable for RotateY {
             return false;
         }
         let p = rec.p;
-        let normal = rec.normal;
         let p_x = self.cos_theta * p.x + self.sin_theta * p.z;
         let p_z = -self.sin_theta * p.x + self.cos_theta * p.z;
-        let normal_x = self.cos_theta * normal.x + self.sin_theta * normal.z;
-        let normal_z = -self.sin_theta * normal.x + self.cos_theta * normal.z;
         rec.p = Point3::new(p_x, p.y, p_z);
-        rec.normal = Vec3::new(normal_x, normal.y, normal_z);
+        let rotate = |n: Vec3| {
+            Vec3::new(
+                self.cos_theta * n.x + self.sin_theta * n.z,
+                n.y,
+                -self.sin_theta * n.x + self.cos_theta * n.z,
+            )
+        };
+        rec.normal = rotate(rec.normal);
+        rec.geometric_normal = rotate(rec.geometric_normal);
+        rec.dpdu = rotate(rec.dpdu);
+        rec.dpdv = rotate(rec.dpdv);
+        rec.set_object(self);
         return true;
     }
     fn bounding_box(&self) -> AABB {
@@ -548,8 +646,10 @@ impl Hittable for ConstantMedium {
         rec.t = rec1.t + hit_distance / ray_length;
         rec.p = r.at(rec.t);
         rec.normal = Vec3::new(1.0, 0.0, 0.0);
+        rec.geometric_normal = rec.normal;
         rec.front_face = true;
         rec.mat = self.phase_function.clone();
+        rec.set_object(self);
         return true;
     }
     fn bounding_box(&self) -> AABB {
@@ -617,6 +717,10 @@ impl AABB {
             _ => panic!("Invalid axis"),
         }
     }
+    pub fn surface_area(&self) -> f64 {
+        let (dx, dy, dz) = (self.x.size(), self.y.size(), self.z.size());
+        2.0 * (dx * dy + dy * dz + dz * dx)
+    }
     pub fn longest_axis(&self) -> usize {
         if self.x.size() > self.y.size() {
             return if self.x.size() > self.z.size() { 0 } else { 2 };
@@ -696,19 +800,51 @@ pub struct Camera {
     pub defocus_angle: f64,
     pub focus_dist: f64,
     pub background: Color,
+    // render threads, 0 for one per core
+    pub threads: usize,
+    pub mis_heuristic: MisHeuristic,
+    // the same seed renders the same image, whatever the thread count
+    pub seed: u64,
+    pub sampler: SamplerType,
+    // which scene this is (`hash_name` of the scene file's text, or of the built-in scene's name
+    // and seed), so a checkpoint of another scene isn't picked up
+    pub scene_id: u64,
+    // bounces before Russian roulette may end a path; max_depth still caps the length
+    pub roulette_depth: u32,
+    pub tone_map: ToneMap,
+    // seconds between checkpoints, 0 to turn checkpointing off
+    pub checkpoint_interval: u64,
+    // relative error a pixel has to get below before adaptive sampling leaves it alone;
+    // 0 samples every pixel samples_per_pixel times
+    pub adaptive_threshold: f64,
+    // cap on the samples of one pixel with adaptive sampling, 0 for four times samples_per_pixel
+    pub max_samples_per_pixel: u32,
+    // also write `<path>.denoised.<ext>`, filtered with the first-hit albedo, normal and depth
+    pub denoise: bool,
+    // first-hit passes written as `<path>.<aov>.<ext>`
+    pub aovs: AovSet,
+    // rays get times spread evenly over [shutter_open, shutter_close]; equal times freeze motion
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    // where the camera is and looks when the shutter closes, if it moves while it is open; it
+    // moves in a straight line from lookfrom and lookat
+    pub lookfrom_end: Option<Point3>,
+    pub lookat_end: Option<Point3>,
 
     image_height: u32,
+    view: View,
+    samples_per_pass: u32,
+}
+
+// The viewport and lens of the camera from one position.
+#[derive(Clone, Copy)]
+struct View {
     center: Point3,
     pixel00_loc: Point3,
     pixel_horizontal: Vec3,
     pixel_vertical: Vec3,
-    u: Vec3,
-    v: Vec3,
-    w: Vec3,
     defocus_disk_u: Vec3,
     defocus_disk_v: Vec3,
-    sqrt_spp: i32,
-    recip_sqrt_spp: f64,
 }
 
 impl Camera {
@@ -737,20 +873,49 @@ impl Camera {
             defocus_angle: defocus_angle,
             focus_dist: focus_dist,
             background,
+            threads: 0,
+            mis_heuristic: MisHeuristic::Power,
+            seed: 0,
+            sampler: SamplerType::Stratified,
+            scene_id: 0,
+            roulette_depth: 5,
+            tone_map: ToneMap::default(),
+            checkpoint_interval: 0,
+            adaptive_threshold: 0.0,
+            max_samples_per_pixel: 0,
+            denoise: false,
+            aovs: AovSet::empty(),
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            lookfrom_end: None,
+            lookat_end: None,
             image_height: 0,
-            center: Point3::zero(),
-            pixel00_loc: Point3::zero(),
-            pixel_horizontal: Vec3::zero(),
-            pixel_vertical: Vec3::zero(),
-            u: Vec3::zero(),
-            v: Vec3::zero(),
-            w: Vec3::zero(),
-            defocus_disk_u: Vec3::zero(),
-            defocus_disk_v: Vec3::zero(),
-            sqrt_spp: 0,
-            recip_sqrt_spp: 0.0,
+            view: View {
+                center: Point3::zero(),
+                pixel00_loc: Point3::zero(),
+                pixel_horizontal: Vec3::zero(),
+                pixel_vertical: Vec3::zero(),
+                defocus_disk_u: Vec3::zero(),
+                defocus_disk_v: Vec3::zero(),
+            },
+            samples_per_pass: 0,
         }
     }
+    pub fn default() -> Self {
+        Self::new(
+            16.0 / 9.0,
+            400,
+            100,
+            50,
+            20.0,
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            10.0,
+            Color::new(0.7, 0.8, 1.0),
+        )
+    }
 
     fn initialize(&mut self) {
         self.image_height = (self.image_width as f64 / self.aspect_ratio) as u32;
@@ -759,78 +924,266 @@ impl Camera {
         } else {
             1
         };
-        self.sqrt_spp = (self.samples_per_pixel as f64).sqrt() as i32;
-        self.recip_sqrt_spp = 1.0 / self.sqrt_spp as f64;
-        self.center = self.lookfrom;
+        self.samples_per_pixel = self.samples_per_pixel.max(1);
+        self.samples_per_pass = (self.samples_per_pixel as f64).sqrt().ceil() as u32;
+        self.view = self.view_from(self.lookfrom, self.lookat);
+    }
 
-        self.w = (self.lookfrom - self.lookat).normalize();
-        self.u = self.vup.cross(&self.w).normalize();
-        self.v = self.w.cross(&self.u);
+    fn view_from(&self, lookfrom: Point3, lookat: Point3) -> View {
+        let w = (lookfrom - lookat).normalize();
+        let u = self.vup.cross(&w).normalize();
+        let v = w.cross(&u);
 
         let theta = self.vfov.to_radians();
         let h = (theta / 2.0).tan();
         let viewport_height = 2.0 * h * self.focus_dist;
         let viewport_width = (self.image_width as f64 / self.image_height as f64) * viewport_height;
 
-        let horizontal = self.u * viewport_width;
-        let vertical = self.v * (-1.0) * viewport_height;
-        self.pixel_horizontal = horizontal / self.image_width as f64;
-        self.pixel_vertical = vertical / self.image_height as f64;
+        let horizontal = u * viewport_width;
+        let vertical = v * (-1.0) * viewport_height;
+        let pixel_horizontal = horizontal / self.image_width as f64;
+        let pixel_vertical = vertical / self.image_height as f64;
 
-        let viewport_upperleft =
-            self.center - self.w * self.focus_dist - horizontal / 2.0 - vertical / 2.0;
-        self.pixel00_loc =
-            viewport_upperleft + self.pixel_horizontal / 2.0 + self.pixel_vertical / 2.0;
+        let viewport_upperleft = lookfrom - w * self.focus_dist - horizontal / 2.0 - vertical / 2.0;
+        let pixel00_loc = viewport_upperleft + pixel_horizontal / 2.0 + pixel_vertical / 2.0;
 
         let defocus_radius = self.focus_dist * (self.defocus_angle / 2.0).to_radians().tan();
-        self.defocus_disk_u = self.u * defocus_radius;
-        self.defocus_disk_v = self.v * defocus_radius;
+        View {
+            center: lookfrom,
+            pixel00_loc,
+            pixel_horizontal,
+            pixel_vertical,
+            defocus_disk_u: u * defocus_radius,
+            defocus_disk_v: v * defocus_radius,
+        }
     }
 
-    fn ray_color(&self, r: Ray, world: &Arc<dyn Hittable>, depth: u32) -> Color {
-        if depth <= 0 {
-            return Color::new(0.0, 0.0, 0.0);
+    // The view at `time`, when the camera moves.
+    fn view_at(&self, time: f64) -> View {
+        if self.lookfrom_end.is_none() && self.lookat_end.is_none() {
+            return self.view;
         }
-        let mut rec = HitRecord {
-            p: Point3::zero(),
-            normal: Vec3::zero(),
-            t: 0.0,
-            front_face: true,
-            mat: None,
-            u: 0.0,
-            v: 0.0,
-        };
-        let hit = world.hit(r, Interval::new(0.001, INFINITY), &mut rec);
-        if !hit {
-            // let direction = r.direction().normalize();
-            // let a = 0.5 * (direction.y + 1.0);
-            // let color1 = Color::new(0.1, 0.1, 0.45);
-            // let color2 = Color::new(0.2, 0.2, 0.9);
-            // return color1 * (1.0 - a) + color2 * a;
-
-            // Color::new(0.0, 0.0, 0.0)
-            return self.background;
+        let open = self.shutter_close - self.shutter_open;
+        let t = if open > 0.0 {
+            (time - self.shutter_open) / open
         } else {
-            let mut scattered = Ray::new(Point3::zero(), Vec3::zero(), 0.0);
-            let mut attenuation = Color::new(0.0, 0.0, 0.0);
-            let rec_clone = rec.clone();
-            let color_from_emission = rec_clone.mat.unwrap().emitted(rec.u, rec.v, &rec.p);
-            let rec_clone = rec.clone();
-
-            if rec
-                .mat
-                .unwrap()
-                .scatter(&r, &rec_clone, &mut attenuation, &mut scattered)
-            {
-                let color_from_scatter =
-                    attenuation.element_mul(self.ray_color(scattered, world, depth - 1));
-                return color_from_emission + color_from_scatter;
+            0.0
+        };
+        let lerp = |a: Point3, b: Option<Point3>| match b {
+            Some(b) => a + (b - a) * t,
+            None => a,
+        };
+        self.view_from(
+            lerp(self.lookfrom, self.lookfrom_end),
+            lerp(self.lookat, self.lookat_end),
+        )
+    }
+
+    fn ray_color(&self, r: Ray, context: &RenderContext, depth: u32) -> Color {
+        let (world, lights) = (context.world, context.lights);
+        let mut color = Color::new(0.0, 0.0, 0.0);
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut ray = r;
+        // Where the lights were last sampled directly, and the material pdf of the bounce taken
+        // from there. Emission the bounce runs into was also reachable by light sampling, so it
+        // only gets its MIS share.
+        let mut sampled_lights_from: Option<(Point3, f64)> = None;
+
+        for bounce in 0..depth {
+            // Russian roulette: end dim paths early and boost the survivors so the estimate
+            // stays unbiased.
+            if bounce >= self.roulette_depth {
+                let survive = throughput.max_component().min(1.0);
+                if random_double() >= survive {
+                    break;
+                }
+                throughput = throughput / survive;
+            }
+
+            let mut rec = HitRecord::default();
+            if !world.hit(ray, Interval::new(0.001, INFINITY), &mut rec) {
+                // let direction = r.direction().normalize();
+                // let a = 0.5 * (direction.y + 1.0);
+                // let color1 = Color::new(0.1, 0.1, 0.45);
+                // let color2 = Color::new(0.2, 0.2, 0.9);
+                // return color1 * (1.0 - a) + color2 * a;
+                color = color + throughput.element_mul(self.background);
+                break;
+            }
+            rec.set_footprint(&ray);
+            // bounced rays carry the cone on from here; a curved or rough surface would widen
+            // it more, which is left out
+            let cone_width = ray.cone_width(rec.t);
+
+            let mat = rec.mat.clone().unwrap();
+            mat.perturb_normal(&mut rec);
+            let weight = match sampled_lights_from {
+                Some((origin, material_pdf)) => {
+                    let light_pdf = lights.pdf_value(origin, ray.direction());
+                    self.mis_heuristic.weight(material_pdf, light_pdf)
+                }
+                None => 1.0,
+            };
+            let emitted = mat.emitted(rec.u, rec.v, &rec.p);
+            color = color + throughput.element_mul(emitted) * weight;
+
+            let mut srec = ScatterRecord::default();
+            if !mat.scatter(&ray, &rec, &mut srec) {
+                break;
             }
+            if srec.skip_pdf {
+                throughput = throughput.element_mul(srec.attenuation);
+                ray = srec.skip_pdf_ray.with_cone(cone_width, ray.spread());
+                sampled_lights_from = None;
+                continue;
+            }
+
+            let sample_lights = !lights.objects.is_empty();
+            let mis = self.mis_heuristic != MisHeuristic::Off;
+            let mut pdf = srec.pdf.clone().unwrap();
+            if sample_lights && mis {
+                // Next-event estimation: one shadow ray towards the lights at every
+                // non-specular hit.
+                let direct = self.sample_lights(&ray, &rec, &srec, &pdf, &mat, context);
+                color = color + throughput.element_mul(direct);
+            } else if sample_lights {
+                let towards_lights = Arc::new(HittablePdf::new(lights.clone(), rec.p));
+                pdf = Arc::new(MixturePdf::new(towards_lights, pdf));
+            }
+
+            let scattered = rec.spawn_ray(pdf.generate(), ray.time());
+            let pdf_value = pdf.value(scattered.direction());
+            if pdf_value <= 0.0 {
+                break;
+            }
+            sampled_lights_from = if sample_lights && mis {
+                Some((rec.p, pdf_value))
+            } else {
+                None
+            };
+            let value = mat.scattering_value(&ray, &rec, &srec, &scattered);
+            throughput = throughput.element_mul(value) / pdf_value;
+            ray = scattered.with_cone(cone_width, ray.spread());
+        }
+
+        color
+    }
+
+    // Light arriving at `rec` directly from a point picked on the lights.
+    fn sample_lights(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        pdf: &Arc<dyn Pdf>,
+        mat: &Arc<dyn Material>,
+        context: &RenderContext,
+    ) -> Color {
+        let (world, lights) = (context.world, context.lights);
+        let black = Color::new(0.0, 0.0, 0.0);
+        let shadow_ray = rec.spawn_ray(lights.random(rec.p), r_in.time());
+        let light_pdf = lights.pdf_value(rec.p, shadow_ray.direction());
+        if light_pdf <= 0.0 {
+            return black;
+        }
+        let value = mat.scattering_value(r_in, rec, srec, &shadow_ray);
+        if value.max_component() <= 0.0 {
+            return black;
+        }
 
-            return color_from_emission;
+        // Whatever the shadow ray hits first is what the point actually sees.
+        let mut light_rec = HitRecord::default();
+        if !world.hit(shadow_ray, Interval::new(0.001, INFINITY), &mut light_rec) {
+            return black;
+        }
+        let emitted = light_rec
+            .mat
+            .unwrap()
+            .emitted(light_rec.u, light_rec.v, &light_rec.p);
+        let weight = self
+            .mis_heuristic
+            .weight(light_pdf, pdf.value(shadow_ray.direction()));
+        value.element_mul(emitted) * (weight / light_pdf)
+    }
+
+    // What `r` hits first, for the denoiser and the AOVs. The albedo is the attenuation of the
+    // material (its texture value for diffuse surfaces); lights and the background count as
+    // their clamped color.
+    fn first_hit(&self, r: Ray, world: &Arc<dyn Hittable>) -> FirstHit {
+        let clamp = |c: Color| Color::new(c.r.min(1.0), c.g.min(1.0), c.b.min(1.0));
+        let mut rec = HitRecord::default();
+        if !world.hit(r, Interval::new(0.001, INFINITY), &mut rec) {
+            return FirstHit::miss(clamp(self.background));
+        }
+        rec.set_footprint(&r);
+        let mat = rec.mat.clone().unwrap();
+        mat.perturb_normal(&mut rec);
+        let mut srec = ScatterRecord::default();
+        let albedo = if mat.scatter(&r, &rec, &mut srec) {
+            srec.attenuation
+        } else {
+            mat.emitted(rec.u, rec.v, &rec.p)
+        };
+        FirstHit {
+            albedo: clamp(albedo),
+            normal: rec.normal,
+            depth: rec.t,
+            u: rec.u,
+            v: rec.v,
+            object: rec.object_id,
+            material: Arc::as_ptr(&mat) as *const () as usize,
         }
     }
 
+    // Feature buffers from a few jittered first hits per pixel.
+    fn render_features(&self, context: &RenderContext) -> FeatureBuffers {
+        const FEATURE_SAMPLES: u32 = 8;
+        let world = context.world;
+        let tiles: Vec<Vec<(usize, usize, FirstHit)>> = context.pool.install(|| {
+            self.tiles()
+                .into_par_iter()
+                .map(|tile| {
+                    let mut results = Vec::with_capacity(TILE_SIZE * TILE_SIZE);
+                    for (i, j) in tile.pixels() {
+                        let mut pixel = FirstHit::miss(Color::new(0.0, 0.0, 0.0));
+                        for k in 0..FEATURE_SAMPLES {
+                            // a stream of its own, apart from the beauty samples
+                            let pixel_index = (j * self.image_width as usize + i) as u64;
+                            seed_random(hash_seed(&[self.seed, pixel_index, u64::MAX, k as u64]));
+                            let r = self.get_ray(i as f64, j as f64);
+                            let hit = self.first_hit(r, world);
+                            pixel.albedo = pixel.albedo + hit.albedo;
+                            pixel.normal += hit.normal;
+                            pixel.depth += hit.depth;
+                            pixel.u += hit.u;
+                            pixel.v += hit.v;
+                            // IDs can't be averaged
+                            if k == 0 {
+                                pixel.object = hit.object;
+                                pixel.material = hit.material;
+                            }
+                        }
+                        let n = FEATURE_SAMPLES as f64;
+                        pixel.albedo = pixel.albedo / n;
+                        pixel.normal = pixel.normal / n;
+                        pixel.depth /= n;
+                        pixel.u /= n;
+                        pixel.v /= n;
+                        results.push((i, j, pixel));
+                    }
+                    results
+                })
+                .collect()
+        });
+
+        let mut features = FeatureBuffers::new(self.image_width, self.image_height);
+        for (i, j, pixel) in tiles.into_iter().flatten() {
+            features.set(i, j, &pixel);
+        }
+        features.number_ids();
+        features
+    }
+
     fn is_ci() -> bool {
         option_env!("CI").unwrap_or_default() == "true"
     }
@@ -839,162 +1192,341 @@ impl Camera {
         Vec3::new(random_double() - 0.5, random_double() - 0.5, 0.0)
     }
 
-    fn get_ray(&self, i: f64, j: f64, s_i: i32, s_j: i32) -> Ray {
-        let offset = self.sample_square_stratified(s_i, s_j);
+    // Draws the pixel offset, then the lens position (with defocus) and the time, in that order,
+    // from the current sample.
+    fn get_ray(&self, i: f64, j: f64) -> Ray {
+        let offset = Self::sample_square();
         // let offset = Vec3::new(0.0,0.0,0.0);
-        let pixel_center = self.pixel00_loc
-            + (self.pixel_horizontal * (i + offset.x))
-            + (self.pixel_vertical * (j + offset.y));
-        let ray_origin = if self.defocus_angle <= 0.0 {
-            self.center
+        let lens = if self.defocus_angle <= 0.0 {
+            None
         } else {
-            self.defocus_disk_sample()
+            Some(Self::defocus_disk_sample())
+        };
+        let ray_time =
+            self.shutter_open + (self.shutter_close - self.shutter_open) * random_double();
+        let view = self.view_at(ray_time);
+        let pixel_center = view.pixel00_loc
+            + (view.pixel_horizontal * (i + offset.x))
+            + (view.pixel_vertical * (j + offset.y));
+        let ray_origin = match lens {
+            None => view.center,
+            Some((x, y)) => view.center + view.defocus_disk_u * x + view.defocus_disk_v * y,
         };
         let ray_direct = (pixel_center - ray_origin).normalize();
-        let ray_time = random_double();
-        Ray::new(ray_origin, ray_direct, ray_time)
-    }
-
-    fn sample_square_stratified(&self, s_i: i32, s_j: i32) -> Vec3 {
-        let px = ((s_i as f64 + random_double()) * self.recip_sqrt_spp) as f64 - 0.5;
-        let py = ((s_j as f64 + random_double()) * self.recip_sqrt_spp) as f64 - 0.5;
-        Vec3::new(px, py, 0.0)
-    }
-
-    fn defocus_disk_sample(&self) -> Point3 {
-        let p = Vec3::random_in_unit_disk();
-        self.center + self.defocus_disk_u * p.x + self.defocus_disk_v * p.y
-    }
-
-    // pub fn render(&mut self, world: &Arc<dyn Hittable>, path: &str) {
-    //     self.initialize();
-    // let bar: ProgressBar = if Self::is_ci() {
-    //     let output_image: image::DynamicImage = image::DynamicImage::ImageRgb8(*img.clone());e_height * self.image_width) as u64)
-    // };
-
-    //     let mut file = File::create(path).expect("Failed to create file");
-    //     writeln!(file, "P3\n{} {}\n255", self.image_width, self.image_height)
-    //         .expect("Failed to write header");
-
-    //     for j in 0..self.image_height as usize {
-    //         for i in 0..self.image_width as usize {
-    //             // let pixel_color: Color = (0..self.samples_per_pixel)
-    //             //     .into_par_iter()
-    //             //     .map(|_| {
-    //             //         let r = self.get_ray(i as f64, j as f64);
-    //             //         Self::ray_color(r, &world, self.max_depth)
-    //             //     })
-    //             //     .reduce(|| Color::new(0.0, 0.0, 0.0), |sum, c| sum + c)
-    //             //     / self.samples_per_pixel as f64;
-    //             let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-    //             for _ in 0..self.samples_per_pixel {
-    //                 let r = self.get_ray(i as f64, j as f64);
-    //                 pixel_color = pixel_color + Self::ray_color(r, &world, self.max_depth);
-    //             }
-    //             pixel_color = pixel_color / self.samples_per_pixel as f64;
-    //             write_color(pixel_color.to_rgb(), &mut file);
-    //             bar.inc(1);
-    //         }
-    //     }
-    //     bar.finish();
-    // }
-
-    pub fn render(&mut self, world: &Arc<dyn Hittable>, path: &str) {
-        const THREAD_LIMIT: usize = 16;
-        const NUM_THREADS: usize = 200;
+        // a cone from the lens as wide as one pixel where it reaches the focus plane
+        let spread = view.pixel_horizontal.length() / (pixel_center - ray_origin).length();
+        Ray::new(ray_origin, ray_direct, ray_time).with_cone(0.0, spread)
+    }
+
+    // A point on the unit disk from two numbers rather than rejection sampling, so a stratified
+    // pair stays stratified on the lens.
+    fn defocus_disk_sample() -> (f64, f64) {
+        let r = random_double().sqrt();
+        let theta = 2.0 * PI * random_double();
+        (r * theta.cos(), r * theta.sin())
+    }
+
+    // Everything besides the image size and samples_per_pixel that changes what the samples add
+    // up to, so a checkpoint is only resumed by the same render.
+    fn fingerprint(&self) -> u64 {
+        let point = |p: Point3| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+        let moving = |end: Option<Point3>| end.map_or([u64::MAX; 3], point);
+        let mut values = vec![
+            self.scene_id,
+            self.seed,
+            self.sampler as u64,
+            self.max_depth as u64,
+            self.roulette_depth as u64,
+            self.mis_heuristic as u64,
+            self.samples_per_pass as u64,
+            self.adaptive_threshold.to_bits(),
+            self.max_samples_per_pixel as u64,
+            self.background.r.to_bits(),
+            self.background.g.to_bits(),
+            self.background.b.to_bits(),
+            self.shutter_open.to_bits(),
+            self.shutter_close.to_bits(),
+            self.aspect_ratio.to_bits(),
+            self.vfov.to_bits(),
+            self.defocus_angle.to_bits(),
+            self.focus_dist.to_bits(),
+        ];
+        values.extend(point(self.lookfrom));
+        values.extend(point(self.lookat));
+        values.extend([
+            self.vup.x.to_bits(),
+            self.vup.y.to_bits(),
+            self.vup.z.to_bits(),
+        ]);
+        values.extend(moving(self.lookfrom_end));
+        values.extend(moving(self.lookat_end));
+        hash_seed(&values)
+    }
+
+    // Renders in passes of about sqrt(samples_per_pixel) samples per pixel, all drawn from
+    // `sampler`. With `checkpoint_interval` set, the sums so far are saved to `<path>.ckpt` (plus
+    // a preview image) at most that many seconds apart, and a later render of the same image
+    // picks up from the checkpoint.
+    //
+    // With `adaptive_threshold` set, samples_per_pixel is only the minimum: further passes go to
+    // the pixels whose relative error is still above the threshold, until none are left or they
+    // reach the maximum. Where the samples went is written to `<path>.samples.png`.
+    //
+    // With `denoise` set, a filtered copy goes to `<path>.denoised.<ext>` next to the raw image,
+    // and each AOV in `aovs` to `<path>.<aov>.<ext>`.
+    pub fn render(
+        &mut self,
+        world: &Arc<dyn Hittable>,
+        lights: &Arc<HittableList>,
+        path: &str,
+    ) -> Result<(), String> {
         self.initialize();
-        let img = Arc::new(Mutex::new(ImageBuffer::new(
-            self.image_width,
-            self.image_height,
-        )));
+        let min_samples = self.samples_per_pixel;
+        let checkpoint_path = format!("{}.ckpt", path);
+        let preview_path = Path::new(path).with_extension("preview.png");
+        let preview_path = preview_path.to_string_lossy();
+
+        let fingerprint = self.fingerprint();
+
+        let mut accumulator = Accumulator::new(self.image_width, self.image_height);
+        if self.checkpoint_interval > 0 {
+            let saved = Accumulator::load(
+                &checkpoint_path,
+                self.image_width,
+                self.image_height,
+                min_samples,
+                fingerprint,
+            )?;
+            if let Some(saved) = saved {
+                println!(
+                    "Resuming from {} ({} passes done)",
+                    checkpoint_path, saved.passes
+                );
+                accumulator = saved;
+            }
+        }
+        let first_pass = accumulator.passes;
+        let uniform_passes = min_samples.div_ceil(self.samples_per_pass);
+        let adaptive = self.adaptive_threshold > 0.0;
+        let max_samples = if self.max_samples_per_pixel == 0 {
+            4 * min_samples
+        } else {
+            self.max_samples_per_pixel.max(min_samples)
+        };
+        let max_passes = if adaptive {
+            uniform_passes + (max_samples - min_samples).div_ceil(self.samples_per_pass)
+        } else {
+            uniform_passes
+        };
+        let pixel_count = (self.image_width * self.image_height) as usize;
+        let sampler = self.sampler.build(self.seed, min_samples, self.image_width);
+
         let bar: ProgressBar = if Self::is_ci() {
             ProgressBar::hidden()
         } else {
-            ProgressBar::new((self.image_height * self.image_width) as u64)
+            ProgressBar::new(
+                (pixel_count as u32 * (uniform_passes - first_pass.min(uniform_passes))) as u64,
+            )
+        };
+        let pool = self.thread_pool()?;
+        let context = RenderContext {
+            world,
+            lights,
+            sampler: &sampler,
+            pool: &pool,
         };
-        let bar = Arc::new(bar);
-
-        thread::scope(|s| {
-            let rows_per_thread = self.image_height / NUM_THREADS as u32;
-            let thread_count = Arc::new(AtomicUsize::new(0));
-            let thread_number_controller = Arc::new(Condvar::new());
-
-            for thread_id in 0..NUM_THREADS {
-                // let world_clone = Arc::clone(&world);
-                // let file_clone = Arc::clone(&file);
-
-                let lock_for_condv = Mutex::new(false);
-                while !(thread_count.load(Ordering::SeqCst) < THREAD_LIMIT) {
-                    thread_number_controller
-                        .wait(lock_for_condv.lock().unwrap())
-                        .unwrap();
+        let mut last_checkpoint = Instant::now();
+
+        for pass in first_pass..max_passes {
+            // how many samples each pixel takes this pass
+            let counts = if pass < uniform_passes {
+                let count = self
+                    .samples_per_pass
+                    .min(min_samples - pass * self.samples_per_pass);
+                vec![count; pixel_count]
+            } else {
+                let noisy = accumulator.noisy_pixels(self.adaptive_threshold, max_samples);
+                let count = noisy.iter().filter(|&&n| n).count();
+                if count == 0 {
+                    break;
                 }
+                bar.inc_length(count as u64);
+                let width = self.image_width as usize;
+                (0..pixel_count)
+                    .map(|k| {
+                        if noisy[k] {
+                            let taken = accumulator.samples(k % width, k / width);
+                            self.samples_per_pass.min(max_samples - taken)
+                        } else {
+                            0
+                        }
+                    })
+                    .collect()
+            };
+            self.render_pass(&context, &counts, &mut accumulator, &bar);
+            accumulator.passes = pass + 1;
+            if self.checkpoint_interval > 0
+                && last_checkpoint.elapsed().as_secs() >= self.checkpoint_interval
+            {
+                accumulator.save(&checkpoint_path, min_samples, fingerprint)?;
+                accumulator.resolve().save(&preview_path, &self.tone_map)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+        bar.finish();
 
-                let mut img_clone = Arc::clone(&img);
-                let camera_clone = self.clone();
-                let thread_count = Arc::clone(&thread_count);
-                let thread_number_controller = Arc::clone(&thread_number_controller);
-                let bar = Arc::clone(&bar);
-                let start_row = thread_id * rows_per_thread as usize;
-                let end_row = if thread_id == NUM_THREADS - 1 {
-                    self.image_height as usize
-                } else {
-                    start_row + rows_per_thread as usize
-                };
-
-                thread_count.fetch_add(1, Ordering::SeqCst);
-                bar.set_message(format!(
-                    "|{} threads outstanding|",
-                    thread_count.load(Ordering::SeqCst)
-                ));
-
-                s.spawn(move |_| {
-                    let mut results: Vec<(usize, usize, [u8; 3])> = Vec::new();
-
-                    for j in start_row..end_row {
-                        for i in 0..camera_clone.image_width as usize {
-                            let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-                            for s_i in 0..camera_clone.sqrt_spp {
-                                for s_j in 0..camera_clone.sqrt_spp {
-                                    let r = camera_clone.get_ray(i as f64, j as f64, s_i, s_j);
-                                    pixel_color = pixel_color
-                                        + camera_clone.ray_color(r, &world, camera_clone.max_depth);
-                                }
-                            }
-                            pixel_color = pixel_color / camera_clone.samples_per_pixel as f64;
-                            // write_color(pixel_color.to_rgb(), &mut file);
-                            results.push((i, j, pixel_color.to_rgb()));
-                            bar.inc(1);
+        let framebuffer = accumulator.resolve();
+        framebuffer.save(path, &self.tone_map)?;
+        if self.denoise || !self.aovs.is_empty() {
+            let features = self.render_features(&context);
+            features.save(path, self.aovs, &self.tone_map)?;
+            if self.denoise {
+                let denoised_path = tagged_path(path, "denoised");
+                Denoiser::default()
+                    .denoise(&framebuffer, &features)
+                    .save(&denoised_path, &self.tone_map)?;
+                println!("Denoised image as \"{}\"", denoised_path);
+            }
+        }
+        if adaptive {
+            let heatmap_path = Path::new(path).with_extension("samples.png");
+            let heatmap_path = heatmap_path.to_string_lossy();
+            accumulator
+                .sample_heatmap(min_samples, max_samples)
+                .save(&heatmap_path, &ToneMap::default())?;
+            let total: u64 = (0..self.image_height as usize)
+                .flat_map(|j| (0..self.image_width as usize).map(move |i| (i, j)))
+                .map(|(i, j)| accumulator.samples(i, j) as u64)
+                .sum();
+            println!(
+                "Adaptive sampling: {:.1} samples per pixel on average, heatmap in {}",
+                total as f64 / (self.image_width * self.image_height) as f64,
+                heatmap_path
+            );
+        }
+        if self.checkpoint_interval > 0 {
+            let _ = fs::remove_file(&checkpoint_path);
+        }
+        Ok(())
+    }
+
+    // `counts[k]` more samples for pixel k, continuing from the samples it already has. Tiles
+    // are handed to the pool's threads by work stealing and fill private buffers, which are added
+    // to the accumulator once the whole pass is done.
+    fn render_pass(
+        &self,
+        context: &RenderContext,
+        counts: &[u32],
+        accumulator: &mut Accumulator,
+        bar: &ProgressBar,
+    ) {
+        let width = self.image_width as usize;
+        let taken = &*accumulator;
+        let tiles: Vec<Vec<PixelSamples>> = context.pool.install(|| {
+            self.tiles()
+                .into_par_iter()
+                .map(|tile| {
+                    let mut results = Vec::with_capacity(TILE_SIZE * TILE_SIZE);
+                    for (i, j) in tile.pixels() {
+                        let pixel = j * width + i;
+                        if counts[pixel] == 0 {
+                            continue;
+                        }
+                        let first = taken.samples(i, j);
+                        let mut pixel_color = Color::new(0.0, 0.0, 0.0);
+                        let mut luminance_sq = 0.0;
+                        for index in first..first + counts[pixel] {
+                            start_sample(context.sampler, pixel as u64, index);
+                            let r = self.get_ray(i as f64, j as f64);
+                            let sample = self.ray_color(r, context, self.max_depth);
+                            pixel_color = pixel_color + sample;
+                            luminance_sq += sample.luminance() * sample.luminance();
                         }
+                        results.push(PixelSamples {
+                            i,
+                            j,
+                            color: pixel_color,
+                            luminance_sq,
+                            samples: counts[pixel],
+                        });
                     }
+                    bar.inc(results.len() as u64);
+                    results
+                })
+                .collect()
+        });
+
+        for pixel in tiles.into_iter().flatten() {
+            accumulator.add(
+                pixel.i,
+                pixel.j,
+                pixel.color,
+                pixel.luminance_sq,
+                pixel.samples,
+            );
+        }
+    }
 
-                    // let mut file = file_clone.lock().unwrap();
-                    for (i, j, color) in results {
-                        write_color(color, &mut img_clone, i, j);
-                    }
-                    thread_count.fetch_sub(1, Ordering::SeqCst);
-                    bar.set_message(format!(
-                        "|{} threads outstanding|",
-                        thread_count.load(Ordering::SeqCst)
-                    ));
-                    thread_number_controller.notify_one();
+    fn tiles(&self) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+        for y in (0..self.image_height as usize).step_by(TILE_SIZE) {
+            for x in (0..self.image_width as usize).step_by(TILE_SIZE) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(self.image_width as usize - x),
+                    height: TILE_SIZE.min(self.image_height as usize - y),
                 });
             }
-        })
-        .unwrap();
-        bar.finish();
-        let cloned_inner_value = (*img).lock().unwrap().clone();
-        let output_image: image::DynamicImage = image::DynamicImage::ImageRgb8(cloned_inner_value);
-        let mut output_file: File = File::create(path).unwrap();
-        match output_image.write_to(&mut output_file, image::ImageOutputFormat::Png) {
-            Ok(_) => {}
-            Err(_) => println!("Outputting image fails."),
         }
+        tiles
+    }
+
+    // `threads` workers, or one per core when it is 0.
+    fn thread_pool(&self) -> Result<ThreadPool, String> {
+        ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .map_err(|e| format!("Failed to start render threads: {}", e))
+    }
+}
+
+// What a render works with besides the camera: the scene a path is traced through (everything
+// it can hit, and the part of it sampled as lights), where its samples come from, and the threads
+// it runs on.
+struct RenderContext<'a> {
+    world: &'a Arc<dyn Hittable>,
+    lights: &'a Arc<HittableList>,
+    sampler: &'a Arc<dyn Sampler>,
+    pool: &'a ThreadPool,
+}
+
+// Side of the square blocks the image is rendered in. Small enough that a tile full of glass
+// doesn't hold up the end of a pass, big enough that scheduling costs nothing.
+const TILE_SIZE: usize = 16;
+
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Tile {
+    fn pixels(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (self.y..self.y + self.height)
+            .flat_map(move |j| (self.x..self.x + self.width).map(move |i| (i, j)))
     }
 }
 
-pub fn random_double() -> f64 {
-    random::<f64>()
+// What one pass added to pixel (i, j): the sum of its new samples and of their squared
+// luminances.
+struct PixelSamples {
+    i: usize,
+    j: usize,
+    color: Color,
+    luminance_sq: f64,
+    samples: u32,
 }
 
 pub fn random_between(min: f64, max: f64) -> f64 {